@@ -0,0 +1,100 @@
+//! `--write-compressed-segments` 的压缩比和耗时
+//!
+//! 运行：`cargo bench --features compress-segments --bench compress [-- 分段文件...]`
+//!
+//! 未指定文件时使用合成的 MPEG-TS 数据：随机载荷模拟已编码的音视频，另有一成空包模拟码率填充。
+//! 实际的压缩比取决于流内容，应以真实分段的结果为准。
+
+use flate2::read::GzDecoder;
+use m3u8_downloader_rs::compress::gzip;
+use rand::{Rng, SeedableRng};
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// MPEG-TS 包大小
+const TS_PACKET_SIZE: usize = 188;
+
+/// 合成分段的包数量（约 4 MiB，相当于数秒的高清视频）
+const SYNTHETIC_PACKETS: usize = 22_000;
+
+/// 每个级别的测量次数，取最快的一次
+const ITERATIONS: usize = 5;
+
+/// 测量的压缩级别：0 只存储，1 为默认
+const LEVELS: [u32; 4] = [0, 1, 6, 9];
+
+/// 随机载荷的 TS 包中每十个插入一个空包（PID 0x1FFF，载荷全为 0xFF）
+fn synthetic_segment() -> Vec<u8> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x6d3375);
+    let mut data = Vec::with_capacity(SYNTHETIC_PACKETS * TS_PACKET_SIZE);
+    for i in 0..SYNTHETIC_PACKETS {
+        let counter = (i % 16) as u8;
+        if i % 10 == 9 {
+            data.extend_from_slice(&[0x47, 0x1F, 0xFF, 0x10 | counter]);
+            data.resize(data.len() + TS_PACKET_SIZE - 4, 0xFF);
+        } else {
+            data.extend_from_slice(&[0x47, 0x01, 0x00, 0x10 | counter]);
+            let start = data.len();
+            data.resize(start + TS_PACKET_SIZE - 4, 0);
+            rng.fill(&mut data[start..]);
+        }
+    }
+    data
+}
+
+/// 运行 `ITERATIONS` 次，返回最后一次的结果和最短耗时
+fn fastest<T>(mut f: impl FnMut() -> T) -> (T, Duration) {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..ITERATIONS {
+        let started = Instant::now();
+        result = Some(f());
+        best = best.min(started.elapsed());
+    }
+    (result.expect("at least one iteration"), best)
+}
+
+fn mib_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+fn bench(name: &str, data: &[u8]) {
+    println!(
+        "{} ({:.1} MiB)",
+        name,
+        data.len() as f64 / (1024.0 * 1024.0)
+    );
+    println!("  level  ratio   compress MiB/s  decompress MiB/s");
+    for level in LEVELS {
+        let (compressed, compress_time) = fastest(|| gzip(data, level).unwrap());
+        let (_, decompress_time) = fastest(|| {
+            let mut decompressed = Vec::with_capacity(data.len());
+            GzDecoder::new(compressed.as_slice())
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed.len(), data.len());
+        });
+        println!(
+            "  {:>5}  {:>5.1}%  {:>14.0}  {:>16.0}",
+            level,
+            compressed.len() as f64 / data.len() as f64 * 100.0,
+            mib_per_sec(data.len(), compress_time),
+            mib_per_sec(data.len(), decompress_time)
+        );
+    }
+}
+
+fn main() {
+    // cargo bench 会额外传入 --bench 参数
+    let files: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    if files.is_empty() {
+        bench("synthetic MPEG-TS segment", &synthetic_segment());
+    }
+    for file in files {
+        let data = std::fs::read(&file).unwrap_or_else(|e| panic!("{}: {}", file, e));
+        bench(&file, &data);
+    }
+}
@@ -0,0 +1,86 @@
+//! `--prefer-native-decrypt`：OpenSSL 与 `aes` crate 的 AES-CBC 解密速度
+//!
+//! 运行：`cargo bench --features openssl-crypto --bench decrypt_backends [-- 分段的MiB]`
+//!
+//! 对同一段随机数据分别以 128、192、256 位密钥加密，再用 `decrypt_with` 的两种实现解密。
+//! 两者都会在运行时检测 AES-NI，结果取决于 CPU 和系统 OpenSSL 的版本。
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockCipher, BlockEncryptMut, KeyInit, KeyIvInit};
+use m3u8_downloader_rs::crypto::{decrypt_with, DecryptBackend};
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+const IV: [u8; 16] = [0; 16];
+
+/// 默认的分段大小（MiB）
+const DEFAULT_SEGMENT_MIB: usize = 8;
+
+/// 每种组合的测量次数，取最快的一次
+const ITERATIONS: usize = 10;
+
+fn encrypt<C>(plain: &[u8], key: &[u8]) -> Vec<u8>
+where
+    C: BlockCipher + BlockEncryptMut + KeyInit,
+{
+    let mut buf = plain.to_vec();
+    buf.resize(plain.len() + 16, 0);
+    let len = cbc::Encryptor::<C>::new_from_slices(key, &IV)
+        .expect("valid key and IV length")
+        .encrypt_padded_mut::<Pkcs7>(&mut buf, plain.len())
+        .expect("buffer has room for padding")
+        .len();
+    buf.truncate(len);
+    buf
+}
+
+/// 运行 `ITERATIONS` 次，返回最短耗时
+fn fastest(mut f: impl FnMut()) -> Duration {
+    (0..ITERATIONS)
+        .map(|_| {
+            let started = Instant::now();
+            f();
+            started.elapsed()
+        })
+        .min()
+        .expect("at least one iteration")
+}
+
+fn mib_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+fn main() {
+    // cargo bench 会额外传入 --bench 参数
+    let mib = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .map(|arg| arg.parse().expect("argument is the segment size in MiB"))
+        .unwrap_or(DEFAULT_SEGMENT_MIB);
+    let mut plain = vec![0u8; mib * 1024 * 1024];
+    rand::rngs::StdRng::seed_from_u64(0x6d3375).fill(&mut plain[..]);
+
+    println!("{} MiB segment, AES-CBC decryption", mib);
+    println!("  key bits  RustCrypto MiB/s  OpenSSL MiB/s");
+    for bits in [128, 192, 256] {
+        let key: Vec<u8> = (0..bits / 8).map(|i| i as u8).collect();
+        let encrypted = match bits {
+            128 => encrypt::<aes::Aes128>(&plain, &key),
+            192 => encrypt::<aes::Aes192>(&plain, &key),
+            _ => encrypt::<aes::Aes256>(&plain, &key),
+        };
+        let measure = |backend| {
+            let elapsed = fastest(|| {
+                let decrypted = decrypt_with(backend, &encrypted, &key, &IV).unwrap();
+                assert_eq!(decrypted.len(), plain.len());
+            });
+            mib_per_sec(plain.len(), elapsed)
+        };
+        println!(
+            "  {:>8}  {:>16.0}  {:>13.0}",
+            bits,
+            measure(DecryptBackend::RustCrypto),
+            measure(DecryptBackend::OpenSsl)
+        );
+    }
+}
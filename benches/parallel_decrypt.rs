@@ -0,0 +1,139 @@
+//! `--parallel-key-decryption` 的取舍：内联解密与下载完成后的 rayon 并行解密
+//!
+//! 运行：`cargo bench --bench parallel_decrypt [-- 分段数 每个分段的MiB]`
+//!
+//! 不涉及网络，只测量下载数据到达之后的工作：
+//! - 内联：逐个分段解密后写入，即默认模式下每个下载任务在下载路径上多出的开销；
+//! - 延迟：下载阶段只写入加密数据，之后用 rayon 并行读取、解密、写入并删除临时文件。
+//!
+//! 分段为随机数据，以 AES-128-CBC 加密，写入系统临时目录。磁盘较慢时延迟模式多出的一次读写更明显。
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit};
+use m3u8_downloader_rs::crypto::decrypt_data;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const KEY: [u8; 16] = *b"0123456789abcdef";
+const IV: [u8; 16] = [0; 16];
+
+/// 默认的分段数和分段大小（MiB），共 128 MiB
+const DEFAULT_SEGMENTS: usize = 64;
+const DEFAULT_SEGMENT_MIB: usize = 2;
+
+/// 每种模式的测量次数，取最快的一次
+const ITERATIONS: usize = 3;
+
+fn encrypted_segments(count: usize, size: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x6d3375);
+    (0..count)
+        .map(|_| {
+            let mut buf = vec![0u8; size + 16];
+            rng.fill(&mut buf[..size]);
+            let len = cbc::Encryptor::<aes::Aes128>::new(&KEY.into(), &IV.into())
+                .encrypt_padded_mut::<Pkcs7>(&mut buf, size)
+                .expect("buffer has room for padding")
+                .len();
+            buf.truncate(len);
+            buf
+        })
+        .collect()
+}
+
+fn segment_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("index{:06}.ts", index))
+}
+
+/// 内联：解密后直接写入分段文件
+fn inline(dir: &Path, segments: &[Vec<u8>]) -> Duration {
+    let started = Instant::now();
+    for (i, data) in segments.iter().enumerate() {
+        let plain = decrypt_data(data, &KEY, &IV).unwrap();
+        std::fs::write(segment_path(dir, i), plain).unwrap();
+    }
+    started.elapsed()
+}
+
+/// 延迟：先写入 `.encrypted` 临时文件，再并行解密，返回两个阶段各自的耗时
+fn deferred(dir: &Path, segments: &[Vec<u8>]) -> (Duration, Duration) {
+    let started = Instant::now();
+    for (i, data) in segments.iter().enumerate() {
+        std::fs::write(segment_path(dir, i).with_extension("ts.encrypted"), data).unwrap();
+    }
+    let download_phase = started.elapsed();
+
+    let started = Instant::now();
+    (0..segments.len()).into_par_iter().for_each(|i| {
+        let path = segment_path(dir, i);
+        let encrypted_path = path.with_extension("ts.encrypted");
+        let data = std::fs::read(&encrypted_path).unwrap();
+        let plain = decrypt_data(&data, &KEY, &IV).unwrap();
+        std::fs::write(&path, plain).unwrap();
+        std::fs::remove_file(&encrypted_path).unwrap();
+    });
+    (download_phase, started.elapsed())
+}
+
+/// 在新的临时目录中运行 `ITERATIONS` 次，返回各阶段耗时之和最短的一次
+fn fastest<T: Copy>(mut f: impl FnMut(&Path) -> T, total: impl Fn(T) -> Duration) -> T {
+    let mut best: Option<T> = None;
+    for _ in 0..ITERATIONS {
+        let dir = std::env::temp_dir().join(format!("m3u8_bench_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let result = f(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if best.is_none_or(|best| total(result) < total(best)) {
+            best = Some(result);
+        }
+    }
+    best.expect("at least one iteration")
+}
+
+fn mib_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+fn main() {
+    // cargo bench 会额外传入 --bench 参数
+    let numbers: Vec<usize> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .map(|arg| {
+            arg.parse()
+                .expect("arguments are <segments> <MiB per segment>")
+        })
+        .collect();
+    let count = numbers.first().copied().unwrap_or(DEFAULT_SEGMENTS);
+    let size = numbers.get(1).copied().unwrap_or(DEFAULT_SEGMENT_MIB) * 1024 * 1024;
+    let segments = encrypted_segments(count, size);
+    let bytes = count * size;
+
+    println!(
+        "{} segments x {} MiB, AES-128-CBC, {} rayon threads",
+        count,
+        size / (1024 * 1024),
+        rayon::current_num_threads()
+    );
+    println!("  mode      download phase  decrypt phase  total     MiB/s");
+
+    let elapsed = fastest(|dir| inline(dir, &segments), |d| d);
+    println!(
+        "  inline    {:>12.3}s  {:>12}   {:>7.3}s  {:>6.0}",
+        elapsed.as_secs_f64(),
+        "-",
+        elapsed.as_secs_f64(),
+        mib_per_sec(bytes, elapsed)
+    );
+
+    let (download_phase, decrypt_phase) = fastest(|dir| deferred(dir, &segments), |(a, b)| a + b);
+    let total = download_phase + decrypt_phase;
+    println!(
+        "  deferred  {:>12.3}s  {:>12.3}s  {:>7.3}s  {:>6.0}",
+        download_phase.as_secs_f64(),
+        decrypt_phase.as_secs_f64(),
+        total.as_secs_f64(),
+        mib_per_sec(bytes, total)
+    );
+}
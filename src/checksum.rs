@@ -0,0 +1,208 @@
+use anyhow::{anyhow, Result};
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::fs;
+
+use crate::cli::HashAlgorithm;
+
+/// 分段校验和清单的默认文件名，扩展名与所用的校验和工具一致
+pub fn checksum_manifest_name(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Sha256 => "checksums.sha256",
+        #[cfg(feature = "blake3")]
+        HashAlgorithm::Blake3 => "checksums.b3",
+        #[cfg(feature = "xxhash")]
+        HashAlgorithm::Xxhash => "checksums.xxh128",
+    }
+}
+
+/// 计算单个文件的校验和（小写十六进制）
+async fn hash_file(path: PathBuf, algorithm: HashAlgorithm) -> Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha256 => Ok(sha256::try_async_digest(&path).await?),
+        #[cfg(feature = "blake3")]
+        HashAlgorithm::Blake3 => {
+            let data = fs::read(&path).await?;
+            Ok(
+                tokio::task::spawn_blocking(move || blake3::hash(&data).to_hex().to_string())
+                    .await?,
+            )
+        }
+        #[cfg(feature = "xxhash")]
+        HashAlgorithm::Xxhash => {
+            let data = fs::read(&path).await?;
+            Ok(format!("{:032x}", xxhash_rust::xxh3::xxh3_128(&data)))
+        }
+    }
+}
+
+/// 在下载任务中边下载边计算分段校验和，按分段索引记录
+#[derive(Debug)]
+pub struct SegmentHasher {
+    algorithm: HashAlgorithm,
+    hashes: Mutex<HashMap<usize, String>>,
+}
+
+impl SegmentHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 计算写完的分段文件的校验和；失败时只记录警告，生成清单时会重新计算
+    pub async fn record(&self, index: usize, path: &Path) {
+        match hash_file(path.to_path_buf(), self.algorithm).await {
+            Ok(hash) => {
+                self.hashes.lock().unwrap().insert(index, hash);
+            }
+            Err(e) => warn!("Failed to hash {:?}: {}", path, e),
+        }
+    }
+
+    /// 返回按 `file_names` 顺序排列的 (文件名, 校验和)
+    ///
+    /// `segment_files` 是按播放列表顺序排列的分段文件名，用于把文件名对应到分段索引；
+    /// 下载时没有记录的文件（占位分段、间隙分段等）在这里补算。
+    pub async fn checksums(
+        &self,
+        segments_dir: &Path,
+        segment_files: &[String],
+        file_names: &[String],
+    ) -> Result<Vec<(String, String)>> {
+        let indices: HashMap<&str, usize> = segment_files
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), i))
+            .collect();
+        let mut hashes = std::mem::take(&mut *self.hashes.lock().unwrap());
+
+        let mut checksums = Vec::with_capacity(file_names.len());
+        let mut missing = Vec::new();
+        for name in file_names {
+            match indices.get(name.as_str()).and_then(|i| hashes.remove(i)) {
+                Some(hash) => checksums.push((name.clone(), hash)),
+                None => missing.push(name.clone()),
+            }
+        }
+        checksums.extend(compute_checksums(segments_dir, &missing, self.algorithm).await?);
+
+        let positions: HashMap<&str, usize> = file_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), i))
+            .collect();
+        checksums.sort_by_key(|(name, _)| positions[name.as_str()]);
+        Ok(checksums)
+    }
+}
+
+/// 并行计算所有分段的校验和，返回按分段顺序排列的 (文件名, 校验和)
+pub async fn compute_checksums(
+    segments_dir: &Path,
+    file_names: &[String],
+    algorithm: HashAlgorithm,
+) -> Result<Vec<(String, String)>> {
+    let parallelism = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    let mut results: Vec<_> = stream::iter(file_names.iter().enumerate())
+        .map(|(i, file_name)| {
+            let file_name = file_name.clone();
+            let path = segments_dir.join(&file_name);
+            async move {
+                let hash = hash_file(path.clone(), algorithm)
+                    .await
+                    .map_err(|e| anyhow!("Failed to hash {:?}: {}", path, e))?;
+                Ok::<_, anyhow::Error>((i, file_name, hash))
+            }
+        })
+        .buffer_unordered(parallelism)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_>>()?;
+
+    results.sort_by_key(|(i, _, _)| *i);
+    Ok(results
+        .into_iter()
+        .map(|(_, file_name, hash)| (file_name, hash))
+        .collect())
+}
+
+/// 以 `sha256sum` 兼容的格式（`b3sum`、`xxhsum` 也使用同样的格式）写入校验和清单
+///
+/// 先写入临时文件再重命名，中断时不会留下不完整的清单。
+pub async fn write_checksum_manifest(checksums: &[(String, String)], path: &Path) -> Result<()> {
+    let content: String = checksums
+        .iter()
+        .map(|(file_name, hash)| format!("{}  {}\n", hash, file_name))
+        .collect();
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    fs::write(&part_path, content).await?;
+    fs::rename(&part_path, path).await?;
+    Ok(())
+}
+
+/// 读取 `sha256sum` 格式的校验和清单
+async fn read_checksum_manifest(path: &Path) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path).await?;
+    let mut manifest = HashMap::new();
+
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let (hash, file_name) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Malformed checksum manifest line: {}", line))?;
+        // sha256sum 在二进制模式下会在文件名前加 '*'
+        let file_name = file_name.trim_start().trim_start_matches('*');
+        manifest.insert(file_name.to_string(), hash.to_lowercase());
+    }
+
+    Ok(manifest)
+}
+
+/// 校验所有分段，一次性报告全部不匹配的分段
+pub async fn verify_segments(
+    segments_dir: &Path,
+    file_names: &[String],
+    manifest_path: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    let manifest = read_checksum_manifest(manifest_path).await?;
+    let checksums = compute_checksums(segments_dir, file_names, algorithm).await?;
+
+    let mut failures = Vec::new();
+    for (file_name, hash) in &checksums {
+        match manifest.get(file_name) {
+            Some(expected) if expected == hash => {}
+            Some(expected) => failures.push(format!(
+                "{}: expected {}, got {}",
+                file_name, expected, hash
+            )),
+            None => failures.push(format!("{}: missing from manifest", file_name)),
+        }
+    }
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            error!(" - {}", failure);
+        }
+        return Err(anyhow!(
+            "Checksum verification failed for {} out of {} segments",
+            failures.len(),
+            file_names.len()
+        ));
+    }
+
+    info!(
+        "All {} segments passed checksum verification.",
+        file_names.len()
+    );
+    Ok(())
+}
@@ -1,30 +1,576 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, ValueEnum};
+use clap_complete::Shell;
+use encoding_rs::Encoding;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use log::LevelFilter;
+
+use crate::downloader::DEFAULT_SEGMENT_FILENAME_PADDING;
+use crate::logging::LogRotation;
+use crate::util::expand_path;
+
+/// 分段合并方式
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergerKind {
+    /// Remux with FFmpeg.
+    Ffmpeg,
+    /// Concatenate MPEG-TS segments directly, without FFmpeg.
+    Native,
+}
+
+/// 分段下载顺序
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadOrder {
+    /// Download one segment at a time, in playlist order; ignores --threads.
+    Sequential,
+    /// Download up to --threads segments at once, in whatever order they finish.
+    Parallel,
+    /// Start downloads from the last segment to the first.
+    Reverse,
+}
+
+/// 输出容器格式
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// MPEG-4 (.mp4).
+    Mp4,
+    /// Matroska (.mkv).
+    Mkv,
+    /// MPEG transport stream (.ts).
+    Ts,
+}
+
+impl Container {
+    /// 该容器对应的文件扩展名
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Ts => "ts",
+        }
+    }
+}
+
+/// `--output-force-extension` 可用的扩展名，均可由扩展名推断出输出容器
+pub const OUTPUT_EXTENSIONS: &[&str] = &["mp4", "m4v", "mov", "mkv", "ts"];
+
+/// `--output-video` 的默认值
+pub const DEFAULT_OUTPUT_VIDEO: &str = "output_video.mp4";
+
+/// 视频流的输出编码
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// Copy the video stream without re-encoding.
+    #[default]
+    Copy,
+    /// Re-encode to H.264/AVC.
+    H264,
+    /// Re-encode to H.265/HEVC.
+    Hevc,
+    /// Re-encode to VP9.
+    Vp9,
+}
+
+/// 可按序号选择的轨道类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackType {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+/// FFmpeg 转码使用的硬件加速后端
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwareAccel {
+    /// VA-API (Intel/AMD on Linux), using /dev/dri/renderD128.
+    Vaapi,
+    /// NVIDIA NVENC/CUDA.
+    Nvenc,
+    /// Intel Quick Sync Video.
+    Qsv,
+}
+
+/// 分段校验和算法
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// SHA-256, compatible with sha256sum.
+    #[default]
+    Sha256,
+    /// BLAKE3, much faster on modern CPUs.
+    #[cfg(feature = "blake3")]
+    Blake3,
+    /// XXH3-128, fastest; non-cryptographic, detects corruption only.
+    #[cfg(feature = "xxhash")]
+    Xxhash,
+}
+
+/// 播放列表格式
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// HLS master or media playlist.
+    M3u8,
+    /// MPEG-DASH manifest.
+    #[cfg(feature = "dash")]
+    Mpd,
+}
+
+/// 图形界面主题
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuiTheme {
+    /// Light theme.
+    Light,
+    /// Dark theme.
+    Dark,
+    /// Follow the operating system theme.
+    System,
+}
 
 /// A multi-threaded M3U8 downloader implemented in Rust.
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
+#[command(group(
+    clap::ArgGroup::new("key_export")
+        .args(["write_raw_aes_key", "show_raw_key"])
+        .multiple(true)
+))]
 pub struct Args {
     /// The M3U8 URL to download.
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        default_value = "",
+        required_unless_present_any = ["input_file", "generate_m3u8_from_segments", "shell_completion"]
+    )]
     pub url: String,
 
+    /// Download every URL listed in this file (one per line, '#' starts a comment).
+    #[arg(long, value_name = "FILE")]
+    pub input_file: Option<PathBuf>,
+
+    /// With --input-file, write an XSPF playlist of the successfully downloaded files.
+    #[arg(long, value_name = "FILE", requires = "input_file")]
+    pub output_xml_playlist: Option<PathBuf>,
+
+    /// With --input-file, write an M3U8 playlist listing each successfully merged output file
+    /// as a segment, so the batch can be played or processed as one multi-title playlist.
+    #[arg(long, value_name = "FILE", requires = "input_file")]
+    pub output_m3u8_from_download: Option<PathBuf>,
+
+    /// With --input-file, give each job its own subdirectory of --output-dir, named after its
+    /// position in the file, or after the stream title and URL hash (<title>-<hash>, as with
+    /// --output-dir-per-download) when --output-video-from-title is set.
+    #[arg(long, requires = "input_file")]
+    pub concurrent_playlists_output_dir_per_job: bool,
+
+    /// Instead of downloading, write playlist.m3u8 for the index{N}.ts segments in DIR,
+    /// using each segment's probed duration.
+    #[arg(long, value_name = "DIR", conflicts_with = "input_file")]
+    pub generate_m3u8_from_segments: Option<PathBuf>,
+
     /// Directory to save the downloaded segments.
     #[arg(short, long, default_value = "output")]
     pub output_dir: PathBuf,
 
+    /// Resolve --output-dir to an absolute path against the current directory at startup and
+    /// log the resulting segment directory and output file paths.
+    #[arg(long)]
+    pub output_dir_abs: bool,
+
+    /// Name the segment/output subdirectory after the stream title, followed by the URL hash
+    /// (<title>-<hash>) so streams with the same title do not share a directory.
+    #[arg(long)]
+    pub output_dir_per_download: bool,
+
     /// Output video filename.
-    #[arg(long, default_value = "output_video.mp4")]
+    #[arg(long, default_value = DEFAULT_OUTPUT_VIDEO)]
     pub output_video: String,
 
-    /// Maximum number of concurrent downloads.
+    /// Name the output video after the playlist's #EXT-X-TITLE, the selected variant's NAME
+    /// attribute, or the last component of the playlist URL, in that order.
+    #[arg(long, conflicts_with = "output_video")]
+    pub output_video_from_title: bool,
+
+    /// Maximum number of concurrent downloads with --segment-download-order parallel or reverse.
     #[arg(short, long, default_value_t = 10)]
     pub threads: usize,
 
+    /// Adjust concurrency every 10 segments based on throughput and 429 responses, starting
+    /// at --threads.
+    #[arg(long)]
+    pub adaptive_threads: bool,
+
+    /// Upper bound for --adaptive-threads [default: twice --threads].
+    #[arg(long, value_name = "N", requires = "adaptive_threads")]
+    pub max_threads: Option<usize>,
+
+    /// Order in which segments are downloaded.
+    #[arg(long, value_enum, default_value_t = DownloadOrder::Sequential)]
+    pub segment_download_order: DownloadOrder,
+
+    /// Media sequence number of the first segment, overriding #EXT-X-MEDIA-SEQUENCE.
+    /// Useful for partial playlists, e.g. exported from browser devtools mid-stream.
+    #[arg(long, value_name = "N")]
+    pub playlist_start_number: Option<u64>,
+
+    /// Offset added to the segment's media sequence number when deriving the default IV.
+    /// Only applies when #EXT-X-KEY has no explicit IV attribute.
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    pub iv_offset: i64,
+
+    /// Percent-decode segment URIs before resolving them (for double-encoded URIs).
+    /// Falls back to the raw URI if decoding fails.
+    #[arg(long, conflicts_with = "segment_url_encode")]
+    pub segment_url_decode: bool,
+
+    /// Percent-encode raw Unicode characters and spaces in segment URIs before resolving them.
+    #[arg(long)]
+    pub segment_url_encode: bool,
+
+    /// Name segment files after the last path component of their URI instead of index{N}.ts.
+    #[arg(long)]
+    pub segment_filename_from_uri: bool,
+
+    /// Zero-pad the index in index{N}.ts segment names to N digits so that they sort in
+    /// playback order; 0 disables padding.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_SEGMENT_FILENAME_PADDING)]
+    pub segment_filename_padding: usize,
+
+    /// Use the local .ts/.m4s FILE as segment INDEX (0-based) instead of downloading it (repeatable).
+    #[arg(long, num_args = 2, value_names = ["INDEX", "FILE"], action = clap::ArgAction::Append)]
+    pub splice_in_local_segment: Vec<String>,
+
+    /// Store segments gzip-compressed on disk; they are decompressed when merging.
+    #[cfg(feature = "compress-segments")]
+    #[arg(long)]
+    pub write_compressed_segments: bool,
+
+    /// Gzip level for --write-compressed-segments: 0 stores without compressing, 1 is the
+    /// fastest and 9 the smallest. Levels above 3 rarely help for already-encoded video.
+    #[cfg(feature = "compress-segments")]
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value_t = crate::compress::DEFAULT_COMPRESSION_LEVEL,
+        value_parser = clap::value_parser!(u32).range(0..=9),
+        requires = "write_compressed_segments"
+    )]
+    pub output_compression_level: u32,
+
+    /// Verify segment checksums against this manifest before merging.
+    #[arg(long)]
+    pub checksum_manifest: Option<PathBuf>,
+
+    /// Write a checksum manifest of the downloaded segments.
+    #[arg(long)]
+    pub generate_checksums: bool,
+
+    /// With --generate-checksums, hash each segment in its download task right after it is
+    /// written instead of re-reading every segment once the download finishes.
+    #[arg(
+        long,
+        requires = "generate_checksums",
+        conflicts_with = "parallel_key_decryption"
+    )]
+    pub concurrent_segment_hashing: bool,
+
+    /// Hash algorithm for --checksum-manifest and --generate-checksums.
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Sha256)]
+    pub segment_hash_algo: HashAlgorithm,
+
+    /// Assemble segments from #EXT-X-PART partial segments (Low-Latency HLS).
+    #[arg(long)]
+    pub low_latency: bool,
+
+    /// Write per-segment timing data to segments.csv in the segment directory.
+    #[arg(long)]
+    pub output_timing_info: bool,
+
+    /// Write segments.json to the segment directory, listing each segment's index, URI,
+    /// local file, size, duration and encryption IV.
+    #[arg(long)]
+    pub write_segments_manifest: bool,
+
+    /// Before downloading, hard-link (or copy) segments whose URL matches one in DIR's
+    /// url_to_hash.json, written by --write-segments-manifest. DIR must still contain the
+    /// segments, i.e. the earlier run used --keep-segments or --no-merge.
+    #[arg(long, value_name = "DIR")]
+    pub reuse_segments_from: Option<PathBuf>,
+
+    /// Write parsed #EXT-X-DATERANGE metadata to a sidecar JSON file.
+    #[arg(long)]
+    pub enable_hls_date_range: bool,
+
+    /// Skip segments that fall entirely within an ad/interstitial date range.
+    #[arg(long)]
+    pub skip_ads: bool,
+
+    /// Timeout in seconds for playlist and key requests (segments use their own timeout).
+    #[arg(long, value_name = "SECS", default_value_t = 10.0, value_parser = parse_seconds)]
+    pub playlist_timeout: f64,
+
+    /// Extra seconds of segment timeout per MB of Content-Length, added to the 30s base timeout.
+    #[arg(long, value_name = "SECS", default_value_t = 5.0, value_parser = parse_seconds)]
+    pub segment_timeout_per_mb: f64,
+
+    /// Every SECS seconds, sync written segments (and the streaming merge output) to disk with
+    /// fsync so a crash or power loss loses at most SECS seconds of data. 0 disables it.
+    #[arg(long, value_name = "SECS", default_value_t = 0.0, value_parser = parse_seconds)]
+    pub flush_interval: f64,
+
+    /// Comma-separated HTTP status codes that trigger a segment retry.
+    #[arg(long, value_parser = parse_status_codes, default_value = "429,500,502,503,504")]
+    pub segment_retry_codes: HashSet<u16>,
+
+    /// Disable segment download retries.
+    #[arg(long)]
+    pub no_retry: bool,
+
+    /// Retry a failed playlist request up to N times with exponential backoff, independently
+    /// of the segment retry settings. Only network errors and 429/5xx responses are retried.
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub playlist_retry: u32,
+
+    /// Save encrypted segments as downloaded and decrypt them all in a parallel pass once the
+    /// download finishes, instead of decrypting each segment inline.
+    #[arg(long)]
+    pub parallel_key_decryption: bool,
+
+    /// Decrypt AES segments with the system OpenSSL library instead of the built-in
+    /// Rust implementation.
+    #[cfg(feature = "openssl-crypto")]
+    #[arg(long)]
+    pub prefer_native_decrypt: bool,
+
+    /// WARNING: exports a sensitive cryptographic key. Write the raw AES key fetched from
+    /// the key server to FILE (16 bytes for AES-128), e.g. for VLC or other decryptors.
+    /// Requires --confirm-key-export.
+    #[arg(long, value_name = "FILE", requires = "confirm_key_export")]
+    pub write_raw_aes_key: Option<PathBuf>,
+
+    /// Confirm that --write-raw-aes-key or --show-raw-key may export the decryption key.
+    #[arg(long, requires = "key_export")]
+    pub confirm_key_export: bool,
+
+    /// For each key, log the method, resolved URL, a fingerprint (first and last 2 bytes) of
+    /// the fetched key, the IV and the segments using it, to check which key/IV combination
+    /// is used.
+    #[arg(long)]
+    pub show_key_info: bool,
+
+    /// WARNING: logs a sensitive cryptographic key. Like --show-key-info, but log the full key
+    /// bytes. Requires --confirm-key-export.
+    #[arg(long, requires = "confirm_key_export")]
+    pub show_raw_key: bool,
+
+    /// Keep going when some segments fail to download; the failed segments are left out of
+    /// the merge.
+    #[arg(long)]
+    pub ignore_errors: bool,
+
+    /// Like --ignore-errors, but replace each failed segment with a black/silent MPEG-TS file
+    /// of the same duration (indexNNN.placeholder.ts, generated with FFmpeg) so the output
+    /// keeps its full length. Without a working FFmpeg the placeholder falls back to null
+    /// packets, which carry no timestamps and add no duration to the output.
+    #[arg(long)]
+    pub skip_segment_on_error: bool,
+
+    /// Warn when consecutive segments' #EXT-X-PROGRAM-DATE-TIME values are further apart than
+    /// the segment duration plus SECS, which indicates segments missing from the playlist.
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    pub segment_max_gap: Option<f64>,
+
+    /// With --segment-max-gap, fill each gap with black video and silence of the gap's
+    /// length, generated with FFmpeg (required), so the output keeps its real timeline.
+    #[arg(long, requires = "segment_max_gap")]
+    pub ignore_gaps: bool,
+
+    /// Maximum number of retries for each segment.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 2,
+        conflicts_with = "no_retry"
+    )]
+    pub max_retries_per_segment: u32,
+
+    /// Additional retries for the first segment, on top of --max-retries-per-segment.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "no_retry"
+    )]
+    pub extra_retries_for_first: u32,
+
+    /// Additional retries for the last segment, on top of --max-retries-per-segment.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "no_retry"
+    )]
+    pub extra_retries_for_last: u32,
+
+    /// Write #EXT-X-SESSION-DATA and #EXT-X-TITLE metadata next to the output video.
+    #[arg(long)]
+    pub write_description: bool,
+
+    /// How to merge the downloaded segments.
+    #[arg(long, value_enum, default_value_t = MergerKind::Ffmpeg)]
+    pub merger: MergerKind,
+
+    /// Force the output container format instead of inferring it from the file extension.
+    #[arg(long, value_enum)]
+    pub container: Option<Container>,
+
+    /// Before merging with FFmpeg, probe the first segment's codecs and check that the output
+    /// container can hold them (e.g. no VP9 in MP4). HEVC in MP4 is tagged hvc1 for Apple players.
+    #[arg(long)]
+    pub output_container_compatibility_check: bool,
+
+    /// When --output-video has no extension, pick mp4 or mkv based on the codecs found in the
+    /// first downloaded segment.
+    #[arg(long)]
+    pub output_format_auto: bool,
+
+    /// Download only the first segment (and its #EXT-X-MAP initialization segment), print
+    /// its container and streams as reported by ffprobe, and exit without downloading.
+    #[arg(long, conflicts_with = "input_file")]
+    pub list_media_info: bool,
+
+    /// Print --list-media-info as the raw ffprobe JSON instead of a table.
+    #[arg(long, requires = "list_media_info")]
+    pub json_output: bool,
+
+    /// Replace the extension of the output filename with EXT, whatever --output-video or
+    /// --output-video-from-title produced. One of mp4, m4v, mov, mkv or ts.
+    #[arg(long, value_name = "EXT", value_parser = parse_output_extension, conflicts_with = "output_format_auto")]
+    pub output_force_extension: Option<String>,
+
+    /// Embed the stream title, source URL and download date as container metadata tags
+    /// (FFmpeg merger only).
+    #[arg(long)]
+    pub output_video_metadata: bool,
+
+    /// Extract a JPEG thumbnail from the first downloaded segment with FFmpeg, written next
+    /// to the output video as <name>.jpg.
+    #[arg(long, visible_alias = "output-thumbnail-from-first-segment")]
+    pub write_thumbnail_from_segment: bool,
+
+    /// Seek this many seconds into the first segment before taking the thumbnail.
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds, default_value = "0", requires = "write_thumbnail_from_segment")]
+    pub thumbnail_time_offset: f64,
+
+    /// Scale the thumbnail to this size, e.g. 320x180.
+    #[arg(long, value_name = "WxH", value_parser = parse_dimensions, requires = "write_thumbnail_from_segment")]
+    pub thumbnail_size: Option<[u32; 2]>,
+
+    /// For an #EXT-X-IMAGES-ONLY (trick-play) playlist, download the first image segment and
+    /// save it as thumbnail.jpg in the output directory. When the image is an #EXT-X-TILES
+    /// sprite sheet, only the first tile is kept (cropped with FFmpeg).
+    #[arg(long)]
+    pub write_playlist_thumbnail: bool,
+
+    /// Embed chapter markers in the merged output (FFmpeg merger only).
+    /// Chapters start at each #EXT-X-DISCONTINUITY unless --chapter-interval is set.
+    /// Ignored when the output is split (--split-chapters without --concat-chapters-after, --split-size).
+    #[arg(long)]
+    pub output_chapters: bool,
+
+    /// Write chapter markers as a WebVTT file (<name>.chapters.vtt) next to the output video.
+    #[arg(long)]
+    pub output_chapters_vtt: bool,
+
+    /// Download the WebVTT subtitle track of the selected variant and convert it to SRT (<name>.<lang>.srt).
+    #[arg(long)]
+    pub write_subtitles: bool,
+
+    /// Subtitle language to download with --write-subtitles, e.g. en or zh-Hans; defaults to the DEFAULT track.
+    #[arg(long, value_name = "LANG", requires = "write_subtitles")]
+    pub subtitle_lang: Option<String>,
+
+    /// Place a chapter marker every N minutes instead of at discontinuities.
+    #[arg(long, value_name = "MINUTES")]
+    pub chapter_interval: Option<f64>,
+
+    /// Merge each #EXT-X-DISCONTINUITY group into its own file (<name>_001.<ext>, ...).
+    #[arg(long)]
+    pub split_chapters: bool,
+
+    /// Number of discontinuity groups to merge concurrently with --split-chapters.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "split_chapters"
+    )]
+    pub parallel_merge: usize,
+
+    /// After --split-chapters, also concatenate the group files into the output video.
+    #[arg(long, requires = "split_chapters")]
+    pub concat_chapters_after: bool,
+
+    /// Split the output into files of at most MB MiB each (<name>_part001.<ext>, ...), e.g.
+    /// 4000 for FAT32. Parts are cut at segment boundaries by uncompressed segment size; the
+    /// run fails rather than truncating if a segment or merged part is over the limit.
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "split_chapters")]
+    pub split_size: Option<u64>,
+
+    /// Also produce the output in this format (repeatable), e.g. --output-also ts.
+    /// Only the single full output is converted, never split group or part files.
+    #[arg(long, value_enum, value_name = "FORMAT", action = clap::ArgAction::Append)]
+    pub output_also: Vec<Container>,
+
+    /// Shorthand for --container mkv --output-chapters --chapter-interval 5.
+    #[arg(long)]
+    pub output_mkv_with_chapters: bool,
+
+    /// After merging, check with ffprobe that the audio duration matches the playlist (warns on mismatch).
+    #[arg(long)]
+    pub verify_audio: bool,
+
+    /// Like --verify-audio, but fail the download on a mismatch.
+    #[arg(long)]
+    pub verify_audio_strict: bool,
+
+    /// When the playlist declares #EXT-X-INDEPENDENT-SEGMENTS, check with ffprobe that the
+    /// first and last segments start with a keyframe.
+    #[arg(long)]
+    pub validate_output: bool,
+
     /// Path to the FFmpeg executable.
     #[arg(long)]
     pub ffmpeg_path: Option<PathBuf>,
 
+    /// Re-encode the video stream with this codec when merging instead of copying it.
+    #[arg(long, value_enum, value_name = "CODEC", default_value_t = VideoCodec::Copy)]
+    pub output_video_codec: VideoCodec,
+
+    /// Scale the video to WxH when --output-video-codec re-encodes it. Use -2 for one side to
+    /// keep the aspect ratio, e.g. 1920x-2 or -2x1080.
+    #[arg(long, value_name = "WxH", value_parser = parse_resolution, allow_hyphen_values = true)]
+    pub output_video_resolution: Option<[i32; 2]>,
+
+    /// Use GPU hardware acceleration when --output-video-codec re-encodes the video.
+    #[arg(long, value_enum, value_name = "BACKEND")]
+    pub ffmpeg_hardware_accel: Option<HardwareAccel>,
+
+    /// Keep only the MPEG-TS stream with this PID when merging (decimal or 0x-prefixed hex).
+    /// Can be repeated, e.g. once for the video PID and once for the audio PID. List the PIDs
+    /// of a segment with `ffprobe -show_programs index000000.ts`.
+    #[arg(long, value_name = "PID", value_parser = parse_pid, action = clap::ArgAction::Append, conflicts_with = "track_type")]
+    pub track_id: Vec<u16>,
+
+    /// Keep only the Nth (0-based) track of a type when merging, e.g. audio:1 for the second
+    /// audio track. Tracks of the other types (video, audio, subtitle) are all kept.
+    #[arg(long, value_name = "TYPE:N", value_parser = parse_track_type)]
+    pub track_type: Option<(TrackType, usize)>,
+
     /// Skip the merging step.
     #[arg(long)]
     pub no_merge: bool,
@@ -33,15 +579,546 @@ pub struct Args {
     #[arg(long)]
     pub keep_segments: bool,
 
+    /// Also write output.m3u8 next to the segments: a local, unencrypted HLS playlist that
+    /// can be served by any HTTP server. Implies keeping the segments.
+    #[arg(long)]
+    pub output_hls: bool,
+
+    /// Append each segment to the output as soon as it and all earlier segments are
+    /// downloaded, then delete it, so finished segments never pile up on disk. Requires
+    /// --merger native.
+    #[arg(long, conflicts_with_all = [
+        "keep_segments", "no_merge", "split_chapters", "parallel_key_decryption",
+        "skip_segment_on_error", "checksum_manifest", "generate_checksums", "validate_output",
+        "output_format_auto", "write_thumbnail_from_segment", "output_hls", "ignore_gaps",
+        "split_size",
+    ])]
+    pub concurrent_cleanup: bool,
+
+    /// Skip the download entirely when the output video already exists and is not empty.
+    #[arg(long)]
+    pub no_clobber: bool,
+
+    /// Limit the total download rate in bytes per second (accepts K/M/G suffixes).
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = parse_byte_size)]
+    pub limit_rate: Option<u64>,
+
+    /// Burst capacity for --limit-rate: bytes saved up while idle that may be spent above the rate.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_byte_size,
+        default_value = "0",
+        requires = "limit_rate"
+    )]
+    pub limit_rate_burst: u64,
+
     /// Custom HTTP header(s). E.g., -H "Cookie: mycookie"
     #[arg(short = 'H', long = "header", action = clap::ArgAction::Append)]
     pub headers: Vec<String>,
 
+    /// Cookie string to send, shorthand for -H "Cookie: VALUE". Repeated values are joined
+    /// with "; ".
+    #[arg(
+        short = 'c',
+        long = "cookie",
+        visible_alias = "cookie-header",
+        value_name = "VALUE",
+        action = clap::ArgAction::Append
+    )]
+    pub cookies: Vec<String>,
+
+    /// Keep cookies set by the server during this run and write them to FILE in
+    /// Netscape/Mozilla cookies.txt format when the download finishes.
+    #[arg(long, value_name = "FILE")]
+    pub write_cookies: Option<PathBuf>,
+
+    /// Send the cookies given with --cookie or -H "Cookie: ..." only to these hosts
+    /// (comma-separated), e.g. example.com,*.example.com; *.example.com matches every
+    /// subdomain. Other hosts, such as third-party CDNs, get no cookies. Defaults to the host
+    /// of --url and its subdomains.
+    #[arg(long, value_name = "DOMAINS", value_delimiter = ',', value_parser = parse_domain_pattern)]
+    pub cookie_domain_allowlist: Vec<String>,
+
+    /// HTTP/2 initial per-stream flow-control window in bytes (K/M suffixes allowed, max 2^31-1).
+    /// Larger windows help fast, high-latency links when the server speaks HTTP/2.
+    #[arg(long, value_name = "BYTES", value_parser = parse_http2_window)]
+    pub http2_initial_stream_window_size: Option<u32>,
+
+    /// HTTP/2 initial connection-wide flow-control window in bytes (K/M suffixes allowed,
+    /// max 2^31-1). Should be at least the stream window.
+    #[arg(long, value_name = "BYTES", value_parser = parse_http2_window)]
+    pub http2_initial_connection_window_size: Option<u32>,
+
+    /// Prefer IPv4 connections on dual-stack hosts.
+    #[arg(long, conflicts_with_all = ["prefer_ipv6", "source_address"])]
+    pub prefer_ipv4: bool,
+
+    /// Prefer IPv6 connections on dual-stack hosts.
+    #[arg(long, conflicts_with = "source_address")]
+    pub prefer_ipv6: bool,
+
+    /// Local IP address to bind outgoing connections to. On multi-NIC hosts this routes
+    /// all download traffic through the interface that owns the address; the address
+    /// must be assigned to a local interface.
+    #[arg(long, visible_alias = "bind-address", value_name = "IP")]
+    pub source_address: Option<IpAddr>,
+
+    /// Route requests to HOST through PROXY_URL (http, https, socks5 or socks5h), e.g.
+    /// cdn.example.com=socks5://localhost:1080. Can be repeated; other hosts connect directly.
+    #[arg(long, value_name = "HOST=PROXY_URL", value_parser = parse_host_proxy, action = clap::ArgAction::Append)]
+    pub proxy_per_host: Vec<(String, Url)>,
+
+    /// Keep retrying the playlist every 5 seconds for up to SECS seconds while it returns 404
+    /// (for scheduled streams that have not started yet).
+    #[arg(long, value_name = "SECS")]
+    pub wait_for_playlist: Option<u64>,
+
+    /// Add a random delay of up to SECS seconds to each playlist re-poll, so several
+    /// downloaders watching the same stream do not hit the CDN in lockstep. 0 disables it.
+    #[arg(long, value_name = "SECS", default_value_t = 2.0, value_parser = parse_seconds)]
+    pub playlist_refresh_jitter: f64,
+
+    /// Record a live stream: after downloading the current window, keep reloading the media
+    /// playlist and download new segments until it ends with #EXT-X-ENDLIST, then merge.
+    #[arg(long, conflicts_with_all = [
+        "concurrent_cleanup", "skip_ads", "enable_hls_date_range", "output_chapters_vtt",
+    ])]
+    pub live: bool,
+
+    /// Parse the media playlist one segment at a time and start downloading while later
+    /// segments are still being parsed, instead of holding every parsed segment before the
+    /// first download (for playlists with tens of thousands of segments). Options that need
+    /// the whole playlist before downloading cannot be combined with it.
+    #[arg(long, conflicts_with_all = [
+        "live", "list_media_info", "playlist_cache_dir", "wait_for_playlist", "skip_ads",
+        "enable_hls_date_range", "output_chapters_vtt", "segment_max_gap",
+        "write_playlist_thumbnail", "concurrent_cleanup", "splice_in_local_segment",
+        "reuse_segments_from", "segment_filename_from_uri",
+    ])]
+    pub stream_segments: bool,
+
+    /// With --live, reload the playlist every SECS seconds; defaults to the target duration.
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds, requires = "live")]
+    pub live_poll_interval: Option<f64>,
+
+    /// With --live, stop recording once SECS seconds of segments have been collected.
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds, requires = "live")]
+    pub live_max_duration: Option<f64>,
+
+    /// With --live, reload the playlist in a background task while segments download, keeping up
+    /// to N reloads buffered, so a new batch can start without waiting for the playlist request.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), requires = "live")]
+    pub concurrent_playlist_fetch: Option<u64>,
+
+    /// Resolve relative segment and key URIs against this URL instead of the playlist's own
+    /// URL; treated as a directory, so a trailing '/' is optional.
+    #[arg(long, value_name = "URL", value_parser = parse_base_url)]
+    pub playlist_base_url: Option<Url>,
+
+    /// Accept absolute segment and key URLs on a different host than the playlist (e.g. a
+    /// separate CDN domain). Cross-host URLs are currently always accepted; this flag keeps
+    /// that behaviour if hostname checks are added later.
+    #[arg(long)]
+    pub no_hostname_check: bool,
+
+    /// Fail a segment whose Content-Type does not look like media (e.g. text/html error
+    /// pages) instead of only logging a warning.
+    #[arg(long)]
+    pub strict_content_type: bool,
+
+    /// Character set of the playlist (e.g. gbk, shift_jis), overriding the HTTP Content-Type charset.
+    #[arg(long, value_name = "CHARSET", value_parser = parse_encoding)]
+    pub playlist_encoding: Option<&'static Encoding>,
+
+    /// Maximum size of a playlist response in bytes (K/M/G suffixes allowed).
+    #[arg(long, value_name = "BYTES", value_parser = parse_byte_size, default_value = "50M")]
+    pub max_playlist_size: u64,
+
+    /// Remove lines with these tags from the playlist before parsing, e.g.
+    /// EXT-X-TIMESTAMP,EXT-X-CUSTOM (for proprietary tags that break the parser).
+    #[arg(long, value_name = "TAGS", value_delimiter = ',')]
+    pub strip_hls_tags: Vec<String>,
+
+    /// Cache segment, init section and key responses on disk (keyed by URL) and reuse them
+    /// on later runs. Intended for development; cached responses never expire. Playlists are
+    /// never cached here (see --playlist-cache-dir).
+    #[arg(long, value_name = "DIR")]
+    pub http_cache_dir: Option<PathBuf>,
+
+    /// Directory for caching playlists between runs (uses conditional requests).
+    #[arg(long)]
+    pub playlist_cache_dir: Option<PathBuf>,
+
+    /// Format of the playlist at --url. Defaults to mpd when the URL path ends in .mpd
+    /// (requires the dash feature) and m3u8 otherwise.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub playlist_format: Option<PlaylistFormat>,
+
+    /// Also write log messages to this file. The file always receives at least debug-level
+    /// messages, regardless of RUST_LOG.
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file: 'daily' or 'size:<SIZE>' (e.g. size:10MB).
+    #[arg(long, value_name = "ROTATION", value_parser = parse_log_rotation, requires = "log_file")]
+    pub log_file_rotate: Option<LogRotation>,
+
+    /// Only print warnings and errors, and hide the progress bar.
+    #[arg(short = 'q', long, conflicts_with = "silent")]
+    pub quiet: bool,
+
+    /// Print nothing at all, not even errors; the exit code reports success or failure.
+    #[arg(long)]
+    pub silent: bool,
+
+    /// Append a JSON line describing each run to this local file.
+    /// Opt-in; nothing is ever sent over the network.
+    #[arg(long, value_name = "FILE")]
+    pub telemetry_file: Option<PathBuf>,
+
+    /// Shell command to run before fetching the playlist.
+    /// Receives M3U8_URL; a non-empty stdout replaces the URL, a non-zero exit aborts.
+    #[arg(long)]
+    pub pre_hook: Option<String>,
+
+    /// Shell command that rewrites the raw playlist text (stdin to stdout) before parsing.
+    #[arg(long)]
+    pub playlist_hook: Option<String>,
+
+    /// Shell command that re-signs each segment URL before it is downloaded.
+    /// Receives the original URL on stdin and prints the signed URL to stdout.
+    #[arg(long, value_name = "CMD")]
+    pub segment_presign_hook: Option<String>,
+
+    /// Shell command to run after a successful download.
+    /// Receives M3U8_OUTPUT_FILE, M3U8_SEGMENTS_DIR, M3U8_SOURCE_URL,
+    /// M3U8_DURATION and M3U8_SEGMENT_COUNT as environment variables.
+    #[arg(long)]
+    pub post_hook: Option<String>,
+
+    /// GUI color theme; overrides the theme saved from the last session.
+    #[arg(long, value_enum)]
+    pub gui_theme: Option<GuiTheme>,
+
+    /// Initial GUI window size, e.g. 1024x768; overrides the size saved from the last session.
+    #[arg(long, value_name = "WxH", value_parser = parse_window_size)]
+    pub gui_window_size: Option<[f32; 2]>,
+
+    /// Start the GUI window maximized.
+    #[arg(long)]
+    pub gui_maximized: bool,
+
+    /// Keep the GUI window above other windows; overrides the setting saved from the last session.
+    #[arg(long)]
+    pub gui_always_on_top: bool,
+
+    /// Print a shell completion script for SHELL to stdout and exit.
+    #[arg(long, value_name = "SHELL", value_enum)]
+    pub shell_completion: Option<Shell>,
+
     /// Start in GUI mode
     #[arg(long, default_value = "true")]
     pub gui: bool,
+
+    /// 快捷参数和路径是否已展开，保证 [`Args::expand`] 只生效一次
+    #[arg(skip)]
+    pub expanded: bool,
+}
+
+/// 解析逗号分隔的HTTP状态码列表
+fn parse_status_codes(value: &str) -> Result<HashSet<u16>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.parse::<u16>() {
+            Ok(code) if (100..=599).contains(&code) => Ok(code),
+            _ => Err(format!("invalid HTTP status code: {}", s)),
+        })
+        .collect()
+}
+
+/// 解析非负的秒数
+fn parse_seconds(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(secs),
+        _ => Err(format!("invalid number of seconds: {}", value)),
+    }
+}
+
+/// 按 WHATWG 标签解析字符集名称
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("unknown character set: {}", label))
+}
+
+/// 解析带可选 K/M/G 后缀（1024进制）的字节数
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid byte size: {}", value))
+}
+
+/// 解析 `--playlist-base-url` 参数，路径补全末尾的 `/`，使相对URI拼接在该目录下
+fn parse_base_url(value: &str) -> Result<Url, String> {
+    let mut url = Url::parse(value.trim()).map_err(|e| format!("invalid URL: {}", e))?;
+    if url.cannot_be_a_base() {
+        return Err(format!("'{}' cannot be used as a base URL", value));
+    }
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// 解析 `--proxy-per-host` 参数：`<主机名>=<代理URL>`
+fn parse_host_proxy(value: &str) -> Result<(String, Url), String> {
+    let (host, proxy) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid value '{}', expected HOST=PROXY_URL", value))?;
+    let host = host.trim().to_ascii_lowercase();
+    if host.is_empty() {
+        return Err(format!("missing host in '{}'", value));
+    }
+    let proxy = Url::parse(proxy.trim()).map_err(|e| format!("invalid proxy URL: {}", e))?;
+    if !matches!(proxy.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!(
+            "unsupported proxy scheme '{}', expected http, https, socks5 or socks5h",
+            proxy.scheme()
+        ));
+    }
+    Ok((host, proxy))
+}
+
+/// 解析 `--cookie-domain-allowlist` 中的一项：主机名，或以 `*.` 开头匹配子域名
+fn parse_domain_pattern(value: &str) -> Result<String, String> {
+    let pattern = value.trim().trim_end_matches('.').to_ascii_lowercase();
+    let host = pattern.strip_prefix("*.").unwrap_or(&pattern);
+    if host.is_empty() || host.contains(['*', '/', ':']) {
+        return Err(format!(
+            "invalid domain '{}', expected a host name such as example.com or *.example.com",
+            value
+        ));
+    }
+    Ok(pattern)
+}
+
+/// 解析 MPEG-TS PID，支持十进制和 `0x` 前缀的十六进制，PID 只有 13 位
+fn parse_pid(value: &str) -> Result<u16, String> {
+    const MAX_PID: u16 = 0x1FFF;
+    let value = value.trim();
+    let pid = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("invalid PID '{}'", value))?;
+    if pid > MAX_PID {
+        return Err(format!("PID must not exceed {} (0x1FFF)", MAX_PID));
+    }
+    Ok(pid)
+}
+
+/// 解析 `TYPE:N` 形式的轨道选择，TYPE 为 video、audio 或 subtitle
+fn parse_track_type(value: &str) -> Result<(TrackType, usize), String> {
+    let (kind, index) = value
+        .split_once(':')
+        .ok_or_else(|| format!("invalid value '{}', expected TYPE:N", value))?;
+    let kind = match kind.trim().to_ascii_lowercase().as_str() {
+        "video" | "v" => TrackType::Video,
+        "audio" | "a" => TrackType::Audio,
+        "subtitle" | "s" => TrackType::Subtitle,
+        other => {
+            return Err(format!(
+                "unknown track type '{}', expected video, audio or subtitle",
+                other
+            ))
+        }
+    };
+    let index = index
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid track index '{}'", index))?;
+    Ok((kind, index))
+}
+
+/// 解析 HTTP/2 流量控制窗口大小，协议规定的最大值为 2^31-1 字节
+fn parse_http2_window(value: &str) -> Result<u32, String> {
+    const MAX_WINDOW: u64 = (1 << 31) - 1;
+    match parse_byte_size(value)? {
+        0 => Err("window size must be greater than 0".to_string()),
+        bytes if bytes > MAX_WINDOW => Err(format!(
+            "window size must not exceed {} bytes (2^31-1)",
+            MAX_WINDOW
+        )),
+        bytes => Ok(bytes as u32),
+    }
+}
+
+/// 解析 `--log-file-rotate` 参数：`daily` 或 `size:<大小>`（如 `size:10MB`）
+fn parse_log_rotation(value: &str) -> Result<LogRotation, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("daily") {
+        return Ok(LogRotation::Daily);
+    }
+    let size = value.strip_prefix("size:").ok_or_else(|| {
+        format!(
+            "invalid rotation '{}', expected 'daily' or 'size:<SIZE>'",
+            value
+        )
+    })?;
+    match parse_byte_size(size.trim().trim_end_matches(['B', 'b']))? {
+        0 => Err("log rotation size must be greater than 0".to_string()),
+        bytes => Ok(LogRotation::Size(bytes)),
+    }
+}
+
+/// 解析 `<宽>x<高>` 形式的尺寸（如 `1024x768`）
+fn parse_dimensions(value: &str) -> Result<[u32; 2], String> {
+    let invalid = || format!("invalid size '{}', expected WxH (e.g. 1024x768)", value);
+    let (width, height) = value.trim().split_once(['x', 'X']).ok_or_else(invalid)?;
+    let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+    let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err("size must be greater than 0".to_string());
+    }
+    Ok([width, height])
+}
+
+/// 解析 `--output-video-resolution` 参数，单边为 `-2` 时由 FFmpeg 按宽高比计算
+fn parse_resolution(value: &str) -> Result<[i32; 2], String> {
+    let invalid = || {
+        format!(
+            "invalid resolution '{}', expected WxH with positive integers or -2 (e.g. 1920x-2)",
+            value
+        )
+    };
+    let (width, height) = value.trim().split_once(['x', 'X']).ok_or_else(invalid)?;
+    let parse_side = |side: &str| match side.trim().parse::<i32>() {
+        Ok(n) if n > 0 || n == -2 => Ok(n),
+        _ => Err(invalid()),
+    };
+    match [parse_side(width)?, parse_side(height)?] {
+        [-2, -2] => Err("only one side of the resolution can be -2".to_string()),
+        resolution => Ok(resolution),
+    }
+}
+
+/// 解析 `--output-force-extension` 参数，允许带前导点，统一转为小写
+fn parse_output_extension(value: &str) -> Result<String, String> {
+    let extension = value.trim().trim_start_matches('.').to_ascii_lowercase();
+    if OUTPUT_EXTENSIONS.contains(&extension.as_str()) {
+        Ok(extension)
+    } else {
+        Err(format!(
+            "unsupported output extension '{}', expected one of: {}",
+            value,
+            OUTPUT_EXTENSIONS.join(", ")
+        ))
+    }
+}
+
+/// 解析 `--gui-window-size` 参数
+fn parse_window_size(value: &str) -> Result<[f32; 2], String> {
+    let [width, height] = parse_dimensions(value)?;
+    Ok([width as f32, height as f32])
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        // 复用clap中声明的默认值，避免在多处维护默认参数
+        Args::parse_from(["m3u8_downloader_rs", "--url", ""])
+    }
+}
+
+impl Args {
+    /// 展开组合型快捷参数和路径参数，`run()` 开始时调用；已展开时不做任何事，可重复调用
+    pub fn expand(&mut self) {
+        if self.expanded {
+            return;
+        }
+        self.expand_shorthands();
+        self.expand_paths();
+        self.expanded = true;
+    }
+
+    /// 将组合型快捷参数展开为对应的各个参数
+    fn expand_shorthands(&mut self) {
+        if !self.cookies.is_empty() {
+            let cookies = std::mem::take(&mut self.cookies);
+            let cookies: Vec<&str> = cookies
+                .iter()
+                .map(|c| c.trim().trim_end_matches(';').trim())
+                .filter(|c| !c.is_empty())
+                .collect();
+            self.headers.push(format!("Cookie: {}", cookies.join("; ")));
+        }
+
+        if self.output_mkv_with_chapters {
+            self.container = Some(Container::Mkv);
+            self.output_chapters = true;
+            self.chapter_interval.get_or_insert(5.0);
+
+            // 仍为默认文件名或没有扩展名时改为 .mkv
+            let output_video = Path::new(&self.output_video);
+            if self.output_video == DEFAULT_OUTPUT_VIDEO || output_video.extension().is_none() {
+                self.output_video = output_video
+                    .with_extension(Container::Mkv.extension())
+                    .to_string_lossy()
+                    .to_string();
+            }
+        }
+    }
+
+    /// `--quiet` 或 `--silent` 时终端日志使用的级别，其余情况遵循 `RUST_LOG`
+    pub fn console_log_level(&self) -> Option<LevelFilter> {
+        if self.silent {
+            Some(LevelFilter::Off)
+        } else if self.quiet {
+            Some(LevelFilter::Warn)
+        } else {
+            None
+        }
+    }
+
+    /// 展开路径参数中的 `~` 和环境变量
+    fn expand_paths(&mut self) {
+        self.output_dir = expand_path(&self.output_dir.to_string_lossy());
+        self.output_video = expand_path(&self.output_video)
+            .to_string_lossy()
+            .to_string();
+        if let Some(log_file) = &self.log_file {
+            self.log_file = Some(expand_path(&log_file.to_string_lossy()));
+        }
+        if let Some(write_cookies) = &self.write_cookies {
+            self.write_cookies = Some(expand_path(&write_cookies.to_string_lossy()));
+        }
+        if let Some(write_raw_aes_key) = &self.write_raw_aes_key {
+            self.write_raw_aes_key = Some(expand_path(&write_raw_aes_key.to_string_lossy()));
+        }
+        if let Some(ffmpeg_path) = &self.ffmpeg_path {
+            self.ffmpeg_path = Some(expand_path(&ffmpeg_path.to_string_lossy()));
+        }
+    }
 }
 
+/// 解析命令行参数并展开，日志文件等在 `run()` 之前使用的路径因此已展开
 pub fn parse_args() -> Args {
-    Args::parse()
+    let mut args = Args::parse();
+    args.expand();
+    args
 }
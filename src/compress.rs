@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, SeekFrom, Write};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// 压缩分段文件的扩展名
+pub const COMPRESSED_EXTENSION: &str = "gz";
+
+/// 判断分段文件是否为 gzip 压缩格式
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == COMPRESSED_EXTENSION)
+}
+
+/// 默认的 gzip 压缩级别：已编码的视频数据在更高级别下几乎压不动，最快的级别性价比最高
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 1;
+
+/// 以指定级别（0-9，0 为只存储不压缩）使用 gzip 压缩数据
+pub fn gzip(data: &[u8], level: u32) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// gzip 压缩分段解压后的大小，取自文件末尾的 ISIZE 字段（原始大小对 2^32 取模，分段远小于此）
+pub async fn decompressed_size(path: &Path) -> Result<u64> {
+    let mut file = fs::File::open(path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {:?}: {}", path, e))?;
+    file.seek(SeekFrom::End(-4))
+        .await
+        .map_err(|e| anyhow!("{:?} is not a gzip file: {}", path, e))?;
+    let mut size = [0; 4];
+    file.read_exact(&mut size).await?;
+    Ok(u32::from_le_bytes(size).into())
+}
+
+/// 读取并解压 gzip 压缩的分段文件
+pub async fn read_decompressed(path: &Path) -> Result<Vec<u8>> {
+    let compressed = fs::read(path).await?;
+    let mut data = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut data)
+        .map_err(|e| anyhow!("Failed to decompress {:?}: {}", path, e))?;
+    Ok(data)
+}
@@ -1,16 +1,79 @@
-use anyhow::{anyhow, Result};
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockCipher, BlockDecryptMut, KeyInit, KeyIvInit};
+use anyhow::{anyhow, bail, Result};
 
-/// 解密数据
+/// AES-CBC 解密所使用的实现
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecryptBackend {
+    /// 纯 Rust 的 `aes` + `cbc` 实现
+    #[default]
+    RustCrypto,
+    /// 通过 FFI 调用系统 OpenSSL
+    #[cfg(feature = "openssl-crypto")]
+    OpenSsl,
+}
+
+/// `#EXT-X-KEY` 的 METHOD 要求的密钥长度（字节），不是 AES-CBC 的方法返回 `None`
+pub fn key_len_for_method(method: &str) -> Option<usize> {
+    match method.to_ascii_uppercase().as_str() {
+        "AES-128" => Some(16),
+        "AES-192" => Some(24),
+        "AES-256" => Some(32),
+        _ => None,
+    }
+}
+
+/// 解密数据，按密钥长度（16、24 或 32 字节）选择 AES-128、AES-192 或 AES-256
 pub fn decrypt_data(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
-    use aes::cipher::block_padding::Pkcs7;
-    use aes::cipher::{BlockDecryptMut, KeyIvInit};
-    use cbc::Decryptor;
+    match key.len() {
+        16 => decrypt_cbc::<aes::Aes128>(encrypted_data, key, iv),
+        24 => decrypt_cbc::<aes::Aes192>(encrypted_data, key, iv),
+        32 => decrypt_cbc::<aes::Aes256>(encrypted_data, key, iv),
+        len => bail!("Unsupported AES key length: {} bytes", len),
+    }
+}
 
-    let cipher = Decryptor::<aes::Aes128>::new(key.into(), iv.into());
+/// 以指定的分组密码做 CBC 解密并去除 PKCS#7 填充
+fn decrypt_cbc<C>(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>>
+where
+    C: BlockCipher + BlockDecryptMut + KeyInit,
+{
+    let cipher = cbc::Decryptor::<C>::new_from_slices(key, iv)
+        .map_err(|e| anyhow!("Invalid key or IV: {}", e))?;
     let mut buf = encrypted_data.to_vec();
-    let decrypted_slice = cipher
+    let decrypted_len = cipher
         .decrypt_padded_mut::<Pkcs7>(&mut buf)
-        .map_err(|e| anyhow!("Decryption error: {}", e))?;
+        .map_err(|e| anyhow!("Decryption error: {}", e))?
+        .len();
+    buf.truncate(decrypted_len);
+
+    Ok(buf)
+}
+
+/// 使用 OpenSSL 解密数据
+#[cfg(feature = "openssl-crypto")]
+pub fn decrypt_data_openssl(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    use openssl::symm::{decrypt, Cipher};
+
+    let cipher = match key.len() {
+        16 => Cipher::aes_128_cbc(),
+        24 => Cipher::aes_192_cbc(),
+        32 => Cipher::aes_256_cbc(),
+        len => bail!("Unsupported AES key length: {} bytes", len),
+    };
+    decrypt(cipher, key, Some(iv), encrypted_data).map_err(|e| anyhow!("Decryption error: {}", e))
+}
 
-    Ok(decrypted_slice.to_vec())
+/// 使用指定的实现解密数据
+pub fn decrypt_with(
+    backend: DecryptBackend,
+    encrypted_data: &[u8],
+    key: &[u8],
+    iv: &[u8],
+) -> Result<Vec<u8>> {
+    match backend {
+        DecryptBackend::RustCrypto => decrypt_data(encrypted_data, key, iv),
+        #[cfg(feature = "openssl-crypto")]
+        DecryptBackend::OpenSsl => decrypt_data_openssl(encrypted_data, key, iv),
+    }
 }
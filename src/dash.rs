@@ -0,0 +1,383 @@
+use anyhow::{anyhow, bail, Result};
+use dash_mpd::{AdaptationSet, BaseURL, Period, Representation, SegmentList, SegmentTemplate, MPD};
+use log::{info, warn};
+use m3u8_rs::{ExtTag, MediaPlaylist, MediaPlaylistType, MediaSegment};
+use std::sync::Arc;
+use url::Url;
+
+use crate::downloader::segment_file_names;
+use crate::http::CachingClient;
+use crate::playlist::{fetch_content, tag_name, FetchedPlaylist, PlaylistOptions};
+
+/// 标记作为独立分段列出的初始化分段，只在程序内部使用，不对应任何 HLS 标签
+pub const INIT_SEGMENT_TAG: &str = "X-DASH-INIT";
+
+/// 获取并解析 MPEG-DASH 清单，转换为媒体播放列表，之后的下载、解密和合并流程与 HLS 共用
+pub async fn fetch_and_parse_mpd(
+    client: Arc<CachingClient>,
+    url: Url,
+    options: &PlaylistOptions,
+) -> Result<FetchedPlaylist> {
+    info!("Fetching MPD manifest from {}", url);
+    let (final_url, content) = fetch_content(&client, &url, false, options).await?;
+    let mpd = dash_mpd::parse(&String::from_utf8_lossy(&content))
+        .map_err(|e| anyhow!("Failed to parse MPD manifest: {}", e))?;
+
+    let base_url = options.base_url.clone().unwrap_or(final_url.clone());
+    info!("Resolving segment URIs against: {}", base_url);
+    let media_playlist = mpd_to_media_playlist(&mpd, &base_url)?;
+    Ok(FetchedPlaylist {
+        segment_keys: vec![None; media_playlist.segments.len()],
+        media_playlist,
+        url: final_url,
+        base_url,
+        session_data: Vec::new(),
+        variant_name: None,
+        subtitles: Vec::new(),
+    })
+}
+
+/// 将 MPD 清单转换为媒体播放列表，分段 URI 均为绝对 URL
+///
+/// 每个周期选择带宽最高的视频表示，其初始化分段作为该周期的第一个分段，按字节拼接即可得到
+/// 完整的 fMP4。只支持点播（static）清单，不支持 DRM 加密的流；独立的音频自适应集不会被下载。
+pub fn mpd_to_media_playlist(mpd: &MPD, manifest_url: &Url) -> Result<MediaPlaylist> {
+    if mpd.mpdtype.as_deref() == Some("dynamic") {
+        bail!("Live (dynamic) MPD manifests are not supported");
+    }
+
+    let mpd_base = join_base_url(manifest_url, &mpd.base_url)?;
+    let mut segments = Vec::new();
+    let mut has_separate_audio = false;
+    for (i, period) in mpd.periods.iter().enumerate() {
+        let (adaptation, representation) = select_representation(period)
+            .ok_or_else(|| anyhow!("Period {} has no representations", i))?;
+        if !adaptation.ContentProtection.is_empty() || !representation.ContentProtection.is_empty()
+        {
+            bail!("DRM-protected DASH streams (ContentProtection) are not supported");
+        }
+        info!(
+            "Period {}: selected representation {} with bandwidth: {}",
+            i,
+            representation.id.as_deref().unwrap_or("<no id>"),
+            representation.bandwidth.unwrap_or_default()
+        );
+        has_separate_audio |= is_video(adaptation, representation)
+            && period
+                .adaptations
+                .iter()
+                .any(|a| a.representations.iter().any(|r| is_audio(a, r)));
+
+        let base = join_base_url(&mpd_base, &period.BaseURL)?;
+        let base = join_base_url(&base, &adaptation.BaseURL)?;
+        let base = join_base_url(&base, &representation.BaseURL)?;
+        // 单周期清单的周期通常不写时长，此时使用整个清单的时长
+        let period_duration = period
+            .duration
+            .or(mpd
+                .mediaPresentationDuration
+                .filter(|_| mpd.periods.len() == 1))
+            .map(|d| d.as_secs_f64());
+
+        let mut period_segments =
+            representation_segments(period, adaptation, representation, &base, period_duration)?;
+        if let (true, Some(first)) = (i > 0, period_segments.first_mut()) {
+            first.discontinuity = true;
+        }
+        segments.extend(period_segments);
+    }
+
+    if segments.is_empty() {
+        bail!("The MPD manifest has no segments");
+    }
+    if has_separate_audio {
+        warn!("Only the video representation is downloaded; separate audio adaptation sets are ignored.");
+    }
+
+    Ok(MediaPlaylist {
+        target_duration: segments
+            .iter()
+            .map(|s| s.duration.ceil() as u64)
+            .max()
+            .unwrap_or_default(),
+        segments,
+        end_list: true,
+        playlist_type: Some(MediaPlaylistType::Vod),
+        ..Default::default()
+    })
+}
+
+/// 依次拼接各层级的第一个 `BaseURL`，没有时沿用上一层的URL
+fn join_base_url(base: &Url, base_urls: &[BaseURL]) -> Result<Url> {
+    match base_urls.first() {
+        Some(base_url) => Ok(base.join(&base_url.base)?),
+        None => Ok(base.clone()),
+    }
+}
+
+fn content_type_is(
+    adaptation: &AdaptationSet,
+    representation: &Representation,
+    kind: &str,
+) -> bool {
+    [
+        &adaptation.contentType,
+        &adaptation.mimeType,
+        &representation.contentType,
+        &representation.mimeType,
+    ]
+    .iter()
+    .any(|value| value.as_deref().is_some_and(|v| v.starts_with(kind)))
+}
+
+fn is_video(adaptation: &AdaptationSet, representation: &Representation) -> bool {
+    content_type_is(adaptation, representation, "video")
+}
+
+fn is_audio(adaptation: &AdaptationSet, representation: &Representation) -> bool {
+    content_type_is(adaptation, representation, "audio")
+}
+
+/// 选择周期内带宽最高的视频表示，没有视频表示时在全部表示中选择
+fn select_representation(period: &Period) -> Option<(&AdaptationSet, &Representation)> {
+    let candidates = || {
+        period
+            .adaptations
+            .iter()
+            .flat_map(|a| a.representations.iter().map(move |r| (a, r)))
+    };
+    candidates()
+        .filter(|(a, r)| is_video(a, r))
+        .max_by_key(|(_, r)| r.bandwidth)
+        .or_else(|| candidates().max_by_key(|(_, r)| r.bandwidth))
+}
+
+/// 生成单个表示的分段列表，分段模板和分段列表按表示、自适应集、周期的顺序继承
+fn representation_segments(
+    period: &Period,
+    adaptation: &AdaptationSet,
+    representation: &Representation,
+    base: &Url,
+    period_duration: Option<f64>,
+) -> Result<Vec<MediaSegment>> {
+    let template = representation
+        .SegmentTemplate
+        .as_ref()
+        .or(adaptation.SegmentTemplate.as_ref())
+        .or(period.SegmentTemplate.as_ref());
+    if let Some(template) = template {
+        return template_segments(template, representation, base, period_duration);
+    }
+
+    let list = representation
+        .SegmentList
+        .as_ref()
+        .or(adaptation.SegmentList.as_ref());
+    if let Some(list) = list {
+        return list_segments(list, base);
+    }
+
+    // 只有 BaseURL（可能带 SegmentBase 索引）时，整个表示就是一个文件
+    if representation.BaseURL.is_empty() {
+        bail!("Representation has no segment information");
+    }
+    Ok(vec![media_segment(
+        base,
+        "",
+        period_duration.unwrap_or_default(),
+    )?])
+}
+
+/// 按 `SegmentTemplate` 展开分段，支持 `SegmentTimeline` 和固定时长两种方式
+fn template_segments(
+    template: &SegmentTemplate,
+    representation: &Representation,
+    base: &Url,
+    period_duration: Option<f64>,
+) -> Result<Vec<MediaSegment>> {
+    let timescale = template.timescale.filter(|&t| t > 0).unwrap_or(1) as f64;
+    let start_number = template.startNumber.unwrap_or(1);
+    let media = template
+        .media
+        .as_deref()
+        .ok_or_else(|| anyhow!("SegmentTemplate has no media attribute"))?;
+
+    let mut segments = Vec::new();
+    if let Some(initialization) = &template.initialization {
+        let uri = expand_template(initialization, representation, start_number, 0);
+        segments.push(init_segment(base, &uri)?);
+    }
+
+    if let Some(timeline) = &template.SegmentTimeline {
+        let mut time = 0;
+        let mut number = start_number;
+        for s in &timeline.segments {
+            time = s.t.unwrap_or(time);
+            let repeat = match s.r {
+                Some(r) if r >= 0 => r as u64,
+                // 负数表示重复到周期结束
+                Some(_) => {
+                    let end = period_duration.ok_or_else(|| {
+                        anyhow!("SegmentTimeline repeats to the end of a period without a duration")
+                    })? * timescale;
+                    ((end - time as f64) / s.d as f64).ceil().max(1.0) as u64 - 1
+                }
+                None => 0,
+            };
+            for _ in 0..=repeat {
+                let uri = expand_template(media, representation, number, time);
+                segments.push(media_segment(base, &uri, s.d as f64 / timescale)?);
+                time += s.d;
+                number += 1;
+            }
+        }
+    } else {
+        let duration = template.duration.filter(|&d| d > 0.0).ok_or_else(|| {
+            anyhow!("SegmentTemplate has neither a SegmentTimeline nor a duration")
+        })?;
+        let total = period_duration
+            .ok_or_else(|| anyhow!("Cannot count segments of a period without a duration"))?;
+        let segment_secs = duration / timescale;
+        let count = (total / segment_secs).ceil() as u64;
+        for i in 0..count {
+            let uri = expand_template(
+                media,
+                representation,
+                start_number + i,
+                (i as f64 * duration) as u64,
+            );
+            let secs = segment_secs.min(total - i as f64 * segment_secs);
+            segments.push(media_segment(base, &uri, secs)?);
+        }
+    }
+    Ok(segments)
+}
+
+/// 按 `SegmentList` 中的 `SegmentURL` 生成分段
+fn list_segments(list: &SegmentList, base: &Url) -> Result<Vec<MediaSegment>> {
+    let timescale = list.timescale.filter(|&t| t > 0).unwrap_or(1) as f64;
+    let duration = list.duration.unwrap_or_default() as f64 / timescale;
+
+    let mut segments = Vec::new();
+    if let Some(initialization) = list.Initialization.as_ref() {
+        let uri = initialization
+            .sourceURL
+            .as_deref()
+            .filter(|_| initialization.range.is_none())
+            .ok_or_else(|| anyhow!("Byte-range initialization segments are not supported"))?;
+        segments.push(init_segment(base, uri)?);
+    }
+    for segment_url in &list.segment_urls {
+        let uri = segment_url
+            .media
+            .as_deref()
+            .filter(|_| segment_url.mediaRange.is_none())
+            .ok_or_else(|| anyhow!("Byte-range SegmentURL entries are not supported"))?;
+        segments.push(media_segment(base, uri, duration)?);
+    }
+    Ok(segments)
+}
+
+fn media_segment(base: &Url, uri: &str, duration: f64) -> Result<MediaSegment> {
+    Ok(MediaSegment {
+        uri: base.join(uri)?.to_string(),
+        duration: duration as f32,
+        ..Default::default()
+    })
+}
+
+/// 初始化分段，排在所属周期的媒体分段之前，合并时先写入输出
+fn init_segment(base: &Url, uri: &str) -> Result<MediaSegment> {
+    Ok(MediaSegment {
+        unknown_tags: vec![ExtTag {
+            tag: INIT_SEGMENT_TAG.to_string(),
+            rest: None,
+        }],
+        ..media_segment(base, uri, 0.0)?
+    })
+}
+
+/// 是否为 `mpd_to_media_playlist` 生成的初始化分段
+pub fn is_init_segment(segment: &MediaSegment) -> bool {
+    segment
+        .unknown_tags
+        .iter()
+        .any(|tag| tag_name(tag) == INIT_SEGMENT_TAG)
+}
+
+/// DASH 分段的本地文件名：初始化分段为 `init{N}.mp4`，媒体分段为 `index{N}.m4s`
+///
+/// 序号按 `padding` 补零，`from_uri` 时与 HLS 相同，使用分段URI中的文件名。
+pub fn dash_segment_file_names(
+    segments: &[MediaSegment],
+    from_uri: bool,
+    padding: usize,
+) -> Vec<String> {
+    if from_uri {
+        return segment_file_names(segments, true, padding);
+    }
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            let (prefix, extension) = if is_init_segment(segment) {
+                ("init", "mp4")
+            } else {
+                ("index", "m4s")
+            };
+            format!(
+                "{}{:0>padding$}.{}",
+                prefix,
+                i,
+                extension,
+                padding = padding
+            )
+        })
+        .collect()
+}
+
+/// 替换 URL 模板中的 `$RepresentationID$`、`$Bandwidth$`、`$Number$`、`$Time$` 和 `$$`，
+/// 数值标识符可带 `%0<宽度>d` 格式，无法识别的标识符原样保留
+pub fn expand_template(
+    template: &str,
+    representation: &Representation,
+    number: u64,
+    time: u64,
+) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('$') else {
+            rest = &rest[start..];
+            break;
+        };
+        let identifier = &after[..end];
+        let (name, format) = identifier.split_once('%').unwrap_or((identifier, ""));
+        match name {
+            "" => expanded.push('$'),
+            "RepresentationID" => expanded.push_str(representation.id.as_deref().unwrap_or("")),
+            "Bandwidth" => expanded.push_str(&format_number(
+                representation.bandwidth.unwrap_or_default(),
+                format,
+            )),
+            "Number" => expanded.push_str(&format_number(number, format)),
+            "Time" => expanded.push_str(&format_number(time, format)),
+            _ => expanded.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// 按 `0<宽度>d` 格式零填充数值
+fn format_number(value: u64, format: &str) -> String {
+    let width = format
+        .trim_start_matches('0')
+        .strip_suffix('d')
+        .and_then(|w| w.parse().ok())
+        .unwrap_or(0);
+    format!("{:0width$}", value, width = width)
+}
@@ -1,28 +1,501 @@
 use anyhow::{anyhow, Result};
-use futures::stream::{self, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
-use log::debug;
-use m3u8_rs::MediaSegment;
-use reqwest::Client;
+use futures::stream::{self, Stream, StreamExt};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, info, warn};
+use m3u8_rs::{Map, MediaSegment};
+use rayon::prelude::*;
+use reqwest::header::{CONTENT_TYPE, RANGE};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinHandle};
 use url::Url;
 
-use crate::crypto::decrypt_data;
-use crate::playlist::KeyInfo;
+use crate::checksum::SegmentHasher;
+use crate::cli::DownloadOrder;
+use crate::crypto::{decrypt_with, key_len_for_method, DecryptBackend};
+use crate::error::M3u8Error;
+use crate::hooks::SegmentPresigner;
+use crate::http::{
+    is_retryable_error, CachingClient, RetryPolicy, DEFAULT_TIMEOUT, RETRY_INITIAL_DELAY,
+};
+use crate::metadata::{read_url_hash_index, segment_url_hash};
+use crate::playlist::{next_segment_key, segment_parts, FetchedPlaylist, KeyInfo};
+use crate::subtitle::vtt_segment_file_name;
+use crate::util::{sanitize_filename, ConcurrencyController, DiskFlusher, TokenBucket};
 
-/// 下载所有分段
+/// 可接受的分段 Content-Type 类型前缀（不区分大小写）
+const SEGMENT_CONTENT_TYPE_PREFIXES: &[&str] = &["video/", "audio/"];
+
+/// 其他可接受的分段 Content-Type（不区分大小写），CDN 常用各种 octet-stream 变体
+const SEGMENT_CONTENT_TYPES: &[&str] = &[
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/x-octet-stream",
+    "application/mp4",
+    "application/vnd.apple.mpegurl",
+];
+
+/// 分段请求本身的超时上限，实际超时由按分段大小计算的期限控制
+const SEGMENT_REQUEST_TIMEOUT_CAP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// 未返回 Content-Length 且启用限速时，按此大小估算分段的下载时间
+const CONSERVATIVE_SEGMENT_SIZE: u64 = 10 * 1024 * 1024;
+
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+
+/// 分段下载选项
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// 最大并发下载数
+    pub max_concurrency: usize,
+    /// 推导默认IV时加到分段序号上的偏移量
+    pub iv_offset: i64,
+    /// 使用 `#EXT-X-PART` 部分分段拼接出完整分段（低延迟HLS）
+    pub low_latency: bool,
+    /// 每个分段的媒体序列号，用于推导默认IV；为空时使用分段索引
+    pub sequence_numbers: Vec<u64>,
+    /// 分段下载的重试策略
+    pub retry_policy: RetryPolicy,
+    /// 分段的下载顺序
+    pub download_order: DownloadOrder,
+    /// 所有下载任务共享的限速器
+    pub rate_limiter: Option<Arc<Mutex<TokenBucket>>>,
+    /// 按播放列表顺序排列的分段本地文件名；为空时使用补零到 `segment_filename_padding` 位的 `index{N}.ts`
+    pub segment_file_names: Vec<String>,
+    /// 默认分段文件名的序号宽度
+    pub segment_filename_padding: usize,
+    /// 分段每MB大小额外增加的超时时间
+    pub segment_timeout_per_mb: Duration,
+    /// 拼接URL前先对分段URI做百分号解码（处理重复编码的URI）
+    pub segment_url_decode: bool,
+    /// 拼接URL前先对分段URI中的非ASCII字符和空格做百分号编码
+    pub segment_url_encode: bool,
+    /// 下载时只保存加密数据，全部下载完成后再并行解密
+    pub deferred_decryption: bool,
+    /// 分段解密所使用的实现
+    pub decrypt_backend: DecryptBackend,
+    /// Content-Type 不像媒体分段时视为下载失败，否则只给出警告
+    pub strict_content_type: bool,
+    /// 启用自适应并发时的最大并发数，初始并发数为 `max_concurrency`
+    pub adaptive_max_threads: Option<usize>,
+    /// 隐藏进度条
+    pub hide_progress: bool,
+    /// 每个分段下载完成（或已存在）时发送其索引
+    pub completed_segments: Option<UnboundedSender<usize>>,
+    /// 每次下载分段前对其URL重新签名
+    pub segment_presigner: Option<Arc<SegmentPresigner>>,
+    /// 将获取到的原始密钥写入此文件
+    pub raw_key_output: Option<PathBuf>,
+    /// 获取密钥后记录密钥详情，未设置时不记录
+    pub key_info_log: Option<KeyInfoLog>,
+    /// 定期将写完的分段同步到磁盘
+    pub disk_flusher: Option<Arc<DiskFlusher>>,
+    /// 压缩保存分段时的 gzip 压缩级别
+    #[cfg(feature = "compress-segments")]
+    pub compression_level: u32,
+    /// 每个分段写完（或已存在）后立即计算其校验和
+    pub segment_hasher: Option<Arc<SegmentHasher>>,
+}
+
+/// 记录密钥详情时密钥的显示方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyInfoLog {
+    /// 只显示首尾各 2 字节，足以区分不同的密钥
+    Fingerprint,
+    /// 显示完整密钥
+    Raw,
+}
+
+/// 自适应并发每完成多少个分段评估一次吞吐量
+const ADAPTIVE_WINDOW_SEGMENTS: usize = 10;
+
+/// 一个评估窗口内的下载统计
+struct ThroughputWindow {
+    segments: usize,
+    bytes: u64,
+    rate_limited: usize,
+    started: Instant,
+}
+
+impl ThroughputWindow {
+    fn new() -> Self {
+        Self {
+            segments: 0,
+            bytes: 0,
+            rate_limited: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// 自适应并发的共享状态，信号量的许可数即当前并发数
+struct AdaptiveConcurrency {
+    semaphore: Arc<Semaphore>,
+    controller: Mutex<ConcurrencyController>,
+    window: Mutex<ThroughputWindow>,
+}
+
+impl AdaptiveConcurrency {
+    fn new(initial: usize, max: usize) -> Self {
+        let controller = ConcurrencyController::new(initial, 1, max);
+        Self {
+            semaphore: Arc::new(Semaphore::new(controller.current)),
+            controller: Mutex::new(controller),
+            window: Mutex::new(ThroughputWindow::new()),
+        }
+    }
+
+    /// 记录一次 429 响应
+    fn record_rate_limited(&self) {
+        self.window.lock().unwrap().rate_limited += 1;
+    }
+
+    /// 记录一个完成的分段，每满一个窗口调整一次并发数
+    fn record_segment(&self, bytes: u64) {
+        let (throughput, error_rate) = {
+            let mut window = self.window.lock().unwrap();
+            window.segments += 1;
+            window.bytes += bytes;
+            if window.segments < ADAPTIVE_WINDOW_SEGMENTS {
+                return;
+            }
+            let elapsed = window.started.elapsed().as_secs_f64().max(f64::EPSILON);
+            let measured = (
+                window.bytes as f64 / elapsed,
+                window.rate_limited as f64 / window.segments as f64,
+            );
+            *window = ThroughputWindow::new();
+            measured
+        };
+
+        let mut controller = self.controller.lock().unwrap();
+        let previous = controller.current;
+        let current = controller.adjust(throughput, error_rate);
+        if current == previous {
+            return;
+        }
+        debug!(
+            "Adaptive concurrency {} -> {} ({:.0} B/s, {:.0}% rate limited)",
+            previous,
+            current,
+            throughput,
+            error_rate * 100.0
+        );
+        if current > previous {
+            self.semaphore.add_permits(current - previous);
+        } else {
+            // 正在使用的许可无法直接收回，等任务归还后再丢弃
+            let semaphore = self.semaphore.clone();
+            let excess = (previous - current) as u32;
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+
+/// 单个分段的下载计时信息
+#[derive(Debug, Clone)]
+pub struct SegmentTiming {
+    pub index: usize,
+    pub uri: String,
+    pub duration_secs: f32,
+    pub program_date_time: Option<String>,
+    pub bytes_downloaded: u64,
+    pub download_time_ms: u64,
+    /// 分段文件已存在，未重新下载
+    pub skipped: bool,
+}
+
+/// 单个分段的下载任务
+struct SegmentTask {
+    timing: SegmentTiming,
+    url: Url,
+    part_urls: Vec<Url>,
+    output_path: PathBuf,
+    /// 补零之前的版本为该分段使用的文件名，续传时沿用
+    legacy_path: Option<PathBuf>,
+    key: Option<Vec<u8>>,
+    iv: Option<Vec<u8>>,
+    max_attempts: u32,
+}
+
+/// 已获取的密钥
+struct FetchedKey {
+    info: KeyInfo,
+    key: Vec<u8>,
+    /// `#EXT-X-KEY` 中显式指定的IV
+    explicit_iv: Option<Vec<u8>>,
+}
+
+/// 下载所有分段，密钥通过 `key_client` 获取
+///
+/// `segment_keys` 与 `segments` 一一对应，为每个分段使用的密钥，`None` 表示未加密。
 pub async fn download_segments(
-    client: Arc<Client>,
+    client: Arc<CachingClient>,
+    key_client: Arc<CachingClient>,
     segments: &[MediaSegment],
     base_url: Url,
     output_dir: PathBuf,
-    max_concurrency: usize,
-    key_info: Option<KeyInfo>,
-) -> Vec<Result<()>> {
-    let pb = Arc::new(ProgressBar::new(segments.len() as u64));
+    segment_keys: &[Option<KeyInfo>],
+    options: &DownloadOptions,
+) -> Vec<Result<SegmentTiming>> {
+    let pb = progress_bar(segments.len() as u64, options.hide_progress);
+
+    // 按出现顺序获取每个不同的密钥及显式指定的IV，密钥轮换时每个密钥只获取一次
+    let mut keys: Vec<FetchedKey> = Vec::new();
+    let mut key_indices: Vec<Option<usize>> = Vec::with_capacity(segments.len());
+    for i in 0..segments.len() {
+        let Some(key_info) = segment_keys.get(i).cloned().flatten() else {
+            key_indices.push(None);
+            continue;
+        };
+        if let Some(index) = keys.iter().position(|k| k.info == key_info) {
+            key_indices.push(Some(index));
+            continue;
+        }
+        match fetch_segment_key(&key_client, &base_url, key_info, &keys).await {
+            Ok(fetched) => keys.push(fetched),
+            // 如果获取密钥失败，返回错误
+            Err(e) => return vec![Err(e)],
+        }
+        key_indices.push(Some(keys.len() - 1));
+    }
+    if let Err(e) = write_first_key(&keys, options).await {
+        return vec![Err(e)];
+    }
+
+    // 收集所有分段信息，避免在异步闭包中使用引用
+    let mut segments_info = Vec::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        let sequence = options.sequence_numbers.get(i).copied().unwrap_or(i as u64);
+        let key = key_indices[i].map(|index| &keys[index]);
+        match segment_task(i, segment, sequence, key, &base_url, &output_dir, options) {
+            Ok(mut task) => {
+                task.max_attempts = options.retry_policy.attempts_for(i, segments.len());
+                segments_info.push(task);
+            }
+            Err(e) => return vec![Err(e)],
+        }
+    }
+
+    if let Some(detail) = options.key_info_log {
+        for (index, fetched) in keys.iter().enumerate() {
+            let ivs: Vec<(usize, &[u8])> = segments_info
+                .iter()
+                .zip(&key_indices)
+                .filter(|(_, key_index)| **key_index == Some(index))
+                .filter_map(|(task, _)| Some((task.timing.index, task.iv.as_deref()?)))
+                .collect();
+            log_key_info(
+                &fetched.info,
+                &base_url,
+                &fetched.key,
+                fetched.explicit_iv.is_some(),
+                &ivs,
+                detail,
+            );
+        }
+    }
+
+    // 延迟解密时记录每个加密分段的输出路径、密钥和IV，供下载完成后的解密阶段使用
+    let decrypt_jobs: Vec<DecryptJob> = segments_info
+        .iter()
+        .filter_map(|task| decrypt_job(task, options))
+        .collect();
+
+    // 倒序下载只改变发起顺序，分段文件名仍按播放列表顺序编号，合并顺序不受影响
+    if options.download_order == DownloadOrder::Reverse {
+        segments_info.reverse();
+    }
+
+    let shared_options = Arc::new(options.clone());
+    let (adaptive, buffer_size) = download_concurrency(options);
+
+    let fetches = stream::iter(segments_info).map(|task| {
+        spawn_segment_download(
+            task,
+            client.clone(),
+            shared_options.clone(),
+            pb.clone(),
+            adaptive.clone(),
+        )
+    });
+
+    let results: Vec<_> = match options.download_order {
+        // 按播放列表顺序逐个下载，上一个分段完成后才发起下一个
+        DownloadOrder::Sequential => fetches.buffered(1).collect().await,
+        DownloadOrder::Parallel | DownloadOrder::Reverse => {
+            fetches.buffer_unordered(buffer_size).collect().await
+        }
+    };
+    pb.finish_with_message("downloaded");
+
+    finish_downloads(results, decrypt_jobs, options.decrypt_backend).await
+}
+
+/// 边解析边下载：每从 `segments` 中取出一个分段就开始下载，密钥在首次出现时获取
+///
+/// 配合 [`fetch_segments_lazy`](crate::playlist::fetch_segments_lazy) 使用，下载开始时后面的
+/// 分段可能还没有解析。返回按播放列表顺序收集的分段和下载结果，合并、校验等需要完整分段
+/// 列表的步骤在下载完成后使用。分段的媒体序列号从 `first_sequence` 开始，文件名使用
+/// [`default_segment_file_name`]。倒序下载需要完整的分段列表，按并行下载处理。
+pub async fn download_segment_stream(
+    client: Arc<CachingClient>,
+    key_client: Arc<CachingClient>,
+    mut segments: impl Stream<Item = Result<MediaSegment>> + Unpin,
+    base_url: Url,
+    output_dir: PathBuf,
+    first_sequence: u64,
+    options: &DownloadOptions,
+) -> (Vec<MediaSegment>, Vec<Result<SegmentTiming>>) {
+    let pb = progress_bar(0, options.hide_progress);
+    let shared_options = Arc::new(options.clone());
+    let (adaptive, buffer_size) = download_concurrency(options);
+    let (sender, receiver) = mpsc::channel::<SegmentTask>(buffer_size);
+
+    // 解析分段、获取密钥并生成下载任务；最近的一个任务留到下一个分段到来时再发出，
+    // 以便知道哪个分段是最后一个
+    let produce = async {
+        let sender = sender;
+        let mut collected = Vec::new();
+        let mut keys: Vec<FetchedKey> = Vec::new();
+        let mut key_ivs: Vec<Vec<(usize, Vec<u8>)>> = Vec::new();
+        let mut decrypt_jobs = Vec::new();
+        let mut current_key = None;
+        let mut pending: Option<SegmentTask> = None;
+        let mut error = None;
+        while let Some(segment) = segments.next().await {
+            let index = collected.len();
+            let segment = match segment {
+                Ok(segment) => segment,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            let key_index = match next_segment_key(&mut current_key, &segment) {
+                None => None,
+                Some(info) => match keys.iter().position(|k| k.info == info) {
+                    Some(position) => Some(position),
+                    None => match fetch_segment_key(&key_client, &base_url, info, &keys).await {
+                        Ok(fetched) => {
+                            keys.push(fetched);
+                            key_ivs.push(Vec::new());
+                            Some(keys.len() - 1)
+                        }
+                        Err(e) => {
+                            error = Some(e);
+                            break;
+                        }
+                    },
+                },
+            };
+            let sequence = first_sequence + index as u64;
+            let key = key_index.map(|k| &keys[k]);
+            let mut task = match segment_task(
+                index,
+                &segment,
+                sequence,
+                key,
+                &base_url,
+                &output_dir,
+                options,
+            ) {
+                Ok(task) => task,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            task.max_attempts = options.retry_policy.attempts_for(index, usize::MAX);
+            if let (Some(key_index), Some(iv)) = (key_index, &task.iv) {
+                key_ivs[key_index].push((index, iv.clone()));
+            }
+            decrypt_jobs.extend(decrypt_job(&task, options));
+            collected.push(segment);
+            pb.inc_length(1);
+            if let Some(previous) = pending.replace(task) {
+                if sender.send(previous).await.is_err() {
+                    break;
+                }
+            }
+        }
+        if let Some(mut last) = pending {
+            if error.is_none() {
+                let index = last.timing.index;
+                last.max_attempts = options.retry_policy.attempts_for(index, index + 1);
+            }
+            let _ = sender.send(last).await;
+        }
+        (collected, keys, key_ivs, decrypt_jobs, error)
+    };
+
+    let fetches = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|task| (task, receiver))
+    })
+    .map(|task| {
+        spawn_segment_download(
+            task,
+            client.clone(),
+            shared_options.clone(),
+            pb.clone(),
+            adaptive.clone(),
+        )
+    });
+    let consume = async {
+        match options.download_order {
+            DownloadOrder::Sequential => fetches.buffered(1).collect::<Vec<_>>().await,
+            DownloadOrder::Parallel | DownloadOrder::Reverse => {
+                fetches.buffer_unordered(buffer_size).collect().await
+            }
+        }
+    };
+
+    let ((collected, keys, key_ivs, decrypt_jobs, error), results) = tokio::join!(produce, consume);
+    pb.finish_with_message("downloaded");
+
+    if let Some(detail) = options.key_info_log {
+        for (fetched, ivs) in keys.iter().zip(&key_ivs) {
+            let ivs: Vec<(usize, &[u8])> = ivs.iter().map(|(i, iv)| (*i, iv.as_slice())).collect();
+            log_key_info(
+                &fetched.info,
+                &base_url,
+                &fetched.key,
+                fetched.explicit_iv.is_some(),
+                &ivs,
+                detail,
+            );
+        }
+    }
+
+    let mut results = finish_downloads(results, decrypt_jobs, options.decrypt_backend).await;
+    if let Err(e) = write_first_key(&keys, options).await {
+        results.push(Err(e));
+    }
+    // 播放列表解析或密钥获取失败时，已产出的分段照常下载，失败记为一个错误
+    if let Some(e) = error {
+        results.push(Err(e));
+    }
+    (collected, results)
+}
+
+/// 创建分段下载进度条，`len` 为分段总数
+fn progress_bar(len: u64, hidden: bool) -> Arc<ProgressBar> {
+    let pb = if hidden {
+        ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden())
+    } else {
+        ProgressBar::new(len)
+    };
     pb.set_style(
         ProgressStyle::default_bar()
             .template(
@@ -31,147 +504,832 @@ pub async fn download_segments(
             .unwrap()
             .progress_chars("#>-"),
     );
+    Arc::new(pb)
+}
 
-    // 收集所有分段信息，避免在异步闭包中使用引用
-    let mut segments_info = Vec::new();
-
-    for (i, segment) in segments.iter().enumerate() {
-        let segment_uri = segment.uri.clone();
-        let segment_url = match base_url.join(&segment_uri) {
-            Ok(url) => url,
-            Err(e) => {
-                return vec![Err(anyhow!(
-                    "无法解析分段URL: {} - 错误: {}",
-                    segment_uri,
-                    e
-                ))];
-            }
-        };
-        let output_path = output_dir.join(format!("index{}.ts", i));
-        segments_info.push((i, segment_url, output_path));
+/// 指定了 `--write-raw-aes-key` 时写出第一个密钥
+async fn write_first_key(keys: &[FetchedKey], options: &DownloadOptions) -> Result<()> {
+    if keys.len() > 1 {
+        info!("Playlist rotates between {} keys.", keys.len());
     }
+    let (Some(path), Some(first)) = (&options.raw_key_output, keys.first()) else {
+        return Ok(());
+    };
+    if keys.len() > 1 {
+        warn!(
+            "The playlist uses {} keys; only the first is written to {:?}.",
+            keys.len(),
+            path
+        );
+    }
+    write_raw_key(path, &first.key).await
+}
 
-    // 获取密钥和IV
-    let (key, iv) = match get_key_iv(client.clone(), base_url.clone(), key_info.clone()).await {
-        Ok((k, v)) => (k, v),
-        Err(e) => {
-            // 如果获取密钥失败，返回错误
-            return vec![Err(e)];
+/// 为第 `index` 个分段准备下载任务，`sequence` 为其媒体序列号，`key` 为其使用的密钥
+///
+/// 重试次数取决于分段在播放列表中的位置，由调用方设置 `max_attempts`。
+fn segment_task(
+    index: usize,
+    segment: &MediaSegment,
+    sequence: u64,
+    key: Option<&FetchedKey>,
+    base_url: &Url,
+    output_dir: &Path,
+    options: &DownloadOptions,
+) -> Result<SegmentTask> {
+    let segment_uri = segment.uri.clone();
+    let segment_url = resolve_segment_url(base_url, &segment_uri, options)
+        .map_err(|e| anyhow!("无法解析分段URL: {} - 错误: {}", segment_uri, e))?;
+    let file_name = options
+        .segment_file_names
+        .get(index)
+        .cloned()
+        .unwrap_or_else(|| default_segment_file_name(index, options.segment_filename_padding));
+    let legacy_path = unpadded_segment_file_name(&file_name).map(|name| output_dir.join(name));
+    let output_path = output_dir.join(file_name);
+
+    // 低延迟模式下，按顺序下载部分分段并拼接
+    let mut part_urls = Vec::new();
+    if options.low_latency {
+        for part in segment_parts(segment) {
+            let url = resolve_segment_url(base_url, &part.uri, options)
+                .map_err(|e| anyhow!("无法解析部分分段URL: {} - 错误: {}", part.uri, e))?;
+            part_urls.push(url);
         }
+    }
+
+    // 未显式指定IV时，按分段序号推导默认IV
+    let iv = match key {
+        None => None,
+        Some(FetchedKey {
+            explicit_iv: Some(iv),
+            ..
+        }) => Some(iv.clone()),
+        Some(_) => Some(default_iv(sequence, options.iv_offset)?),
     };
+    Ok(SegmentTask {
+        timing: SegmentTiming {
+            index,
+            uri: segment_uri,
+            duration_secs: segment.duration,
+            program_date_time: segment.program_date_time.map(|t| t.to_rfc3339()),
+            bytes_downloaded: 0,
+            download_time_ms: 0,
+            skipped: false,
+        },
+        url: segment_url,
+        part_urls,
+        output_path,
+        legacy_path,
+        key: key.map(|k| k.key.clone()),
+        iv,
+        max_attempts: options.retry_policy.max_attempts,
+    })
+}
 
-    let fetches = stream::iter(segments_info)
-        .map(|(_i, segment_url, output_path)| {
-            let client = client.clone();
-            let pb_clone = pb.clone();
-            // 克隆密钥和IV，因为它们需要在异步闭包中使用
-            // 这是必要的，因为 tokio::spawn 创建的任务需要 'static 生命周期
-            let key_clone = key.clone();
-            let iv_clone = iv.clone();
+/// 延迟解密时，加密分段在下载完成后的解密任务
+fn decrypt_job(task: &SegmentTask, options: &DownloadOptions) -> Option<DecryptJob> {
+    if !options.deferred_decryption {
+        return None;
+    }
+    Some(DecryptJob {
+        index: task.timing.index,
+        output_path: task.output_path.clone(),
+        key: task.key.clone()?,
+        iv: task.iv.clone(),
+        #[cfg(feature = "compress-segments")]
+        compression_level: options.compression_level,
+    })
+}
 
-            tokio::spawn(async move {
-                if fs::metadata(&output_path).await.is_ok() {
-                    debug!("Segment {:?} already exists. Skipping.", output_path);
-                    pb_clone.inc(1);
-                    return Ok(());
-                }
+/// 自适应并发的控制器，以及下载流的缓冲区大小
+fn download_concurrency(options: &DownloadOptions) -> (Option<Arc<AdaptiveConcurrency>>, usize) {
+    let adaptive = options
+        .adaptive_max_threads
+        .map(|max| Arc::new(AdaptiveConcurrency::new(options.max_concurrency, max)));
+    // 自适应模式下由信号量限制实际并发数，流的缓冲区只需容纳最大并发数
+    let buffer_size = options
+        .adaptive_max_threads
+        .map_or(options.max_concurrency, |max| {
+            max.max(options.max_concurrency)
+        });
+    (adaptive, buffer_size.max(1))
+}
 
-                // 转换Option<Vec<u8>>到Option<&[u8]>
-                let key_slice = key_clone.as_deref();
-                let iv_slice = iv_clone.as_deref();
-
-                match download_segment(
-                    client.clone(),
-                    &segment_url,
-                    &output_path,
-                    key_slice,
-                    iv_slice,
-                )
-                .await
-                {
-                    Ok(_) => {
-                        pb_clone.inc(1);
-                        Ok(())
-                    }
-                    Err(e) => {
-                        pb_clone.inc(1);
-                        Err(anyhow!("Failed to download {}: {}", segment_url, e))
-                    }
-                }
-            })
-        })
-        .buffer_unordered(max_concurrency);
+/// 在后台任务中下载一个分段，输出文件已存在时跳过
+fn spawn_segment_download(
+    task: SegmentTask,
+    client: Arc<CachingClient>,
+    options: Arc<DownloadOptions>,
+    pb: Arc<ProgressBar>,
+    adaptive: Option<Arc<AdaptiveConcurrency>>,
+) -> JoinHandle<Result<SegmentTiming>> {
+    tokio::spawn(async move {
+        let mut timing = task.timing.clone();
+        // 之前的版本下载的分段未补零，改为新文件名后同样跳过
+        if let Some(legacy_path) = &task.legacy_path {
+            if fs::metadata(&task.output_path).await.is_err()
+                && fs::rename(legacy_path, &task.output_path).await.is_ok()
+            {
+                debug!(
+                    "Renamed {:?} from an earlier download to {:?}",
+                    legacy_path, task.output_path
+                );
+            }
+        }
+        if fs::metadata(&task.output_path).await.is_ok() {
+            debug!("Segment {:?} already exists. Skipping.", task.output_path);
+            timing.skipped = true;
+            if let Some(hasher) = &options.segment_hasher {
+                hasher.record(timing.index, &task.output_path).await;
+            }
+            pb.inc(1);
+            notify_completed(&options, timing.index);
+            return Ok(timing);
+        }
 
-    let results: Vec<_> = fetches.collect().await;
-    pb.finish_with_message("downloaded");
+        let _permit = match &adaptive {
+            Some(adaptive) => Some(adaptive.semaphore.clone().acquire_owned().await?),
+            None => None,
+        };
 
-    results
+        let started = Instant::now();
+
+        let result = download_segment(client.clone(), &task, &options, adaptive.as_deref()).await;
+        if let Some(adaptive) = &adaptive {
+            adaptive.record_segment(*result.as_ref().unwrap_or(&0));
+        }
+        match result {
+            Ok(bytes) => {
+                if let Some(hasher) = &options.segment_hasher {
+                    hasher.record(timing.index, &task.output_path).await;
+                }
+                pb.inc(1);
+                timing.bytes_downloaded = bytes;
+                timing.download_time_ms = started.elapsed().as_millis() as u64;
+                notify_completed(&options, timing.index);
+                Ok(timing)
+            }
+            Err(e) => {
+                pb.inc(1);
+                Err(anyhow!("Failed to download {}: {}", task.url, e))
+            }
+        }
+    })
+}
+
+/// 展开后台任务的结果；延迟解密时在此解密，解密失败的分段改为错误
+async fn finish_downloads(
+    results: Vec<std::result::Result<Result<SegmentTiming>, JoinError>>,
+    decrypt_jobs: Vec<DecryptJob>,
+    backend: DecryptBackend,
+) -> Vec<Result<SegmentTiming>> {
+    let mut results: Vec<Result<SegmentTiming>> = results
         .into_iter()
         .map(|res| match res {
             Ok(inner_res) => inner_res,
             Err(e) => Err(anyhow!("Tokio task failed: {}", e)),
         })
-        .collect()
+        .collect();
+
+    if !decrypt_jobs.is_empty() {
+        // 解密失败的分段将对应的结果改为错误
+        for (index, error) in decrypt_segments(decrypt_jobs, backend).await {
+            if let Some(result) = results
+                .iter_mut()
+                .find(|r| matches!(r, Ok(t) if t.index == index))
+            {
+                *result = Err(error);
+            }
+        }
+    }
+
+    results
 }
 
-async fn get_key_iv(
-    client: Arc<Client>,
-    base_url: Url,
+/// 通知边下载边合并的任务某个分段已经可用
+fn notify_completed(options: &DownloadOptions, index: usize) {
+    if let Some(completed) = &options.completed_segments {
+        // 合并任务出错退出后接收端已关闭，此时忽略即可，错误由合并任务返回
+        let _ = completed.send(index);
+    }
+}
+
+/// 延迟解密阶段的单个分段
+struct DecryptJob {
+    index: usize,
+    output_path: PathBuf,
+    key: Vec<u8>,
+    iv: Option<Vec<u8>>,
+    #[cfg(feature = "compress-segments")]
+    compression_level: u32,
+}
+
+/// 延迟解密时加密数据的临时文件路径
+fn encrypted_path(path: &Path) -> PathBuf {
+    let mut encrypted = path.as_os_str().to_owned();
+    encrypted.push(".encrypted");
+    PathBuf::from(encrypted)
+}
+
+/// 使用 rayon 并行解密所有已下载的加密分段，返回解密失败的分段索引和错误
+async fn decrypt_segments(
+    jobs: Vec<DecryptJob>,
+    backend: DecryptBackend,
+) -> Vec<(usize, anyhow::Error)> {
+    let jobs: Vec<DecryptJob> = jobs
+        .into_iter()
+        .filter(|job| encrypted_path(&job.output_path).exists())
+        .collect();
+    if jobs.is_empty() {
+        return Vec::new();
+    }
+    info!("Decrypting {} segments in parallel.", jobs.len());
+    let indices: Vec<usize> = jobs.iter().map(|job| job.index).collect();
+
+    let decrypt = move || {
+        jobs.par_iter()
+            .filter_map(|job| {
+                decrypt_segment_file(job, backend).err().map(|e| {
+                    (
+                        job.index,
+                        anyhow!("Failed to decrypt {:?}: {}", job.output_path, e),
+                    )
+                })
+            })
+            .collect()
+    };
+    tokio::task::spawn_blocking(decrypt)
+        .await
+        .unwrap_or_else(|e| {
+            indices
+                .into_iter()
+                .map(|index| (index, anyhow!("Decryption task failed: {}", e)))
+                .collect()
+        })
+}
+
+/// 解密单个分段的加密临时文件，写入最终路径后删除临时文件
+fn decrypt_segment_file(job: &DecryptJob, backend: DecryptBackend) -> Result<()> {
+    let path = job.output_path.as_path();
+    let iv = job.iv.as_deref().ok_or_else(|| anyhow!("Missing IV"))?;
+    let encrypted_path = encrypted_path(path);
+    let encrypted_data = std::fs::read(&encrypted_path)?;
+    let decrypted_data = decrypt_with(backend, &encrypted_data, &job.key, iv)?;
+
+    #[cfg(feature = "compress-segments")]
+    let decrypted_data = if crate::compress::is_compressed(path) {
+        crate::compress::gzip(&decrypted_data, job.compression_level)?
+    } else {
+        decrypted_data
+    };
+
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    std::fs::write(&part_path, decrypted_data)?;
+    std::fs::rename(&part_path, path)?;
+    std::fs::remove_file(&encrypted_path)?;
+    Ok(())
+}
+
+/// 将原始密钥写入文件，Unix 上仅允许文件所有者读写
+async fn write_raw_key(path: &Path, key: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(path)
+        .await
+        .map_err(|e| anyhow!("Failed to create key file {:?}: {}", path, e))?;
+    file.write_all(key).await?;
+    file.flush().await?;
+    warn!(
+        "Wrote the raw {}-byte decryption key to {:?}; keep this file private.",
+        key.len(),
+        path
+    );
+    Ok(())
+}
+
+/// 解析密钥URL，相对URI基于播放列表URL
+fn resolve_key_url(base_url: &Url, uri: &str) -> Result<Url> {
+    match Url::parse(uri) {
+        Ok(url) => Ok(url),
+        // 尝试将key URI作为相对URL处理
+        Err(_) => base_url
+            .join(uri)
+            .map_err(|e| anyhow!("无法解析密钥URL: {} - 错误: {}", uri, e)),
+    }
+}
+
+/// 将分段索引压缩为区间列表，如 `0-99, 150`
+fn format_index_ranges(indices: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &index in indices {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// 记录密钥的方法、URL、密钥（指纹或完整内容）、IV 以及使用它的分段
+///
+/// `ivs` 为使用该密钥的分段索引及其IV，按索引升序排列。
+fn log_key_info(
+    key_info: &KeyInfo,
+    base_url: &Url,
+    key: &[u8],
+    explicit_iv: bool,
+    ivs: &[(usize, &[u8])],
+    detail: KeyInfoLog,
+) {
+    let key_url = resolve_key_url(base_url, &key_info.uri)
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| key_info.uri.clone());
+    let key_display = match detail {
+        KeyInfoLog::Raw => hex::encode(key),
+        KeyInfoLog::Fingerprint if key.len() > 4 => format!(
+            "{}...{}",
+            hex::encode(&key[..2]),
+            hex::encode(&key[key.len() - 2..])
+        ),
+        KeyInfoLog::Fingerprint => "<too short to fingerprint>".to_string(),
+    };
+    info!(
+        "Key {}: method {}, {} bytes, key {}",
+        key_url,
+        key_info.method,
+        key.len(),
+        key_display
+    );
+    if detail == KeyInfoLog::Raw {
+        warn!("The full decryption key was logged (--show-raw-key); keep this log private.");
+    }
+    match (ivs.first(), ivs.last()) {
+        (Some((_, iv)), _) if explicit_iv => info!("  IV 0x{} (from #EXT-X-KEY)", hex::encode(iv)),
+        (Some((first, first_iv)), Some((last, last_iv))) => info!(
+            "  IV derived from the media sequence: 0x{} (segment {}) to 0x{} (segment {})",
+            hex::encode(first_iv),
+            first,
+            hex::encode(last_iv),
+            last
+        ),
+        _ => {}
+    }
+    let indices: Vec<usize> = ivs.iter().map(|(i, _)| *i).collect();
+    info!("  Used by segments {}", format_index_ranges(&indices));
+}
+
+/// 获取密钥，长度与 METHOD 不符时返回错误
+async fn fetch_key(client: &CachingClient, base_url: &Url, key_info: &KeyInfo) -> Result<Vec<u8>> {
+    let key_url = resolve_key_url(base_url, &key_info.uri)?;
+    let key_bytes = client.get_cached(&key_url).await?.body;
+
+    // 密钥原样传给解密函数，由其按长度选择 AES-128/192/256；长度与 METHOD 不符时直接报错，
+    // 而不是截断或补零后解密出错误的数据
+    if let Some(expected) = key_len_for_method(&key_info.method) {
+        if key_bytes.len() != expected {
+            return Err(anyhow!(
+                "Key {} is {} bytes, but METHOD={} requires {} bytes",
+                key_url,
+                key_bytes.len(),
+                key_info.method,
+                expected
+            ));
+        }
+    }
+    Ok(key_bytes)
+}
+
+/// 解析 `#EXT-X-KEY` 中显式指定的IV（如果有）
+fn explicit_iv(key_info: &KeyInfo) -> Result<Option<Vec<u8>>> {
+    let Some(iv_str) = &key_info.iv else {
+        return Ok(None);
+    };
+    let hex_str = iv_str.trim_start_matches("0x").trim_start_matches("0X");
+    let mut iv_bytes =
+        hex::decode(hex_str).map_err(|e| anyhow!("无法解析IV值: {} - 错误: {}", iv_str, e))?;
+
+    // 确保IV长度为16字节（AES的分组大小，与密钥长度无关）
+    iv_bytes.resize_with(16, Default::default); // Truncates or pads with 0s to 16 bytes
+    Ok(Some(iv_bytes))
+}
+
+/// 获取分段使用的密钥；与已获取的密钥URI相同（只是IV不同）时直接复用，不再重复请求
+async fn fetch_segment_key(
+    client: &CachingClient,
+    base_url: &Url,
+    key_info: KeyInfo,
+    fetched: &[FetchedKey],
+) -> Result<FetchedKey> {
+    let key = match fetched.iter().find(|k| k.info.uri == key_info.uri) {
+        Some(existing) => existing.key.clone(),
+        None => fetch_key(client, base_url, &key_info).await?,
+    };
+    Ok(FetchedKey {
+        explicit_iv: explicit_iv(&key_info)?,
+        key,
+        info: key_info,
+    })
+}
+
+/// 计算默认IV：HLS规定未指定IV时，使用分段序号的128位大端表示
+pub fn default_iv(sequence: u64, iv_offset: i64) -> Result<Vec<u8>> {
+    let value = sequence as i128 + iv_offset as i128;
+    let value = u128::try_from(value)
+        .map_err(|_| anyhow!("IV序号为负数: 分段 {} 偏移 {}", sequence, iv_offset))?;
+    Ok(value.to_be_bytes().to_vec())
+}
+
+/// 下载并解密第一个分段，存在 `#EXT-X-MAP` 时在前面拼接初始化分段，供探测媒体信息
+///
+/// `key_info` 为第一个分段使用的密钥，`sequence` 为其媒体序列号，用于推导默认IV。
+/// 与完整下载一致，初始化分段不解密。
+pub async fn fetch_first_segment(
+    client: &CachingClient,
+    key_client: Arc<CachingClient>,
+    segments: &[MediaSegment],
+    base_url: &Url,
     key_info: Option<KeyInfo>,
-) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
-    if let Some(ki) = key_info {
-        let key_url = match Url::parse(&ki.uri) {
-            Ok(url) => url,
-            Err(_) => {
-                // 尝试将key URI作为相对URL处理
-                base_url.join(&ki.uri).map_err(|e| anyhow!("无法解析密钥URL: {} - 错误: {}", ki.uri, e))?
+    sequence: u64,
+    iv_offset: i64,
+) -> Result<Vec<u8>> {
+    let segment = segments
+        .first()
+        .ok_or_else(|| anyhow!("The playlist has no segments"))?;
+    let key = match &key_info {
+        Some(ki) => Some((
+            fetch_key(&key_client, base_url, ki).await?,
+            explicit_iv(ki)?,
+        )),
+        None => None,
+    };
+
+    let mut data = match &segment.map {
+        Some(map) => client.get_cached(&base_url.join(&map.uri)?).await?.body,
+        None => Vec::new(),
+    };
+    let body = client.get_cached(&base_url.join(&segment.uri)?).await?.body;
+    match key {
+        Some((key, explicit_iv)) => {
+            let iv = match explicit_iv {
+                Some(iv) => iv,
+                None => default_iv(sequence, iv_offset)?,
+            };
+            data.extend(decrypt_with(DecryptBackend::default(), &body, &key, &iv)?);
+        }
+        None => data.extend(body),
+    }
+    Ok(data)
+}
+
+/// 下载 `#EXT-X-MAP` 初始化分段并保存到 `output_path`，带 BYTERANGE 时只请求该范围
+///
+/// 与完整下载一致，初始化分段不解密。
+pub async fn download_init_segment(
+    client: &CachingClient,
+    base_url: &Url,
+    map: &Map,
+    output_path: &Path,
+) -> Result<()> {
+    let url = base_url.join(&map.uri)?;
+    let data = match &map.byte_range {
+        Some(range) => {
+            let start = range.offset.unwrap_or(0);
+            let end = start + range.length.saturating_sub(1);
+            client
+                .get(url)
+                .header(RANGE, format!("bytes={}-{}", start, end))
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?
+                .to_vec()
+        }
+        None => client.get_cached(&url).await?.body,
+    };
+    fs::write(output_path, data)
+        .await
+        .map_err(|e| anyhow!("Failed to write {:?}: {}", output_path, e))
+}
+
+/// 按顺序下载字幕媒体播放列表中的 WebVTT 分段，保存为 `subtitle{N}.vtt`，返回分段数
+pub async fn download_subtitle_segments(
+    client: &CachingClient,
+    playlist: &FetchedPlaylist,
+    output_dir: &Path,
+) -> Result<usize> {
+    if playlist.segment_keys.iter().any(Option::is_some) {
+        return Err(anyhow!("Encrypted subtitle playlists are not supported"));
+    }
+    let segments = &playlist.media_playlist.segments;
+    for (i, segment) in segments.iter().enumerate() {
+        let url = playlist.base_url.join(&segment.uri)?;
+        let data = client.get_cached(&url).await?.body;
+        let path = output_dir.join(vtt_segment_file_name(i));
+        fs::write(&path, data)
+            .await
+            .map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+    }
+    Ok(segments.len())
+}
+
+/// 初始化分段的本地文件名 `init{N}.<ext>`，扩展名取自URI路径，没有时使用 `mp4`
+pub fn init_segment_file_name(map_uri: &str, index: usize) -> String {
+    let path = map_uri.split(['?', '#']).next().unwrap_or_default();
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| sanitize_filename(&e.to_string_lossy()))
+        .filter(|e| !e.is_empty())
+        .unwrap_or_else(|| "mp4".to_string());
+    format!("init{}.{}", index, extension)
+}
+
+/// 检查响应的 Content-Type，发现错误页等非媒体内容时给出警告，`strict` 时视为错误
+///
+/// 未返回 Content-Type 的响应视为合法。
+fn check_content_type(
+    url: &Url,
+    content_type: Option<&str>,
+    strict: bool,
+) -> Result<(), M3u8Error> {
+    let Some(content_type) = content_type else {
+        return Ok(());
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let accepted = SEGMENT_CONTENT_TYPE_PREFIXES
+        .iter()
+        .any(|prefix| media_type.starts_with(prefix))
+        || SEGMENT_CONTENT_TYPES.contains(&media_type.as_str());
+    let error = M3u8Error::UnexpectedContentType {
+        url: url.to_string(),
+        content_type: content_type.to_string(),
+    };
+    if accepted {
+        Ok(())
+    } else if strict {
+        Err(error)
+    } else {
+        warn!("{}; saving it anyway", error);
+        Ok(())
+    }
+}
+
+/// 根据分段大小计算下载超时：全局超时 + 大小（MB）× 每MB超时
+///
+/// 启用限速时超时不少于按限速下载所需的时间；没有 Content-Length 时按保守的分段大小估算，
+/// 未限速则使用全局超时。
+fn segment_timeout(content_length: Option<u64>, options: &DownloadOptions) -> Duration {
+    let rate = options
+        .rate_limiter
+        .as_ref()
+        .map(|limiter| limiter.lock().unwrap().rate);
+    let at_rate = |bytes: u64, rate: u64| Duration::from_secs_f64(bytes as f64 / rate as f64);
+
+    match (content_length, rate) {
+        (Some(length), rate) => {
+            let scaled = DEFAULT_TIMEOUT
+                + options
+                    .segment_timeout_per_mb
+                    .mul_f64(length as f64 / BYTES_PER_MB);
+            match rate {
+                Some(rate) => scaled.max(DEFAULT_TIMEOUT + at_rate(length, rate)),
+                None => scaled,
             }
+        }
+        (None, Some(rate)) => DEFAULT_TIMEOUT + at_rate(CONSERVATIVE_SEGMENT_SIZE, rate),
+        (None, None) => DEFAULT_TIMEOUT,
+    }
+}
+
+/// 按限速器消耗令牌，必要时等待
+async fn throttle(rate_limiter: Option<&Mutex<TokenBucket>>, bytes: u64) {
+    if let Some(limiter) = rate_limiter {
+        let wait = limiter.lock().unwrap().consume(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 默认的分段文件名序号宽度
+pub const DEFAULT_SEGMENT_FILENAME_PADDING: usize = 6;
+
+/// 默认的分段文件名，序号补零到 `padding` 位，使文件名的字典序与播放顺序一致
+pub fn default_segment_file_name(index: usize, padding: usize) -> String {
+    format!("index{:0>padding$}.ts", index, padding = padding)
+}
+
+/// 补零的默认分段文件名在补零之前的写法，如 `index000012.ts` 对应 `index12.ts`；没有补零时返回 `None`
+fn unpadded_segment_file_name(name: &str) -> Option<String> {
+    let rest = name.strip_prefix("index")?;
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (number, suffix) = rest.split_at(digits);
+    if !suffix.starts_with(".ts") || number.len() < 2 || !number.starts_with('0') {
+        return None;
+    }
+    let index: usize = number.parse().ok()?;
+    Some(format!("index{}{}", index, suffix))
+}
+
+/// 按播放列表顺序生成每个分段的本地文件名
+///
+/// `from_uri` 为真时使用分段URI路径的最后一部分（URL解码并清理后），重名时加上索引前缀；
+/// 否则使用序号补零到 `padding` 位的 `index{N}.ts`。
+pub fn segment_file_names(
+    segments: &[MediaSegment],
+    from_uri: bool,
+    padding: usize,
+) -> Vec<String> {
+    let mut seen = HashSet::new();
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            let name = from_uri
+                .then(|| uri_file_name(&segment.uri))
+                .flatten()
+                .unwrap_or_else(|| default_segment_file_name(i, padding));
+            if seen.insert(name.clone()) {
+                name
+            } else {
+                let name = format!("{}_{}", i, name);
+                seen.insert(name.clone());
+                name
+            }
+        })
+        .collect()
+}
+
+/// 从之前的下载目录中复用URL相同的分段，返回复用的分段数
+///
+/// 按 `url_to_hash.json` 索引查找，优先创建硬链接，跨文件系统等无法链接时复制；
+/// 已存在的分段和扩展名不同（如一方为压缩保存）的分段不会复用。
+pub async fn reuse_segments(
+    source_dir: &Path,
+    output_dir: &Path,
+    targets: &[(Url, String)],
+) -> Result<usize> {
+    let index = read_url_hash_index(source_dir).await?;
+    let mut reused = 0;
+    for (url, name) in targets {
+        let Some(source_name) = index.get(&segment_url_hash(url)) else {
+            continue;
         };
-        
-        let mut key_bytes = client.get(key_url).send().await?
-            .bytes().await?
-            .to_vec();
-        
-        // 确保密钥长度为16字节（AES-128要求）
-        key_bytes.resize_with(16, Default::default); // Truncates or pads with 0s to 16 bytes
-
-        let iv_str = ki.iv.clone().unwrap_or_else(|| "0x00000000000000000000000000000000".to_string());
-        let mut iv_bytes = hex::decode(iv_str.trim_start_matches("0x"))
-            .map_err(|e| anyhow!("无法解析IV值: {} - 错误: {}", iv_str, e))?;
-
-        // 确保IV长度为16字节（AES-128要求）
-        iv_bytes.resize_with(16, Default::default); // Truncates or pads with 0s to 16 bytes
-
-        Ok((Some(key_bytes), Some(iv_bytes)))
-    } else {
-        Ok((None, None))
+        let source = source_dir.join(source_name);
+        let target = output_dir.join(name);
+        if source.extension() != target.extension() || fs::metadata(&target).await.is_ok() {
+            continue;
+        }
+        if !fs::metadata(&source).await.is_ok_and(|m| m.len() > 0) {
+            debug!("Reusable segment {:?} is missing or empty", source);
+            continue;
+        }
+        if let Err(e) = fs::hard_link(&source, &target).await {
+            debug!("Cannot hard-link {:?} ({}), copying instead", source, e);
+            fs::copy(&source, &target).await?;
+        }
+        reused += 1;
     }
+    Ok(reused)
+}
+
+/// 按顺序将本地文件复制为指定索引的分段，已存在的分段不会再下载
+///
+/// 本地文件须为非空的 `.ts` 或 `.m4s` 文件。
+pub async fn splice_local_segments(
+    output_dir: &Path,
+    segment_files: &[String],
+    splices: &[(usize, PathBuf)],
+) -> Result<()> {
+    for (index, file) in splices {
+        let name = segment_files.get(*index).ok_or_else(|| {
+            anyhow!(
+                "Cannot splice {:?} in as segment {}: the playlist has {} segments",
+                file,
+                index,
+                segment_files.len()
+            )
+        })?;
+        let extension = file
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        if !matches!(extension.as_deref(), Some("ts" | "m4s")) {
+            return Err(anyhow!(
+                "Local segment {:?} must have a .ts or .m4s extension",
+                file
+            ));
+        }
+        let metadata = fs::metadata(file)
+            .await
+            .map_err(|e| anyhow!("Failed to read local segment {:?}: {}", file, e))?;
+        if metadata.len() == 0 {
+            return Err(anyhow!("Local segment {:?} is empty", file));
+        }
+
+        fs::copy(file, output_dir.join(name)).await?;
+        info!("Spliced {:?} in as segment {} ({})", file, index, name);
+    }
+    Ok(())
+}
+
+/// 按 `--segment-url-decode`/`--segment-url-encode` 预处理分段URI后再与基础URL拼接
+///
+/// 解码失败（如解码结果不是合法的UTF-8）或解码后无法拼接时，回退到原始URI。
+fn resolve_segment_url(base_url: &Url, uri: &str, options: &DownloadOptions) -> Result<Url> {
+    if options.segment_url_decode {
+        match urlencoding::decode(uri) {
+            Ok(decoded) => match base_url.join(&decoded) {
+                Ok(url) => return Ok(url),
+                Err(e) => debug!(
+                    "Decoded segment URI {:?} is invalid ({}), using it as-is",
+                    decoded, e
+                ),
+            },
+            Err(e) => debug!(
+                "Cannot decode segment URI {:?} ({}), using it as-is",
+                uri, e
+            ),
+        }
+    } else if options.segment_url_encode {
+        return Ok(base_url.join(&encode_uri_chars(uri))?);
+    }
+    Ok(base_url.join(uri)?)
+}
+
+/// 对URI中的非ASCII字符、空白和不安全字符做百分号编码，保留 `/`、`?`、`%` 等保留字符
+fn encode_uri_chars(uri: &str) -> String {
+    let mut encoded = String::with_capacity(uri.len());
+    for c in uri.chars() {
+        if c.is_ascii_graphic()
+            && !matches!(c, '"' | '<' | '>' | '\\' | '^' | '`' | '{' | '|' | '}')
+        {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+/// 取URI路径的最后一部分作为文件名
+fn uri_file_name(uri: &str) -> Option<String> {
+    let path = uri.split(['?', '#']).next()?;
+    let last = path.rsplit('/').find(|s| !s.is_empty())?;
+    let decoded = urlencoding::decode(last).ok()?;
+    Some(sanitize_filename(&decoded))
 }
 
 /// 下载单个分段
 async fn download_segment(
-    client: Arc<Client>,
-    url: &Url,
-    path: &Path,
-    key: Option<&[u8]>,
-    iv: Option<&[u8]>,
-) -> Result<()> {
-    const MAX_RETRIES: u8 = 3;
-    let mut delay = tokio::time::Duration::from_millis(100);
+    client: Arc<CachingClient>,
+    task: &SegmentTask,
+    options: &DownloadOptions,
+    adaptive: Option<&AdaptiveConcurrency>,
+) -> Result<u64> {
+    let url = &task.url;
+    let (part_urls, path) = (&task.part_urls, &task.output_path);
+    let (key, iv) = (task.key.as_deref(), task.iv.as_deref());
+    let retry_policy = &options.retry_policy;
+    let max_attempts = task.max_attempts.max(1);
+    let mut delay = RETRY_INITIAL_DELAY;
     let mut last_error = None;
-    for attempt in 1..=MAX_RETRIES {
-        match try_download_segment(client.clone(), url, path, key, iv).await {
-            Ok(_) => return Ok(()),
+    for attempt in 1..=max_attempts {
+        match try_download_segment(client.clone(), url, part_urls, path, key, iv, options).await {
+            Ok(bytes) => return Ok(bytes),
             Err(e) => {
+                if let (Some(adaptive), true) = (adaptive, is_rate_limited(&e)) {
+                    adaptive.record_rate_limited();
+                }
                 // 只对网络相关错误重试
-                if is_retryable_error(&e) {
-                    if attempt < MAX_RETRIES {
-                        debug!("下载重试 {}/{} 失败 url:{}", attempt, MAX_RETRIES, url,);
+                if is_retryable_error(&e, &retry_policy.retry_codes) {
+                    last_error = Some(e);
+                    if attempt < max_attempts {
+                        debug!("下载重试 {}/{} 失败 url:{}", attempt, max_attempts, url,);
                         tokio::time::sleep(delay).await;
                         delay = delay.saturating_mul(2); // 指数退避
-                        last_error = Some(e);
-                    } else {
-                        break;
                     }
                 } else {
                     return Err(e);
@@ -179,55 +1337,151 @@ async fn download_segment(
             }
         }
     }
-    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("下载重试{}次后失败", MAX_RETRIES)))
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("下载重试{}次后失败", max_attempts)))
+}
+
+/// 下载一个来源的完整响应体并追加到 `data`
+async fn fetch_source(
+    client: &CachingClient,
+    source: &Url,
+    data: &mut Vec<u8>,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let rate_limiter = options.rate_limiter.as_deref();
+    // 启用HTTP缓存时整体读取响应，命中缓存的分段不计入限速
+    if client.is_caching() {
+        let response = client.get_cached(source).await?;
+        check_content_type(
+            &response.url,
+            response.content_type.as_deref(),
+            options.strict_content_type,
+        )?;
+        if !response.from_cache {
+            throttle(rate_limiter, response.body.len() as u64).await;
+        }
+        data.extend_from_slice(&response.body);
+        return Ok(());
+    }
+
+    // 响应头须在全局超时内到达，响应体的期限再按分段大小计算
+    let started = Instant::now();
+    let timeout_error = |timeout| M3u8Error::SegmentTimeout {
+        url: source.to_string(),
+        timeout,
+    };
+    let request = client
+        .get(source.clone())
+        .timeout(SEGMENT_REQUEST_TIMEOUT_CAP)
+        .send();
+    let mut response = tokio::time::timeout(DEFAULT_TIMEOUT, request)
+        .await
+        .map_err(|_| timeout_error(DEFAULT_TIMEOUT))??
+        .error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|v| v.to_str().unwrap_or_default().to_string());
+    check_content_type(
+        response.url(),
+        content_type.as_deref(),
+        options.strict_content_type,
+    )?;
+
+    let timeout = segment_timeout(response.content_length(), options);
+    let body = async {
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            throttle(rate_limiter, chunk.len() as u64).await;
+        }
+        Ok::<_, reqwest::Error>(())
+    };
+    tokio::time::timeout_at((started + timeout).into(), body)
+        .await
+        .map_err(|_| timeout_error(timeout))??;
+    Ok(())
 }
 
 /// 支持重试下载
+///
+/// 若提供了部分分段URL，则按顺序下载并拼接为完整分段后再解密。返回下载的字节数。
 async fn try_download_segment(
-    client: Arc<Client>,
+    client: Arc<CachingClient>,
     url: &Url,
+    part_urls: &[Url],
     path: &Path,
     key: Option<&[u8]>,
     iv: Option<&[u8]>,
-) -> Result<()> {
-    let mut response = client.get(url.clone()).send().await?.error_for_status()?;
+    options: &DownloadOptions,
+) -> Result<u64> {
+    let sources = if part_urls.is_empty() {
+        std::slice::from_ref(url)
+    } else {
+        part_urls
+    };
     let mut encrypted_data = Vec::new();
 
-    while let Some(chunk) = response.chunk().await? {
-        encrypted_data.extend_from_slice(&chunk);
+    let fetched = async {
+        for source in sources {
+            let signed;
+            let source = match &options.segment_presigner {
+                Some(presigner) => {
+                    signed = presigner.presign(source).await?;
+                    &signed
+                }
+                None => source,
+            };
+            fetch_source(&client, source, &mut encrypted_data, options).await?;
+        }
+        Ok::<_, anyhow::Error>(())
     }
+    .await;
+    // 下载失败可能是签名已过期，重试时重新签名
+    if let (Err(_), Some(presigner)) = (&fetched, &options.segment_presigner) {
+        sources.iter().for_each(|source| presigner.forget(source));
+    }
+    fetched?;
+
+    let bytes_downloaded = encrypted_data.len() as u64;
 
-    let decrypted_data = if let (Some(key), Some(iv)) = (key, iv) {
-        decrypt_data(&encrypted_data, key, iv)?
+    // 延迟解密时原样保存加密数据，由下载完成后的解密阶段处理
+    let deferred = options.deferred_decryption && key.is_some();
+    let (decrypted_data, path) = match (key, iv) {
+        _ if deferred => (encrypted_data, encrypted_path(path)),
+        (Some(key), Some(iv)) => (
+            decrypt_with(options.decrypt_backend, &encrypted_data, key, iv)?,
+            path.to_path_buf(),
+        ),
+        _ => (encrypted_data, path.to_path_buf()),
+    };
+    let path = path.as_path();
+
+    #[cfg(feature = "compress-segments")]
+    let decrypted_data = if !deferred && crate::compress::is_compressed(path) {
+        crate::compress::gzip(&decrypted_data, options.compression_level)?
     } else {
-        encrypted_data
+        decrypted_data
     };
 
-    let mut file = fs::File::create(path).await?;
+    // 先写入临时文件，刷新后再重命名，避免中断后留下不完整的分段被当作已下载
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+    let mut file = fs::File::create(&part_path).await?;
     file.write_all(&decrypted_data).await?;
+    file.flush().await?;
+    match &options.disk_flusher {
+        Some(flusher) => flusher.track_finished(file.into_std().await).await,
+        None => drop(file),
+    }
+    fs::rename(&part_path, path).await?;
 
-    Ok(())
+    Ok(bytes_downloaded)
 }
 
-// 检查错误是否可重试
-fn is_retryable_error(error: &anyhow::Error) -> bool {
-    // 检查是否是 reqwest 错误
-    if let Some(reqwest_error) = error.downcast_ref::<reqwest::Error>() {
-        // 检查是否是网络错误或超时错误
-        if reqwest_error.is_timeout() || reqwest_error.is_connect() {
-            return true;
-        }
-        // 检查状态码
-        if let Some(status) = reqwest_error.status() {
-            // 5xx 服务器错误是可重试的
-            if status.is_server_error() {
-                return true;
-            }
-            // 429 Too Many Requests 是可重试的
-            if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
-                return true;
-            }
-        }
-    }
-    false
+/// 检查错误是否为服务器限流（HTTP 429）
+fn is_rate_limited(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        .is_some_and(|status| status == reqwest::StatusCode::TOO_MANY_REQUESTS)
 }
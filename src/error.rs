@@ -0,0 +1,31 @@
+use std::fmt;
+use std::time::Duration;
+
+/// 下载过程中需要调用方区分处理的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum M3u8Error {
+    /// 服务器返回了非媒体分段的内容（如JSON错误页）
+    UnexpectedContentType { url: String, content_type: String },
+    /// 分段未能在按大小计算的超时时间内下载完成
+    SegmentTimeout { url: String, timeout: Duration },
+}
+
+impl fmt::Display for M3u8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            M3u8Error::UnexpectedContentType { url, content_type } => write!(
+                f,
+                "unexpected Content-Type '{}' for segment {}",
+                content_type, url
+            ),
+            M3u8Error::SegmentTimeout { url, timeout } => write!(
+                f,
+                "segment {} timed out after {:.1}s",
+                url,
+                timeout.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for M3u8Error {}
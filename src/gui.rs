@@ -1,12 +1,27 @@
-use crate::cli::Args;
-use crate::run;
+use crate::cli::{Args, GuiTheme};
+use crate::settings::GuiSettings;
+use crate::{run, DownloadStats};
 use anyhow::Result;
-use egui::{Color32, RichText, Ui};
+use egui::{Color32, RichText, Ui, Visuals};
 use egui_chinese_font::setup_chinese_fonts;
+use log::warn;
 use poll_promise::Promise;
 use rfd::FileDialog;
 use std::path::PathBuf;
 
+/// GUI启动选项
+#[derive(Debug, Clone, Default)]
+pub struct GuiOptions {
+    /// 覆盖上次保存的主题
+    pub theme: Option<GuiTheme>,
+    /// 覆盖上次保存的窗口尺寸
+    pub window_size: Option<[f32; 2]>,
+    /// 以最大化窗口启动
+    pub maximized: bool,
+    /// 窗口置顶显示，覆盖上次保存的设置
+    pub always_on_top: bool,
+}
+
 /// GUI应用状态
 pub struct M3u8DownloaderApp {
     // 输入参数
@@ -19,8 +34,15 @@ pub struct M3u8DownloaderApp {
     keep_segments: bool,
     headers: String,
 
+    // 界面设置
+    theme: GuiTheme,
+    system_theme: Option<eframe::Theme>,
+    window_size: Option<[f32; 2]>,
+    window_position: Option<[f32; 2]>,
+    always_on_top: bool,
+
     // 运行时状态
-    download_promise: Option<Promise<Result<()>>>,
+    download_promise: Option<Promise<Result<DownloadStats>>>,
     status_message: String,
     status_color: Color32,
     is_downloading: bool,
@@ -38,6 +60,12 @@ impl Default for M3u8DownloaderApp {
             keep_segments: true,
             headers: String::new(),
 
+            theme: GuiTheme::System,
+            system_theme: None,
+            window_size: None,
+            window_position: None,
+            always_on_top: false,
+
             download_promise: None,
             status_message: "就绪".to_string(),
             status_color: Color32::GRAY,
@@ -48,14 +76,84 @@ impl Default for M3u8DownloaderApp {
 
 impl M3u8DownloaderApp {
     /// 创建新的应用实例
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        // 设置默认主题
-        let mut style = (*cc.egui_ctx.style()).clone();
-        style.visuals.window_rounding = egui::Rounding::same(10.0);
-        style.visuals.window_shadow.blur = 10.0;
-        cc.egui_ctx.set_style(style);
-
-        Self::default()
+    pub fn new(cc: &eframe::CreationContext<'_>, options: GuiOptions) -> Self {
+        // 读取上次保存的设置
+        let settings = GuiSettings::load();
+        let app = Self {
+            output_dir: settings.output_dir,
+            threads: settings.threads,
+            theme: options.theme.unwrap_or(settings.theme),
+            system_theme: cc.integration_info.system_theme,
+            window_size: options.window_size.or(settings.window_size),
+            window_position: settings.window_position,
+            always_on_top: options.always_on_top || settings.always_on_top,
+            ..Self::default()
+        };
+
+        // 命令行指定的尺寸和最大化已在创建窗口时应用，这里只恢复上次保存的尺寸和位置
+        if !options.maximized {
+            if let (None, Some([width, height])) = (options.window_size, app.window_size) {
+                cc.egui_ctx
+                    .send_viewport_cmd(egui::ViewportCommand::InnerSize(egui::vec2(width, height)));
+            }
+            if let Some([x, y]) = app.window_position {
+                cc.egui_ctx
+                    .send_viewport_cmd(egui::ViewportCommand::OuterPosition(egui::pos2(x, y)));
+            }
+        }
+        // 命令行指定的置顶已在创建窗口时应用，这里只恢复上次保存的置顶状态
+        if app.always_on_top && !options.always_on_top {
+            app.apply_window_level(&cc.egui_ctx);
+        }
+        app.apply_theme(&cc.egui_ctx);
+
+        app
+    }
+
+    /// 应用当前主题
+    fn apply_theme(&self, ctx: &egui::Context) {
+        let mut visuals = match (self.theme, self.system_theme) {
+            (GuiTheme::Light, _) | (GuiTheme::System, Some(eframe::Theme::Light)) => {
+                Visuals::light()
+            }
+            _ => Visuals::dark(),
+        };
+        visuals.window_rounding = egui::Rounding::same(10.0);
+        visuals.window_shadow.blur = 10.0;
+        ctx.set_visuals(visuals);
+    }
+
+    /// 在浅色、深色和跟随系统之间切换主题
+    fn toggle_theme(&mut self, ctx: &egui::Context) {
+        self.theme = match self.theme {
+            GuiTheme::Light => GuiTheme::Dark,
+            GuiTheme::Dark => GuiTheme::System,
+            GuiTheme::System => GuiTheme::Light,
+        };
+        self.apply_theme(ctx);
+    }
+
+    /// 应用当前的窗口置顶状态
+    fn apply_window_level(&self, ctx: &egui::Context) {
+        let level = if self.always_on_top {
+            egui::WindowLevel::AlwaysOnTop
+        } else {
+            egui::WindowLevel::Normal
+        };
+        ctx.send_viewport_cmd(egui::ViewportCommand::WindowLevel(level));
+    }
+
+    /// 当前需要持久化的设置
+    fn settings(&self) -> GuiSettings {
+        GuiSettings {
+            theme: self.theme,
+            window_size: self.window_size,
+            window_position: self.window_position,
+            always_on_top: self.always_on_top,
+            output_dir: self.output_dir.clone(),
+            threads: self.threads,
+            ..GuiSettings::default()
+        }
     }
 
     /// 选择输出目录
@@ -107,6 +205,7 @@ impl M3u8DownloaderApp {
             keep_segments: self.keep_segments,
             headers,
             gui: false, // 不需要在这里设置为true，因为已经在GUI模式中
+            ..Args::default()
         };
 
         // 在后台运行下载任务
@@ -120,7 +219,27 @@ impl M3u8DownloaderApp {
 
     /// 渲染输入表单
     fn render_input_form(&mut self, ui: &mut Ui) {
-        ui.heading("M3U8 下载器");
+        ui.horizontal(|ui| {
+            ui.heading("M3U8 下载器");
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                let label = match self.theme {
+                    GuiTheme::Light => "☀ 浅色",
+                    GuiTheme::Dark => "🌙 深色",
+                    GuiTheme::System => "💻 跟随系统",
+                };
+                if ui.button(label).on_hover_text("切换主题").clicked() {
+                    self.toggle_theme(ui.ctx());
+                }
+                if ui
+                    .selectable_label(self.always_on_top, "📌 置顶")
+                    .on_hover_text("窗口置顶显示")
+                    .clicked()
+                {
+                    self.always_on_top = !self.always_on_top;
+                    self.apply_window_level(ui.ctx());
+                }
+            });
+        });
         ui.add_space(10.0);
 
         egui::Frame::group(ui.style()).show(ui, |ui| {
@@ -207,8 +326,15 @@ impl M3u8DownloaderApp {
         if let Some(promise) = &self.download_promise {
             if let Some(result) = promise.ready() {
                 match result {
-                    Ok(_) => {
-                        self.status_message = "下载完成!".to_string();
+                    Ok(stats) => {
+                        self.status_message = format!(
+                            "下载完成! 下载 {} 个分段（跳过 {}，失败 {}），{:.1} MiB，耗时 {:.1} 秒",
+                            stats.segments_downloaded,
+                            stats.segments_skipped,
+                            stats.segments_failed,
+                            stats.total_bytes as f64 / (1024.0 * 1024.0),
+                            stats.duration_secs
+                        );
                         self.status_color = Color32::GREEN;
                     }
                     Err(e) => {
@@ -224,10 +350,37 @@ impl M3u8DownloaderApp {
 }
 
 impl eframe::App for M3u8DownloaderApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         // 检查下载状态
         self.check_download_status();
 
+        // 跟随系统主题的变化
+        let system_theme = frame.info().system_theme;
+        if system_theme != self.system_theme {
+            self.system_theme = system_theme;
+            if self.theme == GuiTheme::System {
+                self.apply_theme(ctx);
+            }
+        }
+
+        // 记录窗口尺寸和位置以便下次启动时恢复，最大化时保留之前的值
+        let (maximized, inner_rect, outer_rect) = ctx.input(|i| {
+            let viewport = i.viewport();
+            (
+                viewport.maximized.unwrap_or(false),
+                viewport.inner_rect,
+                viewport.outer_rect,
+            )
+        });
+        if !maximized {
+            if let Some(rect) = inner_rect {
+                self.window_size = Some([rect.width(), rect.height()]);
+            }
+            if let Some(rect) = outer_rect {
+                self.window_position = Some([rect.min.x, rect.min.y]);
+            }
+        }
+
         // 主窗口
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
@@ -240,15 +393,28 @@ impl eframe::App for M3u8DownloaderApp {
             ctx.request_repaint();
         }
     }
+
+    fn save(&mut self, _storage: &mut dyn eframe::Storage) {
+        if let Err(e) = self.settings().save() {
+            warn!("Failed to save GUI settings: {}", e);
+        }
+    }
 }
 
 /// 启动GUI应用
-pub fn run_gui() -> Result<(), eframe::Error> {
+pub fn run_gui(gui_options: GuiOptions) -> Result<(), eframe::Error> {
+    let mut viewport = egui::ViewportBuilder::default()
+        .with_inner_size(gui_options.window_size.unwrap_or([670.0, 440.0]))
+        .with_min_inner_size([670.0, 440.0])
+        .with_maximized(gui_options.maximized);
+    if gui_options.always_on_top {
+        viewport = viewport.with_always_on_top();
+    }
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default()
-            .with_inner_size([670.0, 440.0])
-            .with_min_inner_size([670.0, 440.0]),
+        viewport,
         centered: true,
+        // 主题由应用自行管理
+        follow_system_theme: false,
         ..Default::default()
     };
 
@@ -257,7 +423,7 @@ pub fn run_gui() -> Result<(), eframe::Error> {
         options,
         Box::new(|cc| {
             setup_chinese_fonts(&cc.egui_ctx).unwrap();
-            Box::new(M3u8DownloaderApp::new(cc))
+            Box::new(M3u8DownloaderApp::new(cc, gui_options))
         }),
     )
 }
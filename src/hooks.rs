@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::process::{Output, Stdio};
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use url::Url;
+
+/// 构建一个通过系统shell执行的命令
+fn shell_command(cmd: &str) -> Command {
+    if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    }
+}
+
+/// 在获取播放列表前执行用户指定的命令，返回（可能被改写的）URL
+///
+/// 命令通过 `M3U8_URL` 环境变量获取原始URL，若其标准输出非空，则使用输出的第一行作为新的URL。
+pub async fn run_pre_hook(cmd: &str, url: &str) -> Result<String> {
+    debug!("Running pre-hook: {}", cmd);
+
+    let output = shell_command(cmd).env("M3U8_URL", url).output().await?;
+
+    debug!(
+        "Pre-hook stderr: {}",
+        String::from_utf8_lossy(&output.stderr).trim_end()
+    );
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Pre-hook exited with code: {:?}",
+            output.status.code()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().map(str::trim).find(|l| !l.is_empty()) {
+        Some(new_url) => Ok(new_url.to_string()),
+        None => Ok(url.to_string()),
+    }
+}
+
+/// 将 `input` 写入命令的标准输入并等待其结束，`name` 用于日志和错误信息
+async fn run_with_stdin(cmd: &str, input: &str, name: &str) -> Result<Output> {
+    let mut child = shell_command(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // 在单独的任务中写入标准输入，避免输出缓冲区写满导致死锁
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open {} stdin", name))?;
+    let input = input.to_string();
+    let writer = tokio::spawn(async move {
+        stdin.write_all(input.as_bytes()).await?;
+        stdin.shutdown().await
+    });
+
+    let output = child.wait_with_output().await?;
+    if let Err(e) = writer.await? {
+        debug!("{} closed stdin early: {}", name, e);
+    }
+
+    debug!(
+        "{} stderr: {}",
+        name,
+        String::from_utf8_lossy(&output.stderr).trim_end()
+    );
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} exited with code: {:?}",
+            name,
+            output.status.code()
+        ));
+    }
+
+    Ok(output)
+}
+
+/// 将原始播放列表文本通过标准输入传给命令，返回其标准输出作为改写后的播放列表
+pub async fn run_playlist_hook(cmd: &str, content: &str) -> Result<String> {
+    debug!("Running playlist hook: {}", cmd);
+
+    let output = run_with_stdin(cmd, content, "Playlist hook").await?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// 下载分段前重新签名分段URL的命令
+///
+/// 原始URL通过标准输入传给命令，标准输出的第一个非空行作为签名后的URL。
+/// 签名结果按原始URL缓存，多个分段共用同一URL（如 `#EXT-X-BYTERANGE`）时只签名一次。
+#[derive(Debug)]
+pub struct SegmentPresigner {
+    cmd: String,
+    cache: Mutex<HashMap<Url, Url>>,
+}
+
+impl SegmentPresigner {
+    pub fn new(cmd: String) -> Self {
+        Self {
+            cmd,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 返回 `url` 签名后的URL，优先使用缓存
+    pub async fn presign(&self, url: &Url) -> Result<Url> {
+        if let Some(signed) = self.cache.lock().unwrap().get(url) {
+            return Ok(signed.clone());
+        }
+
+        debug!("Running segment presign hook for {}", url);
+        let output = run_with_stdin(&self.cmd, url.as_str(), "Segment presign hook").await?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty())
+            .ok_or_else(|| anyhow!("Segment presign hook printed no URL for {}", url))?;
+        let signed = Url::parse(line).map_err(|e| {
+            anyhow!(
+                "Segment presign hook printed an invalid URL {:?}: {}",
+                line,
+                e
+            )
+        })?;
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(url.clone(), signed.clone());
+        Ok(signed)
+    }
+
+    /// 丢弃 `url` 的缓存签名，下次下载时重新签名（签名可能已过期）
+    pub fn forget(&self, url: &Url) {
+        self.cache.lock().unwrap().remove(url);
+    }
+}
+
+/// 下载完成后执行用户指定的命令
+pub async fn run_post_hook(cmd: &str, envs: &[(&str, String)]) -> Result<()> {
+    debug!("Running post-hook: {}", cmd);
+
+    let output = shell_command(cmd)
+        .envs(envs.iter().map(|(k, v)| (*k, v.as_str())))
+        .output()
+        .await?;
+
+    debug!(
+        "Post-hook stdout: {}",
+        String::from_utf8_lossy(&output.stdout).trim_end()
+    );
+    debug!(
+        "Post-hook stderr: {}",
+        String::from_utf8_lossy(&output.stderr).trim_end()
+    );
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Post-hook exited with code: {:?}",
+            output.status.code()
+        ));
+    }
+
+    Ok(())
+}
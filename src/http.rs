@@ -1,22 +1,248 @@
 use anyhow::Result;
+use cookie_store::{CookieDomain, CookieExpiration};
 use log::{debug, warn};
 use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue},
-    Client,
+    cookie::CookieStore as CookieProvider,
+    header::{HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, COOKIE},
+    Client, Proxy, Response,
 };
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::fs;
+use url::Url;
+
+use crate::error::M3u8Error;
+
+/// 客户端的全局请求超时
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 第一次重试前的等待时间，之后每次翻倍
+pub const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// 默认的可重试HTTP状态码
+pub const DEFAULT_RETRY_CODES: &[u16] = &[429, 500, 502, 503, 504];
+
+/// 分段和播放列表请求的重试策略
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// 每个请求的最大尝试次数（包括第一次）
+    pub max_attempts: u32,
+    /// 第一个分段额外的尝试次数
+    pub extra_attempts_first: u32,
+    /// 最后一个分段额外的尝试次数
+    pub extra_attempts_last: u32,
+    /// 触发重试的HTTP状态码
+    pub retry_codes: HashSet<u16>,
+}
+
+impl RetryPolicy {
+    /// 计算第 `index` 个分段（共 `total` 个）的最大尝试次数，首尾分段会加上额外次数
+    pub fn attempts_for(&self, index: usize, total: usize) -> u32 {
+        let mut attempts = self.max_attempts.max(1);
+        if index == 0 {
+            attempts = attempts.saturating_add(self.extra_attempts_first);
+        }
+        if index + 1 == total {
+            attempts = attempts.saturating_add(self.extra_attempts_last);
+        }
+        attempts
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            extra_attempts_first: 0,
+            extra_attempts_last: 0,
+            retry_codes: DEFAULT_RETRY_CODES.iter().copied().collect(),
+        }
+    }
+}
+
+/// 检查错误是否可重试
+pub fn is_retryable_error(error: &anyhow::Error, retry_codes: &HashSet<u16>) -> bool {
+    if let Some(M3u8Error::SegmentTimeout { .. }) = error.downcast_ref::<M3u8Error>() {
+        return true;
+    }
+    // 检查是否是 reqwest 错误
+    if let Some(reqwest_error) = error.downcast_ref::<reqwest::Error>() {
+        // 检查是否是网络错误或超时错误
+        if reqwest_error.is_timeout() || reqwest_error.is_connect() {
+            return true;
+        }
+        // 检查状态码是否在用户配置的重试列表中
+        if let Some(status) = reqwest_error.status() {
+            return retry_codes.contains(&status.as_u16());
+        }
+    }
+    false
+}
+
+/// 按重试策略执行请求，可重试的错误以指数退避重试，返回最后一次的错误
+pub async fn retry_with_backoff<T, F, Fut>(
+    policy: &RetryPolicy,
+    url: &Url,
+    mut request: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut delay = RETRY_INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < max_attempts && is_retryable_error(&e, &policy.retry_codes) => {
+                warn!(
+                    "Request to {} failed (attempt {}/{}), retrying in {}ms: {}",
+                    url,
+                    attempt,
+                    max_attempts,
+                    delay.as_millis(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// 发送GET请求，按重试策略重试网络错误和可重试的状态码
+pub async fn fetch_with_retry(
+    client: &Client,
+    url: &Url,
+    policy: &RetryPolicy,
+) -> Result<Response> {
+    retry_with_backoff(policy, url, || async {
+        Ok(client.get(url.clone()).send().await?.error_for_status()?)
+    })
+    .await
+}
+
+/// HTTP客户端选项
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// 自定义请求头，格式为 `Header: Value`
+    pub headers: Vec<String>,
+    /// 绑定的本地地址，使用未指定地址（如 `0.0.0.0`）时仅限定地址族
+    pub local_address: Option<IpAddr>,
+    /// 请求超时，未设置时使用 [`DEFAULT_TIMEOUT`]
+    pub timeout: Option<Duration>,
+    /// HTTP/2 单个流的初始流量控制窗口（字节）
+    pub http2_stream_window: Option<u32>,
+    /// HTTP/2 整个连接的初始流量控制窗口（字节）
+    pub http2_connection_window: Option<u32>,
+    /// 按主机名选择的代理，未列出的主机直接连接
+    pub host_proxies: HashMap<String, Url>,
+    /// 保存服务器设置的 Cookie，并在后续请求中发送
+    pub cookie_store: Option<Arc<CookieStoreMutex>>,
+    /// 只向匹配的主机发送命令行指定的 Cookie，为 `None` 时发送给所有主机
+    ///
+    /// 命令行未指定 `--cookie-domain-allowlist` 时为播放列表的主机及其子域名，见 [`DomainFilter::for_host`]。
+    pub cookie_domains: Option<DomainFilter>,
+}
+
+/// 域名白名单，`*.example.com` 匹配 example.com 的所有子域名（不含 example.com 本身）
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainFilter {
+    patterns: Vec<String>,
+}
+
+impl DomainFilter {
+    pub fn new(patterns: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(|p| p.trim().trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// 只包含 `host` 本身及其子域名（`*.host`）的白名单
+    pub fn for_host(host: &str) -> Self {
+        Self::new([host.to_string(), format!("*.{}", host)])
+    }
+
+    /// 域名（忽略大小写和 Cookie 域名的前导 `.`）是否在白名单中
+    pub fn matches(&self, cookie_domain: &str) -> bool {
+        let domain = cookie_domain
+            .trim_start_matches('.')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(suffix) => domain
+                    .strip_suffix(suffix)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => domain == *pattern,
+            })
+    }
+}
+
+/// 只向白名单中的主机附加固定 Cookie 的 Cookie 提供者，服务器设置的 Cookie 仍交给 `store` 管理
+struct FilteredCookies {
+    cookie: HeaderValue,
+    domains: DomainFilter,
+    store: Option<Arc<CookieStoreMutex>>,
+}
+
+impl CookieProvider for FilteredCookies {
+    fn set_cookies(&self, headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        if let Some(store) = &self.store {
+            store.set_cookies(headers, url);
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let stored = self.store.as_ref().and_then(|store| store.cookies(url));
+        if !url
+            .host_str()
+            .is_some_and(|host| self.domains.matches(host))
+        {
+            return stored;
+        }
+        match stored {
+            Some(stored) => HeaderValue::from_str(&format!(
+                "{}; {}",
+                self.cookie.to_str().ok()?,
+                stored.to_str().ok()?
+            ))
+            .ok(),
+            None => Some(self.cookie.clone()),
+        }
+    }
+}
 
 /// 构建HTTP客户端，包含自定义请求头
-pub fn build_http_client(custom_headers: &[String]) -> Result<Client> {
+pub fn build_http_client(options: &ClientOptions) -> Result<Client> {
     let mut headers = HeaderMap::new();
     headers.insert(
         "User-Agent", 
         HeaderValue::from_static("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/123.0.0.0 Safari/537.36")
     );
 
-    for header in custom_headers {
+    let mut cookies = Vec::new();
+    for header in &options.headers {
         if let Some((key, value)) = header.split_once(':') {
             let header_name = HeaderName::from_bytes(key.trim().as_bytes())?;
+            // 多个 Cookie 头合并为一个，而不是互相覆盖
+            if header_name == COOKIE {
+                cookies.push(value.trim().trim_end_matches(';').trim().to_string());
+                continue;
+            }
             let header_value = HeaderValue::from_str(value.trim())?;
             headers.insert(header_name, header_value);
         } else {
@@ -24,12 +250,283 @@ pub fn build_http_client(custom_headers: &[String]) -> Result<Client> {
         }
     }
 
+    // 限定了域名的 Cookie 由 Cookie 提供者按请求的主机附加，不能放进默认请求头
+    let mut filtered_cookies = None;
+    if !cookies.is_empty() {
+        let cookie = cookies.join("; ");
+        validate_cookie_header(&cookie)?;
+        let cookie = HeaderValue::from_str(&cookie)?;
+        match &options.cookie_domains {
+            Some(domains) => {
+                debug!("Sending cookies only to {:?}", domains);
+                filtered_cookies = Some(Arc::new(FilteredCookies {
+                    cookie,
+                    domains: domains.clone(),
+                    store: options.cookie_store.clone(),
+                }));
+            }
+            None => {
+                headers.insert(COOKIE, cookie);
+            }
+        }
+    }
+
     debug!("Using HTTP headers: {:?}", headers);
 
-    let client = Client::builder()
+    let mut builder = Client::builder()
         .default_headers(headers)
-        .timeout(Duration::from_secs(30))
-        .build()?;
+        .timeout(options.timeout.unwrap_or(DEFAULT_TIMEOUT));
+
+    // 分段本身是已压缩的媒体数据，但播放列表和密钥响应压缩后可以小很多
+    #[cfg(feature = "compression")]
+    {
+        builder = builder.gzip(true).brotli(true).deflate(true);
+    }
+
+    if options.http2_stream_window.is_some() || options.http2_connection_window.is_some() {
+        debug!(
+            "HTTP/2 flow control windows: stream {:?}, connection {:?}",
+            options.http2_stream_window, options.http2_connection_window
+        );
+        builder = builder
+            .http2_initial_stream_window_size(options.http2_stream_window)
+            .http2_initial_connection_window_size(options.http2_connection_window);
+    }
+
+    if !options.host_proxies.is_empty() {
+        debug!("Per-host proxies: {:?}", options.host_proxies);
+        let proxies = options.host_proxies.clone();
+        builder = builder.proxy(Proxy::custom(move |url| {
+            let host = url.host_str()?.to_ascii_lowercase();
+            proxies.get(&host).cloned()
+        }));
+    }
+
+    if let Some(filtered_cookies) = filtered_cookies {
+        builder = builder.cookie_provider(filtered_cookies);
+    } else if let Some(cookie_store) = &options.cookie_store {
+        builder = builder.cookie_provider(cookie_store.clone());
+    }
+
+    if let Some(local_address) = options.local_address {
+        check_local_address(local_address)?;
+        debug!(
+            "Binding HTTP connections to local address: {}",
+            local_address
+        );
+        builder = builder.local_address(local_address);
+    }
+
+    let client = builder.build()?;
 
     Ok(client)
 }
+
+/// 检查 Cookie 头是否由 `name=value` 对组成，避免拼写错误的 Cookie 被服务器静默忽略
+fn validate_cookie_header(cookie: &str) -> Result<()> {
+    for pair in cookie.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        let valid = pair.split_once('=').is_some_and(|(name, _)| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
+        });
+        if !valid {
+            anyhow::bail!(
+                "Invalid cookie '{}': expected name=value pairs separated by ';'",
+                pair
+            );
+        }
+    }
+    Ok(())
+}
+
+/// 构建仅用于获取播放列表和密钥的客户端，使用更短的超时以便尽早发现失效的地址
+pub fn build_playlist_client(options: &ClientOptions, timeout: Duration) -> Result<Client> {
+    build_http_client(&ClientOptions {
+        timeout: Some(timeout),
+        ..options.clone()
+    })
+}
+
+/// 检查地址是否分配给了本机的某个网络接口，避免到第一次连接时才报出难以理解的错误
+fn check_local_address(address: IpAddr) -> Result<()> {
+    if address.is_unspecified() {
+        return Ok(());
+    }
+    UdpSocket::bind(SocketAddr::new(address, 0)).map_err(|e| {
+        anyhow::anyhow!(
+            "Cannot bind to local address {}: {} (is it assigned to a local network interface?)",
+            address,
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// 将 Cookie 存储以 Netscape/Mozilla `cookies.txt` 格式写入文件，可被 curl、wget 和浏览器扩展读取
+///
+/// 会话 Cookie 的过期时间记为 0，HttpOnly 的 Cookie 按 curl 的约定加上 `#HttpOnly_` 前缀。
+pub fn write_netscape_cookies(store: &CookieStore, path: &Path) -> Result<()> {
+    let mut content = String::from("# Netscape HTTP Cookie File\n");
+    for cookie in store.iter_unexpired() {
+        let (domain, include_subdomains) = match &cookie.domain {
+            CookieDomain::HostOnly(host) => (host.clone(), "FALSE"),
+            CookieDomain::Suffix(suffix) => (format!(".{}", suffix), "TRUE"),
+            CookieDomain::NotPresent | CookieDomain::Empty => continue,
+        };
+        let expires = match cookie.expires {
+            CookieExpiration::AtUtc(time) => time.unix_timestamp(),
+            CookieExpiration::SessionEnd => 0,
+        };
+        let http_only = if cookie.http_only().unwrap_or(false) {
+            "#HttpOnly_"
+        } else {
+            ""
+        };
+        content.push_str(&format!(
+            "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            http_only,
+            domain,
+            include_subdomains,
+            cookie.path.as_ref(),
+            if cookie.secure().unwrap_or(false) {
+                "TRUE"
+            } else {
+                "FALSE"
+            },
+            expires,
+            cookie.name(),
+            cookie.value()
+        ));
+    }
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+/// 缓存条目的元数据
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntryMeta {
+    final_url: String,
+    content_type: Option<String>,
+}
+
+/// 一次GET请求的完整响应
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    /// 重定向后的最终URL
+    pub url: Url,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    /// 响应是否来自本地缓存
+    pub from_cache: bool,
+}
+
+/// 带可选磁盘缓存的HTTP客户端
+///
+/// 缓存以 `sha256(url)` 为键保存响应体，不做过期校验，主要用于反复调试同一个流。
+/// 其余请求通过 `Deref` 直接使用内部的 reqwest 客户端。
+#[derive(Debug, Clone)]
+pub struct CachingClient {
+    client: Client,
+    cache_dir: Option<PathBuf>,
+}
+
+impl CachingClient {
+    pub fn new(client: Client, cache_dir: Option<PathBuf>) -> Self {
+        Self { client, cache_dir }
+    }
+
+    /// 是否启用了响应缓存
+    pub fn is_caching(&self) -> bool {
+        self.cache_dir.is_some()
+    }
+
+    /// 发送GET请求并读取完整响应体，启用缓存时优先从缓存读取
+    ///
+    /// 响应包含 `Cache-Control: no-store` 或 `no-cache` 时不会写入缓存。
+    pub async fn get_cached(&self, url: &Url) -> Result<CachedResponse> {
+        let paths = self.cache_dir.as_ref().map(|dir| {
+            let key = sha256::digest(url.as_str());
+            (
+                dir.join(format!("{}.body", key)),
+                dir.join(format!("{}.json", key)),
+            )
+        });
+
+        if let Some((body_path, meta_path)) = &paths {
+            if let (Ok(body), Ok(meta)) = (fs::read(body_path).await, fs::read(meta_path).await) {
+                if let Ok(meta) = serde_json::from_slice::<CacheEntryMeta>(&meta) {
+                    debug!("HTTP cache hit: {}", url);
+                    return Ok(CachedResponse {
+                        url: Url::parse(&meta.final_url)?,
+                        content_type: meta.content_type,
+                        body,
+                        from_cache: true,
+                    });
+                }
+            }
+        }
+
+        let response = self
+            .client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let final_url = response.url().clone();
+        let header_value = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_type = header_value(CONTENT_TYPE);
+        let cache_control = header_value(CACHE_CONTROL);
+        let body = response.bytes().await?.to_vec();
+
+        if let Some((body_path, meta_path)) = &paths {
+            let uncacheable = cache_control.as_deref().is_some_and(|cc| {
+                cc.split(',').map(str::trim).any(|d| {
+                    d.eq_ignore_ascii_case("no-store") || d.eq_ignore_ascii_case("no-cache")
+                })
+            });
+            if uncacheable {
+                debug!(
+                    "Not caching {} due to Cache-Control: {:?}",
+                    url, cache_control
+                );
+            } else {
+                let meta = CacheEntryMeta {
+                    final_url: final_url.to_string(),
+                    content_type: content_type.clone(),
+                };
+                if let Some(dir) = body_path.parent() {
+                    fs::create_dir_all(dir).await?;
+                }
+                // 先写响应体再写元数据，读取时两者都存在才视为命中
+                fs::write(body_path, &body).await?;
+                fs::write(meta_path, serde_json::to_vec(&meta)?).await?;
+            }
+        }
+
+        Ok(CachedResponse {
+            url: final_url,
+            content_type,
+            body,
+            from_cache: false,
+        })
+    }
+}
+
+impl Deref for CachingClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
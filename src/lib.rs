@@ -1,54 +1,1236 @@
+pub mod checksum;
 pub mod cli;
+#[cfg(feature = "compress-segments")]
+pub mod compress;
 pub mod crypto;
+#[cfg(feature = "dash")]
+pub mod dash;
 pub mod downloader;
+pub mod error;
 pub mod gui;
+pub mod hooks;
 pub mod http;
+pub mod logging;
 pub mod merger;
+pub mod metadata;
 pub mod playlist;
+pub mod probe;
+pub mod settings;
+pub mod subtitle;
+pub mod telemetry;
+pub mod util;
 
 use anyhow::Result;
-use log::{error, info};
-use std::sync::Arc;
+use log::{debug, error, info, warn};
+use m3u8_rs::{Map, MediaPlaylist, MediaSegment};
+use reqwest_cookie_store::CookieStoreMutex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::fs;
+use tokio::sync::mpsc;
 use url::Url;
 
-use crate::cli::Args;
-use crate::downloader::download_segments;
-use crate::http::build_http_client;
-use crate::merger::{cleanup_segments, merge_segments};
-use crate::playlist::fetch_and_parse_playlist;
+use crate::checksum::{
+    checksum_manifest_name, compute_checksums, verify_segments, write_checksum_manifest,
+    SegmentHasher,
+};
+use crate::cli::{Args, DownloadOrder, MergerKind, PlaylistFormat, VideoCodec};
+use crate::crypto::DecryptBackend;
+use crate::downloader::{
+    default_iv, download_init_segment, download_segment_stream, download_segments,
+    download_subtitle_segments, fetch_first_segment, init_segment_file_name, reuse_segments,
+    segment_file_names, splice_local_segments, DownloadOptions, KeyInfoLog,
+};
+use crate::hooks::{run_post_hook, run_pre_hook, SegmentPresigner};
+use crate::http::{
+    build_http_client, build_playlist_client, write_netscape_cookies, CachingClient, ClientOptions,
+    DomainFilter, RetryPolicy,
+};
+use crate::merger::{
+    check_container_compatibility, check_ffmpeg, cleanup_segments, create_merger,
+    extract_first_tile, extract_thumbnail, generate_gap_filler, generate_playlist_from_segments,
+    generate_srt_from_vtt_segments, merge_groups, merge_parts, output_container, split_by_size,
+    write_local_hls_playlist, StreamingMerge,
+};
+use crate::metadata::{
+    ad_segment_indices, build_chapters, parse_date_ranges, stream_title, write_batch_playlist,
+    write_chapters_vtt, write_date_ranges, write_description, write_ffmetadata_chapters,
+    write_segments_manifest, write_timing_csv, write_title_description, write_url_hash_index,
+    write_xspf_playlist, PlaylistEntry, VideoMetadata, CHAPTERS_FILE_NAME, SEGMENTS_MANIFEST_NAME,
+    URL_HASH_INDEX_NAME,
+};
+use crate::playlist::{
+    discontinuity_groups, fetch_and_parse_playlist, fetch_segments_lazy, fetch_with_cache,
+    find_segment_gaps, is_images_only, is_mpd_url, live_poll_delay, playlist_title,
+    segment_tile_layout, select_subtitle, KeyInfo, LivePlaylistState, PlaylistOptions, SegmentGap,
+    SubtitleRendition,
+};
+use crate::probe::{
+    ffprobe_path, probe_media_info, probe_streams, validate_independent_segments, verify_audio,
+    MediaInfo,
+};
+use crate::subtitle::vtt_segment_file_name;
+use crate::telemetry::{append_record, RunStats, RunStatus, TelemetryRecord};
+use crate::util::{
+    detect_container, placeholder_file_name, placeholder_segment, sanitize_filename, DiskFlusher,
+    TokenBucket,
+};
 
-/// 运行M3U8下载器的主要逻辑
-pub async fn run(args: Args) -> Result<()> {
-    let client = Arc::new(build_http_client(&args.headers)?);
-    let m3u8_url = Url::parse(&args.url)?;
+/// 一次运行的结果统计，由 [`run`] 返回
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadStats {
+    /// 本次实际下载的分段数
+    pub segments_downloaded: usize,
+    /// 本地已存在而跳过下载的分段数
+    pub segments_skipped: usize,
+    /// 下载失败的分段数
+    pub segments_failed: usize,
+    /// 本次下载的字节数
+    pub total_bytes: u64,
+    /// 运行耗时（秒）
+    pub duration_secs: f64,
+    /// 合并后的输出文件；没有合并出单个文件时为分段目录，批量模式下为输出根目录
+    pub output_path: PathBuf,
+    /// 播放列表的总时长（秒），批量模式下为各播放列表之和
+    pub playlist_duration_secs: f64,
+}
 
-    // 创建一个唯一的输出目录，避免冲突
-    let url_hash = &sha256::digest(&args.url)[..12];
-    let output_dir = args.output_dir.join(url_hash);
-    info!("Segments will be saved to: {:?}", output_dir);
-    fs::create_dir_all(&output_dir).await?;
+impl DownloadStats {
+    fn new(stats: &RunStats, elapsed: Duration) -> Self {
+        Self {
+            segments_downloaded: stats
+                .segment_count
+                .saturating_sub(stats.failed_segments + stats.skipped_segments),
+            segments_skipped: stats.skipped_segments,
+            segments_failed: stats.failed_segments,
+            total_bytes: stats.total_bytes,
+            duration_secs: elapsed.as_secs_f64(),
+            output_path: stats
+                .output_file
+                .clone()
+                .or_else(|| stats.segments_dir.clone())
+                .unwrap_or_default(),
+            playlist_duration_secs: stats.duration_secs,
+        }
+    }
+}
+
+impl fmt::Display for DownloadStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} segments downloaded, {} skipped, {} failed; {:.1} MiB in {:.1}s; {:.1}s of media at {:?}",
+            self.segments_downloaded,
+            self.segments_skipped,
+            self.segments_failed,
+            self.total_bytes as f64 / (1024.0 * 1024.0),
+            self.duration_secs,
+            self.playlist_duration_secs,
+            self.output_path
+        )
+    }
+}
+
+/// 运行M3U8下载器，指定 `--input-file` 时依次下载文件中的每个URL
+pub async fn run(mut args: Args) -> Result<DownloadStats> {
+    let started = Instant::now();
+    // GUI 和库调用方直接构造参数，不经过 parse_args
+    args.expand();
+    // 在启动时固定相对路径，避免之后切换工作目录导致文件位置出乎意料
+    if args.output_dir_abs {
+        args.output_dir = std::path::absolute(&args.output_dir).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to resolve output directory {:?}: {}",
+                args.output_dir,
+                e
+            )
+        })?;
+        info!("Output directory resolved to {:?}", args.output_dir);
+    }
+
+    if let Some(dir) = &args.generate_m3u8_from_segments {
+        let ffprobe = ffprobe_path(args.ffmpeg_path.as_deref());
+        let playlist = generate_playlist_from_segments(dir, &ffprobe).await?;
+        return Ok(DownloadStats {
+            duration_secs: started.elapsed().as_secs_f64(),
+            output_path: playlist,
+            ..Default::default()
+        });
+    }
+
+    let mut stats = RunStats::default();
+    match args.input_file.clone() {
+        Some(input_file) => run_batch(args, &input_file, &mut stats).await?,
+        None => run_single(args, &mut stats).await?,
+    }
+    Ok(DownloadStats::new(&stats, started.elapsed()))
+}
+
+/// 依次下载输入文件中的每个URL（每行一个，`#` 开头为注释），单个失败不会中断后续下载
+///
+/// `totals` 累计所有下载的分段和字节数。
+async fn run_batch(args: Args, input_file: &Path, totals: &mut RunStats) -> Result<()> {
+    totals.segments_dir = Some(args.output_dir.clone());
+    let content = fs::read_to_string(input_file)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read input file {:?}: {}", input_file, e))?;
+    let urls: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    info!("Batch mode: {} URLs in {:?}", urls.len(), input_file);
+
+    let mut entries = Vec::new();
+    let mut failures = 0;
+    let index_width = urls.len().to_string().len();
+    for (i, url) in urls.iter().enumerate() {
+        info!("[{}/{}] Downloading {}", i + 1, urls.len(), url);
+        let mut item_args = Args {
+            url: url.to_string(),
+            input_file: None,
+            ..args.clone()
+        };
+        // 每个任务使用独立的子目录，以任务序号或流标题命名；标题目录带有URL哈希后缀，
+        // 同名的任务也不会共用目录
+        if args.concurrent_playlists_output_dir_per_job {
+            if args.output_video_from_title {
+                item_args.output_dir_per_download = true;
+            } else {
+                item_args.output_dir =
+                    args.output_dir
+                        .join(format!("{:0width$}", i + 1, width = index_width));
+            }
+        }
+        let mut stats = RunStats::default();
+        let result = run_single(item_args, &mut stats).await;
+        totals.segment_count += stats.segment_count;
+        totals.failed_segments += stats.failed_segments;
+        totals.skipped_segments += stats.skipped_segments;
+        totals.total_bytes += stats.total_bytes;
+        totals.duration_secs += stats.duration_secs;
+        match result {
+            Ok(_) => {
+                if let Some(output_file) = stats.output_file {
+                    entries.push(PlaylistEntry {
+                        title: stats.title.unwrap_or_else(|| url.to_string()),
+                        location: output_file,
+                        duration_ms: (stats.duration_secs * 1000.0).round() as u64,
+                    });
+                }
+            }
+            Err(e) => {
+                error!("Failed to download {}: {}", url, e);
+                failures += 1;
+            }
+        }
+    }
+
+    // 导出成功下载的文件列表
+    if let Some(xspf_path) = &args.output_xml_playlist {
+        write_xspf_playlist(&entries, xspf_path).await?;
+        info!(
+            "XSPF playlist with {} entries written to {:?}",
+            entries.len(),
+            xspf_path
+        );
+    }
+    if let Some(m3u8_path) = &args.output_m3u8_from_download {
+        let playlist_entries: Vec<(String, PathBuf, f32)> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.title.clone(),
+                    e.location.clone(),
+                    e.duration_ms as f32 / 1000.0,
+                )
+            })
+            .collect();
+        write_batch_playlist(&playlist_entries, m3u8_path).await?;
+        info!(
+            "M3U8 playlist with {} entries written to {:?}",
+            playlist_entries.len(),
+            m3u8_path
+        );
+    }
+
+    if failures > 0 {
+        anyhow::bail!("{} out of {} downloads failed.", failures, urls.len());
+    }
+    Ok(())
+}
+
+/// 下载单个URL，启用 `--telemetry-file` 时在结束后追加一条运行记录
+async fn run_single(args: Args, stats: &mut RunStats) -> Result<()> {
+    let Some(telemetry_file) = args.telemetry_file.clone() else {
+        return download(args, stats).await;
+    };
+
+    let url = args.url.clone();
+    let start_time = chrono::Utc::now();
+    let (result, status) = tokio::select! {
+        result = download(args, stats) => {
+            let status = if result.is_ok() { RunStatus::Ok } else { RunStatus::Failed };
+            (result, status)
+        }
+        _ = tokio::signal::ctrl_c() => {
+            (Err(anyhow::anyhow!("Download cancelled")), RunStatus::Cancelled)
+        }
+    };
+
+    let record = TelemetryRecord::new(&url, start_time, chrono::Utc::now(), stats, status);
+    if let Err(e) = append_record(&telemetry_file, &record).await {
+        warn!("Failed to write telemetry to {:?}: {}", telemetry_file, e);
+    }
+
+    result
+}
 
-    let (media_playlist, base_url, key_info) =
-        fetch_and_parse_playlist(client.clone(), m3u8_url).await?;
+/// 以URL哈希命名的唯一输出目录，避免冲突
+///
+/// 使用原始URL计算，确保钩子改写（如签名）后仍能复用同一目录。
+fn url_output_dir(args: &Args) -> PathBuf {
+    args.output_dir.join(url_hash(&args.url))
+}
 
+/// 原始URL的 SHA-256 前 12 位
+fn url_hash(url: &str) -> String {
+    sha256::digest(url)[..12].to_string()
+}
+
+/// 以流标题加URL哈希命名的输出目录 `<标题>-<哈希>`，没有标题时只用URL哈希
+///
+/// 标题相同的不同流（如同一节目的多集）不会写入同一目录。
+fn title_output_dir(args: &Args, playlist: &MediaPlaylist, url: &Url) -> PathBuf {
+    let title = stream_title(playlist, url)
+        .map(|title| sanitize_filename(&title))
+        .unwrap_or_default();
+    let hash = url_hash(&args.url);
+    if title.is_empty() {
+        args.output_dir.join(hash)
+    } else {
+        args.output_dir.join(format!("{}-{}", title, hash))
+    }
+}
+
+/// 检查输出文件是否已存在且非空
+async fn output_exists(path: &Path) -> bool {
+    fs::metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0)
+}
+
+/// 以流标题命名的输出文件名，依次尝试 `#EXT-X-TITLE`、变体流的 `NAME` 属性和URL路径
+///
+/// 沿用 `--output-video` 的扩展名；同时启用 `--output-format-auto` 时不加扩展名，由分段内容决定。
+fn title_output_video(
+    args: &Args,
+    playlist: &MediaPlaylist,
+    variant_name: Option<String>,
+    url: &Url,
+) -> String {
+    let title = playlist_title(playlist)
+        .or(variant_name)
+        .or_else(|| stream_title(playlist, url))
+        .unwrap_or_default();
+    let name = sanitize_filename(&title);
+    if args.output_format_auto {
+        return name;
+    }
+    let extension = match (&args.output_force_extension, args.container) {
+        (Some(extension), _) => extension.clone(),
+        (None, Some(container)) => container.extension().to_string(),
+        (None, None) => Path::new(&args.output_video)
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_else(|| "mp4".to_string()),
+    };
+    format!("{}.{}", name, extension)
+}
+
+/// 图像播放列表缩略图的文件名
+const PLAYLIST_THUMBNAIL_NAME: &str = "thumbnail.jpg";
+
+/// 下载图像播放列表的第一个分段，保存为输出目录下的 `thumbnail.jpg`
+///
+/// 分段为 `#EXT-X-TILES` 拼图时用 FFmpeg 裁剪出第一张，裁剪失败则保留整张拼图。
+async fn write_playlist_thumbnail(
+    client: &CachingClient,
+    playlist: &MediaPlaylist,
+    base_url: &Url,
+    output_dir: &Path,
+    ffmpeg_path: Option<&Path>,
+) -> Result<PathBuf> {
+    let segment = playlist
+        .segments
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("The playlist has no image segments"))?;
+    let image = client.get_cached(&base_url.join(&segment.uri)?).await?.body;
+    let thumbnail = output_dir.join(PLAYLIST_THUMBNAIL_NAME);
+
+    match segment_tile_layout(segment).filter(|tiles| tiles.layout != [1, 1]) {
+        Some(tiles) => {
+            let sheet = thumbnail.with_extension("tiles.jpg");
+            fs::write(&sheet, &image).await?;
+            match extract_first_tile(ffmpeg_path, &sheet, &thumbnail, tiles.resolution).await {
+                Ok(()) => fs::remove_file(&sheet).await?,
+                Err(e) => {
+                    warn!(
+                        "Failed to crop the first tile, keeping the whole {}x{} sprite sheet: {}",
+                        tiles.layout[0], tiles.layout[1], e
+                    );
+                    fs::rename(&sheet, &thumbnail).await?;
+                }
+            }
+        }
+        None => fs::write(&thumbnail, &image).await?,
+    }
+    Ok(thumbnail)
+}
+
+/// 按播放列表顺序确定每个分段的本地文件名，压缩保存的分段以 .gz 结尾，下载和合并时据此压缩和解压
+///
+/// MPEG-DASH 的分段是分片 MP4，`dash` 时使用 `.m4s` 和 `.mp4` 扩展名。
+fn local_segment_files(args: &Args, segments: &[MediaSegment], dash: bool) -> Vec<String> {
+    let segment_files = match dash {
+        #[cfg(feature = "dash")]
+        true => dash::dash_segment_file_names(
+            segments,
+            args.segment_filename_from_uri,
+            args.segment_filename_padding,
+        ),
+        _ => segment_file_names(
+            segments,
+            args.segment_filename_from_uri,
+            args.segment_filename_padding,
+        ),
+    };
+    #[cfg(feature = "compress-segments")]
+    if args.write_compressed_segments {
+        return segment_files
+            .into_iter()
+            .map(|name| format!("{}.{}", name, compress::COMPRESSED_EXTENSION))
+            .collect();
+    }
+    segment_files
+}
+
+/// 为下载失败的分段写入占位文件
+///
+/// 优先用 FFmpeg 生成与分段等长的黑屏静音分段，输出时长保持不变；FFmpeg 不可用时退回
+/// 不带时间戳的空包占位，缺失的时长不计入输出。
+async fn write_placeholder(
+    args: &Args,
+    reference: Option<&Path>,
+    path: &Path,
+    duration_secs: f32,
+) -> Result<()> {
+    // 先生成到未压缩的临时文件，按需压缩后再写入占位文件
+    let filler_path = path.with_extension("filler.ts");
+    let placeholder = match generate_gap_filler(
+        args.ffmpeg_path.as_deref(),
+        reference,
+        &filler_path,
+        f64::from(duration_secs),
+    )
+    .await
+    {
+        Ok(()) => {
+            let data = fs::read(&filler_path).await?;
+            fs::remove_file(&filler_path).await?;
+            data
+        }
+        Err(e) => {
+            warn!(
+                "Could not generate a black/silent placeholder for {:?}, writing null packets \
+                 instead (the missing duration is dropped from the output): {}",
+                path, e
+            );
+            let _ = fs::remove_file(&filler_path).await;
+            placeholder_segment(duration_secs)
+        }
+    };
+    #[cfg(feature = "compress-segments")]
+    let placeholder = if compress::is_compressed(path) {
+        compress::gzip(&placeholder, args.output_compression_level)?
+    } else {
+        placeholder
+    };
+    fs::write(path, placeholder).await?;
+    Ok(())
+}
+
+/// 下载所选字幕轨道的 WebVTT 分段，转换为输出视频旁的 `<名称>.<语言>.srt`
+///
+/// 字幕分段在转换后删除，`--keep-segments` 时保留。
+async fn write_subtitles(
+    args: &Args,
+    client: &CachingClient,
+    playlist_client: Arc<CachingClient>,
+    subtitles: &[SubtitleRendition],
+    playlist_options: &PlaylistOptions,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let subtitle = select_subtitle(subtitles, args.subtitle_lang.as_deref()).ok_or_else(|| {
+        match &args.subtitle_lang {
+            Some(lang) => anyhow::anyhow!("No subtitle track with language '{}'", lang),
+            None => anyhow::anyhow!("The playlist has no subtitle tracks"),
+        }
+    })?;
     info!(
-        "Successfully parsed media playlist. Found {} segments.",
-        media_playlist.segments.len()
+        "Downloading subtitle track '{}' from {}",
+        subtitle.name, subtitle.url
     );
+    let playlist =
+        fetch_and_parse_playlist(playlist_client, subtitle.url.clone(), playlist_options).await?;
+
+    let extension = match &subtitle.language {
+        Some(language) => format!("{}.srt", sanitize_filename(language)),
+        None => "srt".to_string(),
+    };
+    let srt_path = output_dir.join(Path::new(&args.output_video).with_extension(extension));
+    let result = async {
+        let count = download_subtitle_segments(client, &playlist, output_dir).await?;
+        generate_srt_from_vtt_segments(output_dir, count, &srt_path).await
+    }
+    .await;
 
-    let download_results = download_segments(
+    if !args.keep_segments {
+        let names: Vec<String> = (0..playlist.media_playlist.segments.len())
+            .map(vtt_segment_file_name)
+            .collect();
+        cleanup_segments(output_dir, &names).await?;
+    }
+    result.map(|()| srt_path)
+}
+
+/// 下载并探测第一个分段，将媒体信息打印到标准输出，不创建输出目录
+async fn list_media_info(
+    args: &Args,
+    client: &CachingClient,
+    key_client: Arc<CachingClient>,
+    playlist: &MediaPlaylist,
+    base_url: &Url,
+    key_info: Option<KeyInfo>,
+) -> Result<()> {
+    let data = fetch_first_segment(
         client,
-        &media_playlist.segments,
+        key_client,
+        &playlist.segments,
         base_url,
-        output_dir.clone(),
-        args.threads,
         key_info,
+        args.playlist_start_number
+            .unwrap_or(playlist.media_sequence),
+        args.iv_offset,
     )
-    .await;
+    .await?;
+    let sample = std::env::temp_dir().join(format!("m3u8_media_info_{}", uuid::Uuid::new_v4()));
+    fs::write(&sample, &data).await?;
+    let ffprobe = ffprobe_path(args.ffmpeg_path.as_deref());
+    let probed = probe_media_info(&ffprobe, &sample).await;
+    if let Err(e) = fs::remove_file(&sample).await {
+        warn!("Failed to remove {:?}: {}", sample, e);
+    }
+
+    let json = probed?;
+    if args.json_output {
+        println!("{}", json.trim_end());
+    } else {
+        print!("{}", MediaInfo::from_json(&json)?);
+    }
+    Ok(())
+}
+
+/// 运行M3U8下载器的主要逻辑
+async fn download(mut args: Args, stats: &mut RunStats) -> Result<()> {
+    // FFmpeg 要在全部下载完成后才读取文件列表，无法边下载边删除分段
+    if args.concurrent_cleanup && args.merger != MergerKind::Native {
+        anyhow::bail!("--concurrent-cleanup requires --merger native; FFmpeg only reads the segments after every download has finished.");
+    }
+    if args.output_video_codec != VideoCodec::Copy && args.merger == MergerKind::Native {
+        anyhow::bail!(
+            "--output-video-codec requires --merger ffmpeg; the native merger cannot re-encode."
+        );
+    }
+    if args.output_video_resolution.is_some() && args.output_video_codec == VideoCodec::Copy {
+        anyhow::bail!("--output-video-resolution requires --output-video-codec; scaling needs the video to be re-encoded.");
+    }
+    // 间隙的填充分段由 FFmpeg 生成
+    if args.ignore_gaps {
+        check_ffmpeg(args.ffmpeg_path.as_deref())
+            .await
+            .map_err(|e| anyhow::anyhow!("--ignore-gaps requires FFmpeg: {}", e))?;
+    }
+    if (!args.track_id.is_empty() || args.track_type.is_some()) && args.merger == MergerKind::Native
+    {
+        anyhow::bail!(
+            "--track-id and --track-type require --merger ffmpeg; the native merger keeps every track."
+        );
+    }
+    // 文件名可能由不可信的输入拼接而成，不让其扩展名决定输出格式
+    if let Some(extension) = &args.output_force_extension {
+        let forced = Path::new(&args.output_video)
+            .with_extension(extension)
+            .to_string_lossy()
+            .to_string();
+        if forced != args.output_video {
+            info!(
+                "Output video renamed to {:?} (--output-force-extension)",
+                forced
+            );
+            args.output_video = forced;
+        }
+    }
+
+    // 输出目录和文件名都不依赖播放列表时，无需获取播放列表即可检查输出文件
+    if args.no_clobber && !args.output_dir_per_download && !args.output_video_from_title {
+        let output_file = url_output_dir(&args).join(&args.output_video);
+        if output_exists(&output_file).await {
+            info!(
+                "Output file {:?} already exists, skipping download (--no-clobber).",
+                output_file
+            );
+            stats.output_file = Some(output_file);
+            return Ok(());
+        }
+    }
+
+    let local_address = if args.prefer_ipv4 {
+        Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    } else if args.prefer_ipv6 {
+        Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    } else {
+        args.source_address
+    };
+    // 需要导出 Cookie 时，两个客户端共享同一个 Cookie 存储
+    let cookie_store = args
+        .write_cookies
+        .as_ref()
+        .map(|_| Arc::new(CookieStoreMutex::default()));
+    // 未指定白名单时只向播放列表的主机及其子域名发送 Cookie，第三方 CDN 收不到凭据；
+    // 下载前钩子改写的URL在客户端创建之后才得到，这里按用户给出的URL决定
+    let cookie_domains = if args.cookie_domain_allowlist.is_empty() {
+        match Url::parse(&args.url).ok().as_ref().and_then(Url::host_str) {
+            Some(host) => DomainFilter::for_host(host),
+            None => {
+                let has_cookies = args.headers.iter().any(|header| {
+                    header
+                        .split_once(':')
+                        .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("cookie"))
+                });
+                if has_cookies {
+                    warn!("--url has no host, so cookies are not sent anywhere; use --cookie-domain-allowlist to choose the hosts.");
+                }
+                DomainFilter::default()
+            }
+        }
+    } else {
+        DomainFilter::new(args.cookie_domain_allowlist.clone())
+    };
+    let client_options = ClientOptions {
+        headers: args.headers.clone(),
+        local_address,
+        timeout: None,
+        http2_stream_window: args.http2_initial_stream_window_size,
+        http2_connection_window: args.http2_initial_connection_window_size,
+        host_proxies: args.proxy_per_host.iter().cloned().collect(),
+        cookie_store: cookie_store.clone(),
+        cookie_domains: Some(cookie_domains),
+    };
+    let client = build_http_client(&client_options)?;
+    let client = Arc::new(CachingClient::new(client, args.http_cache_dir.clone()));
+    // 播放列表和密钥使用单独的客户端，超时更短
+    let playlist_client = build_playlist_client(
+        &client_options,
+        Duration::from_secs_f64(args.playlist_timeout),
+    )?;
+    let playlist_client = Arc::new(CachingClient::new(
+        playlist_client,
+        args.http_cache_dir.clone(),
+    ));
+
+    // 执行下载前钩子，允许用户改写URL
+    let source_url = match &args.pre_hook {
+        Some(hook) => {
+            info!("Running pre-hook...");
+            let url = run_pre_hook(hook, &args.url)
+                .await
+                .map_err(|e| anyhow::anyhow!("Pre-hook failed, aborting: {}", e))?;
+            if url != args.url {
+                info!("Pre-hook rewrote URL to: {}", url);
+            }
+            url
+        }
+        None => args.url.clone(),
+    };
+    let m3u8_url = Url::parse(&source_url)?;
+
+    let playlist_options = PlaylistOptions {
+        playlist_hook: args.playlist_hook.clone(),
+        encoding: args.playlist_encoding,
+        wait_for_playlist: args.wait_for_playlist.map(Duration::from_secs),
+        refresh_jitter: Duration::from_secs_f64(args.playlist_refresh_jitter),
+        live_poll_interval: args.live_poll_interval.map(Duration::from_secs_f64),
+        base_url: args.playlist_base_url.clone(),
+        max_size: Some(args.max_playlist_size),
+        strip_tags: args.strip_hls_tags.clone(),
+        retry_policy: RetryPolicy {
+            max_attempts: args.playlist_retry.saturating_add(1),
+            ..Default::default()
+        },
+    };
+    let is_mpd = match args.playlist_format {
+        #[cfg(feature = "dash")]
+        Some(PlaylistFormat::Mpd) => true,
+        Some(PlaylistFormat::M3u8) => false,
+        None => is_mpd_url(&m3u8_url),
+    };
+    if args.stream_segments && args.segment_download_order == DownloadOrder::Reverse {
+        anyhow::bail!("--segment-download-order reverse needs the whole playlist before downloading; it cannot be used with --stream-segments.");
+    }
+    #[cfg(feature = "compress-segments")]
+    if args.stream_segments && args.write_compressed_segments {
+        anyhow::bail!("--stream-segments cannot be used with --write-compressed-segments.");
+    }
+    // 在下载前检查拆分大小，避免下载完成后才因溢出失败
+    let split_bytes = args
+        .split_size
+        .map(|mb| {
+            mb.checked_mul(1024 * 1024)
+                .ok_or_else(|| anyhow::anyhow!("--split-size {} MB is too large", mb))
+        })
+        .transpose()?;
+    // 边解析边下载时，分段在下载阶段才从流中逐个取出
+    let mut lazy_segments = None;
+    let fetched = match &args.playlist_cache_dir {
+        _ if args.stream_segments => {
+            if is_mpd {
+                anyhow::bail!("--stream-segments is not supported for MPEG-DASH manifests.");
+            }
+            let lazy =
+                fetch_segments_lazy(playlist_client.clone(), m3u8_url.clone(), &playlist_options)
+                    .await?;
+            lazy_segments = Some(lazy.segments);
+            lazy.fetched
+        }
+        #[cfg(feature = "dash")]
+        _ if is_mpd => {
+            if args.merger == MergerKind::Ffmpeg {
+                warn!("DASH segments are usually fragmented MP4, which the FFmpeg concat merger cannot join; use --merger native for such streams.");
+            }
+            dash::fetch_and_parse_mpd(playlist_client.clone(), m3u8_url.clone(), &playlist_options)
+                .await?
+        }
+        #[cfg(not(feature = "dash"))]
+        _ if is_mpd => {
+            anyhow::bail!(
+                "{} looks like an MPEG-DASH manifest; rebuild with the dash feature to download it, or pass --playlist-format m3u8.",
+                m3u8_url
+            )
+        }
+        Some(cache_dir) => {
+            fetch_with_cache(
+                playlist_client.clone(),
+                m3u8_url.clone(),
+                cache_dir,
+                &playlist_options,
+            )
+            .await?
+        }
+        None => {
+            fetch_and_parse_playlist(playlist_client.clone(), m3u8_url.clone(), &playlist_options)
+                .await?
+        }
+    };
+
+    let session_data = fetched.session_data;
+    let base_url = fetched.base_url;
+    let mut segment_keys = fetched.segment_keys;
+    let variant_name = fetched.variant_name;
+    let subtitles = fetched.subtitles;
+    let live_url = fetched.url;
+    let mut media_playlist = fetched.media_playlist;
+
+    // 直播录制时缓存首次获取的分段列表，用于还原增量更新和发现新分段
+    let live_state = if args.live {
+        if is_mpd {
+            anyhow::bail!("--live is not supported for MPEG-DASH manifests.");
+        }
+        let mut state = LivePlaylistState::new();
+        state.apply_update(&mut media_playlist.clone())?;
+        Some(state)
+    } else {
+        None
+    };
+    let mut next_live_sequence =
+        media_playlist.media_sequence + media_playlist.segments.len() as u64;
+
+    if args.list_media_info {
+        return list_media_info(
+            &args,
+            &client,
+            playlist_client,
+            &media_playlist,
+            &base_url,
+            segment_keys.first().cloned().flatten(),
+        )
+        .await;
+    }
+
+    let output_dir = if args.output_dir_per_download {
+        // 以流标题命名输出目录，便于浏览
+        title_output_dir(&args, &media_playlist, &m3u8_url)
+    } else {
+        url_output_dir(&args)
+    };
+    stats.title = stream_title(&media_playlist, &m3u8_url);
+    stats.segments_dir = Some(output_dir.clone());
+    if args.output_video_from_title {
+        args.output_video = title_output_video(&args, &media_playlist, variant_name, &m3u8_url);
+        info!(
+            "Output video named after stream title: {:?}",
+            args.output_video
+        );
+    }
+    if args.no_clobber && (args.output_dir_per_download || args.output_video_from_title) {
+        let output_file = output_dir.join(&args.output_video);
+        if output_exists(&output_file).await {
+            info!(
+                "Output file {:?} already exists, skipping download (--no-clobber).",
+                output_file
+            );
+            stats.output_file = Some(output_file);
+            return Ok(());
+        }
+    }
+    info!("Segments will be saved to: {:?}", output_dir);
+    if args.output_dir_abs {
+        // 输出文件相对于分段目录
+        info!(
+            "Output video will be written to {:?}",
+            output_dir.join(&args.output_video)
+        );
+    }
+    fs::create_dir_all(&output_dir).await?;
+
+    if lazy_segments.is_none() {
+        info!(
+            "Successfully parsed media playlist. Found {} segments.",
+            media_playlist.segments.len()
+        );
+    }
+
+    // 图像播放列表的第一个分段即为封面缩略图
+    if args.write_playlist_thumbnail {
+        if is_images_only(&media_playlist) {
+            match write_playlist_thumbnail(
+                &client,
+                &media_playlist,
+                &base_url,
+                &output_dir,
+                args.ffmpeg_path.as_deref(),
+            )
+            .await
+            {
+                Ok(path) => info!("Playlist thumbnail written to {:?}", path),
+                Err(e) => warn!("Failed to write playlist thumbnail: {}", e),
+            }
+        } else {
+            warn!("--write-playlist-thumbnail: the playlist is not #EXT-X-IMAGES-ONLY; skipping.");
+        }
+    }
+
+    // 按节目时间检查分段间隙，在过滤分段之前进行，以免把跳过的广告当作间隙
+    let mut segment_gaps = match args.segment_max_gap {
+        Some(max_gap) => find_segment_gaps(&media_playlist.segments, max_gap),
+        None => Vec::new(),
+    };
+    for gap in &segment_gaps {
+        warn!(
+            "{:.1}s gap before segment {} ({}); segments may be missing from the playlist.",
+            gap.gap_secs, gap.index, media_playlist.segments[gap.index].uri
+        );
+    }
+
+    // 保存流的描述信息
+    if args.write_description {
+        let name = Path::new(&args.output_video)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "output_video".to_string());
+        write_description(&session_data, &output_dir, &name).await?;
+        if let Some(title) = playlist_title(&media_playlist) {
+            write_title_description(&title, &output_dir, &name).await?;
+        }
+        info!("Stream description written to {:?}", output_dir);
+    }
+
+    // 记录每个分段的媒体序列号，跳过分段后仍能正确推导默认IV
+    // 序列号从 #EXT-X-MEDIA-SEQUENCE 开始，可通过 --playlist-start-number 覆盖
+    let start_number = args
+        .playlist_start_number
+        .unwrap_or(media_playlist.media_sequence);
+    let mut sequence_numbers: Vec<u64> = (0..media_playlist.segments.len() as u64)
+        .map(|i| start_number + i)
+        .collect();
+    let sequence_offset = start_number.wrapping_sub(media_playlist.media_sequence);
+
+    // 解析 EXT-X-DATERANGE 元数据
+    if args.enable_hls_date_range || args.skip_ads {
+        let date_ranges = parse_date_ranges(&media_playlist);
+        info!("Found {} date ranges in playlist.", date_ranges.len());
+
+        if args.enable_hls_date_range {
+            let date_range_path =
+                output_dir.join(Path::new(&args.output_video).with_extension("dateranges.json"));
+            write_date_ranges(&date_ranges, &date_range_path).await?;
+            info!("Date ranges written to {:?}", date_range_path);
+        }
+
+        if args.skip_ads {
+            let ad_indices = ad_segment_indices(&media_playlist, &date_ranges);
+            if !ad_indices.is_empty() {
+                info!("Skipping {} ad segments.", ad_indices.len());
+                for &i in ad_indices.iter().rev() {
+                    media_playlist.segments.remove(i);
+                    segment_keys.remove(i);
+                    sequence_numbers.remove(i);
+                    // 间隙按分段索引记录，移到跳过的分段之后的分段前
+                    for gap in segment_gaps.iter_mut().filter(|gap| gap.index > i) {
+                        gap.index -= 1;
+                    }
+                }
+                segment_gaps.retain(|gap| gap.index < media_playlist.segments.len());
+            }
+        }
+    }
+
+    // 将章节写为 WebVTT 文件
+    if args.output_chapters_vtt {
+        let chapters = build_chapters(&media_playlist, args.chapter_interval.map(|m| m * 60.0));
+        let vtt_path =
+            output_dir.join(Path::new(&args.output_video).with_extension("chapters.vtt"));
+        write_chapters_vtt(&chapters, &vtt_path).await?;
+        info!("{} chapters written to {:?}", chapters.len(), vtt_path);
+    }
+
+    // 按播放列表顺序确定每个分段的本地文件名，供下载、校验、合并和清理共用
+    let mut segment_files = local_segment_files(&args, &media_playlist.segments, is_mpd);
+
+    // 压缩保存的分段无法直接播放
+    #[cfg(feature = "compress-segments")]
+    if args.output_hls && args.write_compressed_segments {
+        anyhow::bail!("--output-hls cannot be used with --write-compressed-segments.");
+    }
+
+    // 替换为本地文件的分段先复制到位，下载时会跳过已存在的分段
+    if !args.splice_in_local_segment.is_empty() {
+        #[cfg(feature = "compress-segments")]
+        if args.write_compressed_segments {
+            anyhow::bail!(
+                "--splice-in-local-segment cannot be used with --write-compressed-segments."
+            );
+        }
+        let splices = args
+            .splice_in_local_segment
+            .chunks(2)
+            .map(|pair| {
+                let index = pair[0].parse().map_err(|_| {
+                    anyhow::anyhow!("Invalid --splice-in-local-segment index: {}", pair[0])
+                })?;
+                Ok((index, PathBuf::from(&pair[1])))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        splice_local_segments(&output_dir, &segment_files, &splices).await?;
+    }
+
+    // 复用之前下载中URL相同的分段，同样会被下载跳过
+    if let Some(source_dir) = &args.reuse_segments_from {
+        let targets: Vec<(Url, String)> = media_playlist
+            .segments
+            .iter()
+            .zip(&segment_files)
+            .filter_map(|(segment, name)| Some((base_url.join(&segment.uri).ok()?, name.clone())))
+            .collect();
+        let reused = reuse_segments(source_dir, &output_dir, &targets).await?;
+        info!(
+            "Reused {} of {} segments from {:?}",
+            reused,
+            targets.len(),
+            source_dir
+        );
+    }
+
+    let disk_flusher = (args.flush_interval > 0.0)
+        .then(|| DiskFlusher::spawn(Duration::from_secs_f64(args.flush_interval)));
+    let segment_hasher = args
+        .concurrent_segment_hashing
+        .then(|| Arc::new(SegmentHasher::new(args.segment_hash_algo)));
+
+    // 边下载边合并时，由后台任务按顺序拼接完成的分段并删除
+    let (completed_segments, streaming_merge) = if args.concurrent_cleanup {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let merge = StreamingMerge::spawn(
+            output_dir.clone(),
+            PathBuf::from(&args.output_video),
+            segment_files.clone(),
+            receiver,
+            disk_flusher.clone(),
+        )
+        .await?;
+        (Some(sender), Some(merge))
+    } else {
+        (None, None)
+    };
+
+    #[cfg(feature = "openssl-crypto")]
+    let decrypt_backend = if args.prefer_native_decrypt {
+        DecryptBackend::OpenSsl
+    } else {
+        DecryptBackend::default()
+    };
+    #[cfg(not(feature = "openssl-crypto"))]
+    let decrypt_backend = DecryptBackend::default();
+
+    let download_options = DownloadOptions {
+        max_concurrency: args.threads,
+        download_order: args.segment_download_order,
+        iv_offset: args.iv_offset,
+        low_latency: args.low_latency,
+        sequence_numbers: sequence_numbers.clone(),
+        retry_policy: if args.no_retry {
+            RetryPolicy {
+                max_attempts: 1,
+                retry_codes: args.segment_retry_codes.clone(),
+                ..Default::default()
+            }
+        } else {
+            RetryPolicy {
+                max_attempts: args.max_retries_per_segment.saturating_add(1),
+                extra_attempts_first: args.extra_retries_for_first,
+                extra_attempts_last: args.extra_retries_for_last,
+                retry_codes: args.segment_retry_codes.clone(),
+            }
+        },
+        segment_file_names: segment_files.clone(),
+        segment_filename_padding: args.segment_filename_padding,
+        segment_timeout_per_mb: Duration::from_secs_f64(args.segment_timeout_per_mb),
+        segment_url_decode: args.segment_url_decode,
+        segment_url_encode: args.segment_url_encode,
+        deferred_decryption: args.parallel_key_decryption,
+        decrypt_backend,
+        strict_content_type: args.strict_content_type,
+        hide_progress: args.quiet || args.silent,
+        completed_segments,
+        segment_presigner: args
+            .segment_presign_hook
+            .clone()
+            .map(|cmd| Arc::new(SegmentPresigner::new(cmd))),
+        raw_key_output: args.write_raw_aes_key.clone(),
+        key_info_log: if args.show_raw_key {
+            Some(KeyInfoLog::Raw)
+        } else if args.show_key_info {
+            Some(KeyInfoLog::Fingerprint)
+        } else {
+            None
+        },
+        disk_flusher: disk_flusher.clone(),
+        segment_hasher: segment_hasher.clone(),
+        #[cfg(feature = "compress-segments")]
+        compression_level: args.output_compression_level,
+        adaptive_max_threads: args
+            .adaptive_threads
+            .then(|| args.max_threads.unwrap_or(args.threads * 2)),
+        rate_limiter: args
+            .limit_rate
+            .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, args.limit_rate_burst)))),
+    };
+    let mut download_results = match lazy_segments {
+        // 边解析边下载，之后的步骤使用下载期间收集的完整分段列表
+        Some(segments) => {
+            let (streamed, results) = download_segment_stream(
+                client.clone(),
+                playlist_client.clone(),
+                segments,
+                base_url.clone(),
+                output_dir.clone(),
+                start_number,
+                &download_options,
+            )
+            .await;
+            info!("Parsed {} segments while downloading.", streamed.len());
+            media_playlist.segments = streamed;
+            segment_keys = playlist::segment_keys(&media_playlist.segments);
+            sequence_numbers = (0..media_playlist.segments.len() as u64)
+                .map(|i| start_number + i)
+                .collect();
+            segment_files = local_segment_files(&args, &media_playlist.segments, is_mpd);
+            results
+        }
+        None => {
+            download_segments(
+                client.clone(),
+                playlist_client.clone(),
+                &media_playlist.segments,
+                base_url.clone(),
+                output_dir.clone(),
+                &segment_keys,
+                &download_options,
+            )
+            .await
+        }
+    };
+
+    // 直播录制：按间隔重新获取媒体播放列表，下载新出现的分段，直到直播结束
+    if let Some(mut state) = live_state {
+        let mut end_list = media_playlist.end_list;
+        let mut target_duration = media_playlist.target_duration;
+        // 预取时由后台任务在下载期间获取下一次更新，状态随之移入任务
+        let mut prefetched = match args.concurrent_playlist_fetch {
+            Some(prefetch) if !end_list => Some(std::mem::take(&mut state).spawn_reloader(
+                playlist_client.clone(),
+                live_url.clone(),
+                next_live_sequence,
+                playlist_options.clone(),
+                target_duration,
+                prefetch as usize,
+            )),
+            _ => None,
+        };
+        let mut recorded_secs: f64 = media_playlist
+            .segments
+            .iter()
+            .map(|s| s.duration as f64)
+            .sum();
+        while !end_list {
+            if args
+                .live_max_duration
+                .is_some_and(|max| recorded_secs >= max)
+            {
+                info!(
+                    "Recorded {:.1}s of the live stream, stopping (--live-max-duration).",
+                    recorded_secs
+                );
+                break;
+            }
+            let update = match &mut prefetched {
+                Some((updates, _)) => match updates.recv().await {
+                    Some(update) => update,
+                    None => break,
+                },
+                None => {
+                    tokio::time::sleep(live_poll_delay(target_duration, &playlist_options)).await;
+                    state
+                        .reload(
+                            playlist_client.clone(),
+                            &live_url,
+                            next_live_sequence,
+                            &playlist_options,
+                        )
+                        .await
+                }
+            };
+            let update = match update {
+                Ok(update) => update,
+                Err(e) => {
+                    warn!(
+                        "Failed to reload the live playlist, stopping the recording: {}",
+                        e
+                    );
+                    break;
+                }
+            };
+            end_list = update.end_list;
+            target_duration = update.target_duration;
+            if update.segments.is_empty() {
+                continue;
+            }
+
+            let first = media_playlist.segments.len();
+            let count = update.segments.len();
+            next_live_sequence = update.first_sequence + count as u64;
+            recorded_secs += update
+                .segments
+                .iter()
+                .map(|s| s.duration as f64)
+                .sum::<f64>();
+            media_playlist.segments.extend(update.segments);
+            segment_keys.extend(update.segment_keys);
+            sequence_numbers.extend(
+                (update.first_sequence..next_live_sequence)
+                    .map(|n| n.wrapping_add(sequence_offset)),
+            );
+            segment_files = local_segment_files(&args, &media_playlist.segments, is_mpd);
+
+            // 新分段与已录制的最后一个分段之间同样检查间隙
+            if let Some(max_gap) = args.segment_max_gap {
+                let start = first.saturating_sub(1);
+                for gap in find_segment_gaps(&media_playlist.segments[start..], max_gap) {
+                    let gap = SegmentGap {
+                        index: start + gap.index,
+                        ..gap
+                    };
+                    warn!(
+                        "{:.1}s gap before segment {} ({}); segments may be missing from the playlist.",
+                        gap.gap_secs, gap.index, media_playlist.segments[gap.index].uri
+                    );
+                    segment_gaps.push(gap);
+                }
+            }
+
+            info!("Live playlist has {} new segments.", count);
+            download_results.extend(
+                download_segments(
+                    client.clone(),
+                    playlist_client.clone(),
+                    &media_playlist.segments[first..],
+                    base_url.clone(),
+                    output_dir.clone(),
+                    &segment_keys[first..],
+                    &DownloadOptions {
+                        sequence_numbers: sequence_numbers[first..].to_vec(),
+                        segment_file_names: segment_files[first..].to_vec(),
+                        ..download_options.clone()
+                    },
+                )
+                .await,
+            );
+        }
+        media_playlist.end_list = end_list;
+        if let Some((_, reloader)) = prefetched {
+            reloader.abort();
+        }
+    }
+    // 释放下载选项中已完成分段的发送端，边下载边合并的任务才能结束
+    drop(download_options);
+
+    // 同步最后一个周期内写完的分段
+    if let Some(flusher) = disk_flusher {
+        tokio::task::spawn_blocking(move || flusher.flush()).await?;
+    }
 
     let successful_downloads = download_results.iter().filter(|&r| r.is_ok()).count();
     let failed_downloads = download_results.len() - successful_downloads;
+    stats.segment_count = media_playlist.segments.len();
+    stats.duration_secs = media_playlist
+        .segments
+        .iter()
+        .map(|s| s.duration as f64)
+        .sum();
+    stats.failed_segments = failed_downloads;
+    stats.skipped_segments = download_results
+        .iter()
+        .filter(|r| r.as_ref().is_ok_and(|t| t.skipped))
+        .count();
+    stats.total_bytes = download_results
+        .iter()
+        .filter_map(|r| r.as_ref().ok())
+        .map(|t| t.bytes_downloaded)
+        .sum();
 
     if failed_downloads > 0 {
         error!(
@@ -56,32 +1238,493 @@ pub async fn run(args: Args) -> Result<()> {
             failed_downloads,
             media_playlist.segments.len()
         );
-        for result in download_results {
+        for result in &download_results {
             if let Err(e) = result {
                 error!(" - {}", e);
             }
         }
-        anyhow::bail!("Download failed for some segments. Aborting.");
+        if !args.ignore_errors && !args.skip_segment_on_error {
+            anyhow::bail!("Download failed for some segments. Aborting.");
+        }
+    } else {
+        info!(
+            "All {} segments downloaded successfully.",
+            successful_downloads
+        );
     }
 
-    info!(
-        "All {} segments downloaded successfully.",
-        successful_downloads
-    );
+    // 下载字幕轨道并转换为 SRT，失败不影响视频的合并
+    if args.write_subtitles {
+        match write_subtitles(
+            &args,
+            &client,
+            playlist_client,
+            &subtitles,
+            &playlist_options,
+            &output_dir,
+        )
+        .await
+        {
+            Ok(path) => info!("Subtitles written to {:?}", path),
+            Err(e) => warn!("Failed to write subtitles: {}", e),
+        }
+    }
+
+    // 忽略错误时，缺失的分段要么以单独命名的占位文件代替，要么从合并列表中去掉
+    let mut missing_files = HashSet::new();
+    let mut placeholder_files = HashMap::new();
+    if failed_downloads > 0 {
+        let mut downloaded = Vec::with_capacity(segment_files.len());
+        for name in &segment_files {
+            downloaded.push(fs::try_exists(output_dir.join(name)).await.unwrap_or(false));
+        }
+        for (i, (name, segment)) in segment_files
+            .iter()
+            .zip(&media_playlist.segments)
+            .enumerate()
+        {
+            let path = output_dir.join(name);
+            if downloaded[i] {
+                continue;
+            }
+            if args.skip_segment_on_error {
+                let placeholder_name = placeholder_file_name(name);
+                let placeholder_path = output_dir.join(&placeholder_name);
+                // 以最近的已下载分段为参考，使占位分段的分辨率与前后一致
+                let reference = (0..i)
+                    .rev()
+                    .chain(i + 1..segment_files.len())
+                    .find(|&j| downloaded[j])
+                    .map(|j| output_dir.join(&segment_files[j]));
+                write_placeholder(
+                    &args,
+                    reference.as_deref(),
+                    &placeholder_path,
+                    segment.duration,
+                )
+                .await?;
+                warn!(
+                    "Wrote placeholder {:?} for missing segment {:?}",
+                    placeholder_path, path
+                );
+                placeholder_files.insert(name.clone(), placeholder_name);
+            } else {
+                warn!("Skipping missing segment {:?}", path);
+                missing_files.insert(name.clone());
+            }
+        }
+    }
+    // 合并时使用的文件名，缺失的分段换成其占位文件
+    let merge_name = |name: &String| placeholder_files.get(name).unwrap_or(name).clone();
+    let mut merge_files: Vec<String> = segment_files
+        .iter()
+        .filter(|name| !missing_files.contains(*name))
+        .map(merge_name)
+        .collect();
+
+    // 在每个间隙之后的分段前插入 FFmpeg 生成的黑屏静音分段
+    let mut gap_files = Vec::new();
+    if args.ignore_gaps {
+        for gap in &segment_gaps {
+            // 间隙之前保留的分段数，加上已插入的填充分段即为插入位置
+            let position = segment_files[..gap.index]
+                .iter()
+                .filter(|name| !missing_files.contains(*name))
+                .count()
+                + gap_files.len();
+            let name = format!("gap{}.ts", gap.index);
+            let reference = merge_files.get(position).map(|f| output_dir.join(f));
+            generate_gap_filler(
+                args.ffmpeg_path.as_deref(),
+                reference.as_deref(),
+                &output_dir.join(&name),
+                gap.gap_secs,
+            )
+            .await?;
+            warn!("Wrote filler {:?} for a {:.1}s gap", name, gap.gap_secs);
+            merge_files.insert(position, name.clone());
+            gap_files.push(name);
+        }
+    }
+
+    // 导出每个分段的计时信息
+    if args.output_timing_info {
+        let mut timings: Vec<_> = download_results
+            .iter()
+            .filter_map(|r| r.as_ref().ok().cloned())
+            .collect();
+        timings.sort_by_key(|t| t.index);
+        let timing_path = output_dir.join("segments.csv");
+        write_timing_csv(&timings, &timing_path).await?;
+        info!("Segment timing info written to {:?}", timing_path);
+    }
+
+    // 导出分段清单，未显式指定IV的分段记录按序号推导的IV
+    if args.write_segments_manifest {
+        let segments: Vec<_> = media_playlist
+            .segments
+            .iter()
+            .zip(&segment_files)
+            .enumerate()
+            .map(|(i, (segment, name))| {
+                let key = segment_keys[i].clone().map(|key| KeyInfo {
+                    iv: key.iv.or_else(|| {
+                        default_iv(sequence_numbers[i], args.iv_offset)
+                            .ok()
+                            .map(|iv| format!("0x{}", hex::encode(iv)))
+                    }),
+                    ..key
+                });
+                (i, segment.clone(), output_dir.join(name), key)
+            })
+            .collect();
+        let manifest_path = output_dir.join(SEGMENTS_MANIFEST_NAME);
+        write_segments_manifest(&segments, &manifest_path).await?;
+        info!("Segments manifest written to {:?}", manifest_path);
+
+        let urls: Vec<(Url, PathBuf)> = media_playlist
+            .segments
+            .iter()
+            .zip(&segment_files)
+            .filter_map(|(segment, name)| {
+                Some((base_url.join(&segment.uri).ok()?, output_dir.join(name)))
+            })
+            .collect();
+        write_url_hash_index(&urls, &output_dir.join(URL_HASH_INDEX_NAME)).await?;
+    }
+
+    // 将分段重新打包为本地 HLS 播放列表，缺失的分段处标记不连续
+    if args.output_hls {
+        // 初始化分段下载到本地，EXT-X-MAP 改为指向本地文件
+        let mut init_files: Vec<(&Map, Map)> = Vec::new();
+        for map in media_playlist
+            .segments
+            .iter()
+            .filter_map(|s| s.map.as_ref())
+        {
+            if init_files
+                .iter()
+                .any(|(m, _)| m.uri == map.uri && m.byte_range == map.byte_range)
+            {
+                continue;
+            }
+            let name = init_segment_file_name(&map.uri, init_files.len());
+            download_init_segment(&client, &base_url, map, &output_dir.join(&name)).await?;
+            init_files.push((
+                map,
+                Map {
+                    uri: name,
+                    ..Default::default()
+                },
+            ));
+        }
+
+        let mut local_playlist = MediaPlaylist {
+            segments: Vec::new(),
+            ..media_playlist.clone()
+        };
+        let mut after_gap = false;
+        // 当前生效的初始化分段和最近一次写出的初始化分段，只在变化时写出 EXT-X-MAP
+        let mut current_map = None;
+        let mut written_map = None;
+        for (segment, name) in media_playlist.segments.iter().zip(&segment_files) {
+            if let Some(map) = &segment.map {
+                current_map = init_files
+                    .iter()
+                    .find(|(m, _)| m.uri == map.uri && m.byte_range == map.byte_range)
+                    .map(|(_, local)| local.clone());
+            }
+            if missing_files.contains(name) {
+                after_gap = true;
+                continue;
+            }
+            let map = if current_map != written_map {
+                current_map.clone()
+            } else {
+                None
+            };
+            written_map = current_map.clone();
+            local_playlist.segments.push(MediaSegment {
+                uri: merge_name(name),
+                discontinuity: segment.discontinuity || after_gap,
+                map,
+                ..segment.clone()
+            });
+            after_gap = false;
+        }
+        write_local_hls_playlist(&local_playlist, &output_dir).await?;
+    }
+
+    // 播放列表声明每个分段都可独立解码时，抽查首尾分段
+    if args.validate_output {
+        if media_playlist.independent_segments {
+            let segment_paths: Vec<_> = merge_files
+                .iter()
+                .map(|name| output_dir.join(name))
+                .collect();
+            #[cfg(feature = "compress-segments")]
+            let segment_paths: Vec<_> = segment_paths
+                .into_iter()
+                .filter(|path| !compress::is_compressed(path))
+                .collect();
+            let ffprobe = ffprobe_path(args.ffmpeg_path.as_deref());
+            validate_independent_segments(&ffprobe, &segment_paths).await;
+        } else {
+            debug!("Playlist has no #EXT-X-INDEPENDENT-SEGMENTS tag; skipping segment validation.");
+        }
+    }
+
+    // 校验分段完整性
+    if let Some(manifest_path) = &args.checksum_manifest {
+        info!("Verifying segment checksums against {:?}", manifest_path);
+        verify_segments(
+            &output_dir,
+            &merge_files,
+            manifest_path,
+            args.segment_hash_algo,
+        )
+        .await?;
+    }
+
+    if args.generate_checksums {
+        let manifest_path = output_dir.join(checksum_manifest_name(args.segment_hash_algo));
+        // 下载时已计算的校验和直接使用，其余文件在这里补算
+        let checksums = match &segment_hasher {
+            Some(hasher) => {
+                hasher
+                    .checksums(&output_dir, &segment_files, &merge_files)
+                    .await?
+            }
+            None => compute_checksums(&output_dir, &merge_files, args.segment_hash_algo).await?,
+        };
+        write_checksum_manifest(&checksums, &manifest_path).await?;
+        info!("Checksum manifest written to {:?}", manifest_path);
+    }
+
+    // 输出文件名没有扩展名时，根据第一个分段的编码推断容器格式
+    if args.output_format_auto && Path::new(&args.output_video).extension().is_none() {
+        if let Some(first) = merge_files.first() {
+            let path = output_dir.join(first);
+            #[cfg(feature = "compress-segments")]
+            let data = if compress::is_compressed(&path) {
+                compress::read_decompressed(&path).await
+            } else {
+                fs::read(&path).await.map_err(Into::into)
+            };
+            #[cfg(not(feature = "compress-segments"))]
+            let data = fs::read(&path).await;
+            match data.ok().as_deref().and_then(detect_container) {
+                Some(container) => {
+                    args.output_video = Path::new(&args.output_video)
+                        .with_extension(container.extension())
+                        .to_string_lossy()
+                        .to_string();
+                    info!(
+                        "Detected output format from first segment: {:?}",
+                        args.output_video
+                    );
+                }
+                None => warn!("Could not detect the container format of {:?}", path),
+            }
+        }
+    }
+
+    // 从第一个分段截取缩略图，与输出视频放在一起
+    if args.write_thumbnail_from_segment {
+        match merge_files.first().map(|name| output_dir.join(name)) {
+            #[cfg(feature = "compress-segments")]
+            Some(segment) if compress::is_compressed(&segment) => {
+                warn!("Thumbnails cannot be extracted from compressed segments; skipping.")
+            }
+            Some(segment) => {
+                let thumbnail = output_dir.join(&args.output_video).with_extension("jpg");
+                match extract_thumbnail(
+                    args.ffmpeg_path.as_deref(),
+                    &segment,
+                    &thumbnail,
+                    args.thumbnail_time_offset,
+                    args.thumbnail_size,
+                )
+                .await
+                {
+                    Ok(()) => info!("Thumbnail written to {:?}", thumbnail),
+                    Err(e) => warn!("Failed to extract thumbnail: {}", e),
+                }
+            }
+            None => warn!("No downloaded segments to extract a thumbnail from."),
+        }
+    }
 
     // 合并文件
-    if !args.no_merge {
+    let output_file = if !args.no_merge {
         let output_video_path = &args.output_video;
         info!("Merging segments into: {:?}", output_video_path);
+        // 仅拆分而未拼接时没有单一的输出文件
+        let single_output =
+            (!args.split_chapters || args.concat_chapters_after) && args.split_size.is_none();
+        if !single_output && (args.output_chapters || !args.output_also.is_empty()) {
+            warn!("Chapters and --output-also apply only to a single full output; ignoring them for split output files.");
+        }
 
-        match merge_segments(
-            &output_dir,
-            output_video_path,
-            args.ffmpeg_path.as_deref(),
-            media_playlist.segments.len(),
-        )
-        .await
-        {
+        // 生成章节元数据，由FFmpeg合并时嵌入
+        if args.output_chapters && single_output {
+            if args.merger == MergerKind::Ffmpeg {
+                let chapters =
+                    build_chapters(&media_playlist, args.chapter_interval.map(|m| m * 60.0));
+                write_ffmetadata_chapters(&chapters, &output_dir.join(CHAPTERS_FILE_NAME)).await?;
+                info!("Generated {} chapters.", chapters.len());
+            } else {
+                warn!(
+                    "Chapters are only embedded by the ffmpeg merger; ignoring --output-chapters."
+                );
+            }
+        }
+        if !args.output_also.is_empty() && args.merger != MergerKind::Ffmpeg {
+            warn!("Additional output formats require the ffmpeg merger; ignoring --output-also.");
+        }
+
+        // 将下载来源等信息写入输出文件的容器元数据
+        let video_metadata = if args.output_video_metadata {
+            if args.merger != MergerKind::Ffmpeg {
+                warn!("Container metadata is only written by the ffmpeg merger; ignoring --output-video-metadata.");
+            }
+            Some(VideoMetadata {
+                title: stats.title.clone().unwrap_or_else(|| {
+                    Path::new(output_video_path)
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                }),
+                comment: format!("Downloaded from {}", m3u8_url),
+                date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+                encoder: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            })
+        } else {
+            None
+        };
+
+        // 合并前检查输出容器能否存放分段中的编码
+        let mut video_tag = None;
+        if args.output_container_compatibility_check && args.merger == MergerKind::Ffmpeg {
+            match (
+                output_container(args.container, Path::new(output_video_path)),
+                merge_files.first(),
+            ) {
+                (Some(container), Some(first)) => {
+                    let ffprobe = ffprobe_path(args.ffmpeg_path.as_deref());
+                    match probe_streams(&ffprobe, &output_dir.join(first)).await {
+                        Ok(streams) => {
+                            video_tag = check_container_compatibility(
+                                container,
+                                &streams,
+                                args.output_video_codec,
+                            )
+                            .map_err(|e| {
+                                anyhow::anyhow!(
+                                    "{}. Segments are still available in {:?}",
+                                    e,
+                                    output_dir
+                                )
+                            })?;
+                            info!(
+                                "Codecs of {} are compatible with {}.",
+                                first,
+                                container.extension()
+                            );
+                        }
+                        Err(e) => warn!(
+                            "Could not probe {}, skipping the container compatibility check: {}",
+                            first, e
+                        ),
+                    }
+                }
+                _ => debug!("Unknown output container or no segments; skipping the container compatibility check."),
+            }
+        }
+
+        // 分组和部分文件不嵌入按完整时间轴生成的章节，也不生成额外格式
+        let part_merger = create_merger(&args, video_metadata.clone(), video_tag, false);
+        let merger = create_merger(&args, video_metadata, video_tag, true);
+        let merge_result = if args.split_chapters {
+            // 每个不连续分组合并为单独的文件
+            let groups: Vec<Vec<String>> = discontinuity_groups(&media_playlist.segments)
+                .into_iter()
+                .map(|range| {
+                    segment_files[range]
+                        .iter()
+                        .filter(|name| !missing_files.contains(*name))
+                        .map(merge_name)
+                        .collect::<Vec<_>>()
+                })
+                .filter(|group| !group.is_empty())
+                .collect();
+            info!(
+                "Merging {} discontinuity groups, up to {} at a time.",
+                groups.len(),
+                args.parallel_merge
+            );
+            match merge_groups(
+                part_merger.as_ref(),
+                &output_dir,
+                Path::new(output_video_path),
+                &groups,
+                args.parallel_merge,
+            )
+            .await
+            {
+                Ok(parts) if args.concat_chapters_after => {
+                    // 再将各分组文件拼接为完整的输出
+                    let part_names: Vec<String> = parts
+                        .iter()
+                        .map(|p| p.to_string_lossy().to_string())
+                        .collect();
+                    let result = merger
+                        .merge(&output_dir, Path::new(output_video_path), &part_names)
+                        .await;
+                    if result.is_ok() && !args.keep_segments {
+                        if let Err(e) = cleanup_segments(&output_dir, &part_names).await {
+                            warn!("Failed to remove group files: {}", e);
+                        }
+                    }
+                    result
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            }
+        } else if let Some(max_bytes) = split_bytes {
+            // 按大小拆分为多个文件
+            match split_by_size(&output_dir, &merge_files, max_bytes).await {
+                Ok(parts) => {
+                    info!(
+                        "Splitting output into {} parts of at most {} MiB.",
+                        parts.len(),
+                        max_bytes / (1024 * 1024)
+                    );
+                    merge_parts(
+                        part_merger.as_ref(),
+                        &output_dir,
+                        Path::new(output_video_path),
+                        &parts,
+                        max_bytes,
+                    )
+                    .await
+                    .map(|_| ())
+                }
+                Err(e) => Err(e),
+            }
+        } else if let Some(merge) = streaming_merge {
+            merge.finish().await.map(|count| {
+                info!("Appended {} segments while downloading.", count);
+            })
+        } else {
+            merger
+                .merge(&output_dir, Path::new(output_video_path), &merge_files)
+                .await
+        };
+
+        match merge_result {
+            Ok(_) if args.split_size.is_some() => info!("Successfully merged output parts."),
+            Ok(_) if !single_output => info!("Successfully merged segment groups."),
             Ok(_) => info!("Successfully merged segments into {:?}", output_video_path),
             Err(e) => {
                 error!("Failed to merge segments: {}", e);
@@ -92,17 +1735,89 @@ pub async fn run(args: Args) -> Result<()> {
             }
         }
 
-        // 清理分段文件
-        if !args.keep_segments {
+        // 校验合并结果中的音频流
+        if single_output && (args.verify_audio || args.verify_audio_strict) {
+            let expected: f64 = media_playlist
+                .segments
+                .iter()
+                .map(|s| s.duration as f64)
+                .sum();
+            let ffprobe = ffprobe_path(args.ffmpeg_path.as_deref());
+            let merged = output_dir.join(output_video_path);
+            if let Err(e) =
+                verify_audio(&ffprobe, &merged, expected, args.verify_audio_strict).await
+            {
+                if args.verify_audio_strict {
+                    anyhow::bail!(
+                        "Audio verification failed: {}. Segments are still available in {:?}",
+                        e,
+                        output_dir
+                    );
+                }
+                warn!("Audio verification failed: {}", e);
+            }
+        }
+
+        // 清理分段文件，本地 HLS 播放列表仍引用这些分段
+        if !args.keep_segments && !args.output_hls {
             info!("Cleaning up segment files...");
-            match cleanup_segments(&output_dir).await {
+            let cleanup = async {
+                cleanup_segments(&output_dir, &segment_files).await?;
+                cleanup_segments(&output_dir, &gap_files).await?;
+                let placeholders: Vec<String> = placeholder_files.values().cloned().collect();
+                cleanup_segments(&output_dir, &placeholders).await
+            };
+            match cleanup.await {
                 Ok(_) => info!("Segment files cleaned up successfully."),
                 Err(e) => error!("Failed to clean up some segment files: {}", e),
             }
         }
+
+        // ffmpeg在分段目录中运行，输出路径相对于该目录
+        single_output.then(|| output_dir.join(output_video_path))
     } else {
         info!("Skipping merge step as requested.");
+        None
+    };
+
+    // 导出本次运行中服务器设置的 Cookie
+    if let (Some(path), Some(store)) = (&args.write_cookies, &cookie_store) {
+        let store = store
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Cookie store lock poisoned"))?;
+        write_netscape_cookies(&store, path)?;
+        info!("Cookies written to {:?}", path);
     }
 
+    // 执行下载后钩子
+    if let Some(hook) = &args.post_hook {
+        let duration: f32 = media_playlist.segments.iter().map(|s| s.duration).sum();
+        let envs = [
+            (
+                "M3U8_OUTPUT_FILE",
+                output_file
+                    .as_ref()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "M3U8_SEGMENTS_DIR",
+                output_dir.to_string_lossy().to_string(),
+            ),
+            ("M3U8_SOURCE_URL", args.url.clone()),
+            ("M3U8_DURATION", duration.to_string()),
+            (
+                "M3U8_SEGMENT_COUNT",
+                media_playlist.segments.len().to_string(),
+            ),
+        ];
+        info!("Running post-hook...");
+        if let Err(e) = run_post_hook(hook, &envs).await {
+            warn!("Post-hook failed: {}", e);
+        }
+    }
+
+    stats.output_file = output_file;
+
     Ok(())
 }
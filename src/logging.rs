@@ -0,0 +1,135 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate};
+use log::LevelFilter;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// 日志文件的轮转方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    /// 每天轮转一次，旧文件以日期作为后缀
+    Daily,
+    /// 文件超过指定字节数时轮转，旧文件以时间戳作为后缀
+    Size(u64),
+}
+
+/// 日志文件选项
+#[derive(Debug, Clone)]
+pub struct LogFileOptions {
+    pub path: PathBuf,
+    pub rotation: Option<LogRotation>,
+}
+
+/// 支持按日期或大小轮转的日志文件
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Option<LogRotation>,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, rotation: Option<LogRotation>) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // 按已有文件的修改日期判断，避免跨天重启后把前一天的日志写进新的一天
+        let opened_on = metadata
+            .modified()
+            .map(|t| DateTime::<Local>::from(t).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file,
+            size: metadata.len(),
+            opened_on,
+        })
+    }
+
+    /// 将当前文件重命名为带后缀的备份并重新打开
+    fn rotate(&mut self) -> io::Result<()> {
+        let suffix = match self.rotation {
+            Some(LogRotation::Daily) => self.opened_on.format("%Y-%m-%d").to_string(),
+            _ => Local::now().format("%Y%m%d-%H%M%S%.3f").to_string(),
+        };
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(format!(".{}", suffix));
+        self.file.flush()?;
+        fs::rename(&self.path, backup)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let should_rotate = match self.rotation {
+            Some(LogRotation::Daily) => Local::now().date_naive() != self.opened_on,
+            Some(LogRotation::Size(limit)) => self.size > 0 && self.size + buf.len() as u64 > limit,
+            None => false,
+        };
+        if should_rotate {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// 初始化日志系统
+///
+/// 标准错误输出遵循 `RUST_LOG`（默认 `info`），指定 `console_level` 时改用该级别；
+/// 指定日志文件时，文件中至少记录 `debug` 级别的日志。
+pub fn init(log_file: Option<&LogFileOptions>, console_level: Option<LevelFilter>) -> Result<()> {
+    let stderr_logger = match console_level {
+        Some(level) => env_logger::Builder::new().filter_level(level).build(),
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+            .build(),
+    };
+
+    let Some(options) = log_file else {
+        let max_level = stderr_logger.filter();
+        log::set_boxed_logger(Box::new(stderr_logger))?;
+        log::set_max_level(max_level);
+        return Ok(());
+    };
+
+    let file = RotatingFile::open(&options.path, options.rotation)
+        .map_err(|e| anyhow!("Failed to open log file {:?}: {}", options.path, e))?;
+    let file_dispatch = fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{} [{}] {}: {}",
+                Local::now().to_rfc3339(),
+                record.level(),
+                record.target(),
+                message
+            ))
+        })
+        .level(LevelFilter::Debug.max(stderr_logger.filter()))
+        .chain(Box::new(file) as Box<dyn Write + Send>);
+
+    fern::Dispatch::new()
+        .level(LevelFilter::Debug.max(stderr_logger.filter()))
+        .chain(Box::new(stderr_logger) as Box<dyn log::Log>)
+        .chain(file_dispatch)
+        .apply()?;
+    Ok(())
+}
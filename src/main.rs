@@ -1,18 +1,20 @@
+use clap::CommandFactory;
 use log::{error, info};
+use m3u8_downloader_rs::logging::LogFileOptions;
 use std::env;
+use std::io;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // 初始化日志系统
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-
     // 检查是否启动GUI模式（无参数）
     let args: Vec<String> = env::args().collect();
 
     if args.len() <= 1 {
+        m3u8_downloader_rs::logging::init(None, None)?;
+
         // 无参数，直接启动GUI模式
         info!("Starting M3U8 downloader in GUI mode...");
-        if let Err(e) = m3u8_downloader_rs::gui::run_gui() {
+        if let Err(e) = m3u8_downloader_rs::gui::run_gui(Default::default()) {
             error!("GUI error: {}", e);
             std::process::exit(1);
         }
@@ -20,23 +22,53 @@ async fn main() -> anyhow::Result<()> {
         // 解析命令行参数
         let cli_args = m3u8_downloader_rs::cli::parse_args();
 
+        // 输出 shell 补全脚本后直接退出
+        if let Some(shell) = cli_args.shell_completion {
+            let mut command = m3u8_downloader_rs::cli::Args::command();
+            clap_complete::generate(shell, &mut command, "m3u8_downloader_rs", &mut io::stdout());
+            return Ok(());
+        }
+
+        // 初始化日志系统，指定 --log-file 时同时写入日志文件
+        let log_file = cli_args.log_file.clone().map(|path| LogFileOptions {
+            path,
+            rotation: cli_args.log_file_rotate,
+        });
+        m3u8_downloader_rs::logging::init(log_file.as_ref(), cli_args.console_log_level())?;
+
+        // 静默模式下连 panic 信息也不输出，只通过退出码反映结果
+        if cli_args.silent {
+            std::panic::set_hook(Box::new(|_| {}));
+        }
+
         // 检查是否指定了--gui参数
         if cli_args.gui {
             // GUI模式
             info!("Starting M3U8 downloader in GUI mode...");
-            if let Err(e) = m3u8_downloader_rs::gui::run_gui() {
+            let gui_options = m3u8_downloader_rs::gui::GuiOptions {
+                theme: cli_args.gui_theme,
+                window_size: cli_args.gui_window_size,
+                maximized: cli_args.gui_maximized,
+                always_on_top: cli_args.gui_always_on_top,
+            };
+            if let Err(e) = m3u8_downloader_rs::gui::run_gui(gui_options) {
                 error!("GUI error: {}", e);
                 std::process::exit(1);
             }
         } else {
             // 命令行模式
             info!("Starting M3U8 downloader in CLI mode...");
-            info!("URL: {}", cli_args.url);
+            if cli_args.input_file.is_none() {
+                info!("URL: {}", cli_args.url);
+            }
 
             // 运行下载器
-            if let Err(e) = m3u8_downloader_rs::run(cli_args).await {
-                error!("An error occurred: {}", e);
-                std::process::exit(1);
+            match m3u8_downloader_rs::run(cli_args).await {
+                Ok(stats) => info!("Done: {}", stats),
+                Err(e) => {
+                    error!("An error occurred: {}", e);
+                    std::process::exit(1);
+                }
             }
         }
     }
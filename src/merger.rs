@@ -1,79 +1,1075 @@
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{info, warn};
+use m3u8_rs::{MediaPlaylist, MediaPlaylistType, MediaSegment};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+
+use crate::cli::{Args, Container, HardwareAccel, MergerKind, TrackType, VideoCodec};
+use crate::metadata::{VideoMetadata, CHAPTERS_FILE_NAME};
+use crate::probe::{ffprobe_path, probe_streams, segment_duration_secs, ProbeStream};
+use crate::subtitle::{merge_vtt_segments, vtt_to_srt};
+use crate::util::DiskFlusher;
+
+/// 分段合并器，输出路径相对于分段目录解析，分段按 `segments` 中的文件名顺序合并
+#[async_trait]
+pub trait Merger: Send + Sync {
+    async fn merge(&self, segments_dir: &Path, output: &Path, segments: &[String]) -> Result<()>;
+}
+
+/// FFmpeg 合并选项
+#[derive(Debug, Clone, Default)]
+pub struct FfmpegOptions {
+    pub ffmpeg_path: Option<PathBuf>,
+    /// 强制输出的容器格式，未设置时由 FFmpeg 根据扩展名推断
+    pub container: Option<Container>,
+    /// ffmetadata 格式的章节文件，相对于分段目录解析
+    pub chapters_file: Option<PathBuf>,
+    /// 主输出之外额外生成的格式，输出路径仅替换扩展名
+    pub output_also: Vec<Container>,
+    /// 写入输出文件的容器元数据
+    pub metadata: Option<VideoMetadata>,
+    /// 传给 FFmpeg 的 `-loglevel`，未设置时使用 FFmpeg 的默认输出
+    pub log_level: Option<&'static str>,
+    /// 视频流的输出编码，`Copy` 时不转码
+    pub video_codec: VideoCodec,
+    /// 转码时使用的硬件加速后端，需与 `video_codec` 有对应的编码器
+    pub hardware_accel: Option<HardwareAccel>,
+    /// 转码时缩放到的分辨率 [宽, 高]，`-2` 表示按宽高比计算
+    pub resolution: Option<[i32; 2]>,
+    /// 只保留选中的轨道，未设置时保留全部
+    pub track_selection: Option<TrackSelection>,
+    /// 输出为 MP4 时附加的视频标签（`-tag:v`）
+    pub video_tag: Option<&'static str>,
+}
+
+/// 合并时保留的轨道
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackSelection {
+    /// 只保留这些 PID 的 MPEG-TS 流
+    Pids(Vec<u16>),
+    /// 只保留该类型的第 N 个轨道，其他类型的轨道全部保留
+    Nth(TrackType, usize),
+}
+
+impl TrackSelection {
+    /// 对应的 FFmpeg `-map` 流说明符
+    fn map_specifiers(&self) -> Vec<String> {
+        match self {
+            // MPEG-TS 中的流 ID 即为 PID
+            Self::Pids(pids) => pids.iter().map(|pid| format!("0:i:{}", pid)).collect(),
+            Self::Nth(selected, index) => [TrackType::Video, TrackType::Audio, TrackType::Subtitle]
+                .into_iter()
+                .map(|kind| {
+                    let specifier = match kind {
+                        TrackType::Video => "v",
+                        TrackType::Audio => "a",
+                        TrackType::Subtitle => "s",
+                    };
+                    if kind == *selected {
+                        format!("0:{}:{}", specifier, index)
+                    } else {
+                        // `?` 使该类型的轨道不存在时不报错
+                        format!("0:{}?", specifier)
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// 使用 FFmpeg 合并分段
+pub struct FfmpegMerger {
+    pub options: FfmpegOptions,
+}
+
+#[async_trait]
+impl Merger for FfmpegMerger {
+    async fn merge(&self, segments_dir: &Path, output: &Path, segments: &[String]) -> Result<()> {
+        merge_segments(segments_dir, output, segments, &self.options).await
+    }
+}
+
+/// 不依赖 FFmpeg，直接按顺序拼接 MPEG-TS 分段
+pub struct NativeMerger;
+
+#[async_trait]
+impl Merger for NativeMerger {
+    async fn merge(&self, segments_dir: &Path, output: &Path, segments: &[String]) -> Result<()> {
+        let mut output_file = create_native_output(&segments_dir.join(output)).await?;
+        for file_name in segments {
+            append_segment(&mut output_file, &segments_dir.join(file_name)).await?;
+        }
+        output_file.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// 创建直接拼接的输出文件
+async fn create_native_output(output_path: &Path) -> Result<fs::File> {
+    warn_native_container(output_path);
+    Ok(fs::File::create(output_path).await?)
+}
+
+/// 扩展名不是 `.ts` 时给出提示
+fn warn_native_container(output_path: &Path) {
+    if output_path.extension().is_some_and(|ext| ext != "ts") {
+        warn!(
+            "Native merge produces an MPEG-TS stream; {:?} will not be remuxed into its container.",
+            output_path
+        );
+    }
+}
+
+/// 将一个分段追加到输出文件末尾
+async fn append_segment(output_file: &mut fs::File, segment_path: &Path) -> Result<()> {
+    // 压缩的分段边读边解压
+    #[cfg(feature = "compress-segments")]
+    if crate::compress::is_compressed(segment_path) {
+        let data = crate::compress::read_decompressed(segment_path).await?;
+        output_file.write_all(&data).await?;
+        return Ok(());
+    }
+    let mut segment = fs::File::open(segment_path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {:?}: {}", segment_path, e))?;
+    tokio::io::copy(&mut segment, output_file).await?;
+    Ok(())
+}
+
+/// 追加分段后删除分段文件
+async fn append_and_remove(output_file: &mut fs::File, segment_path: &Path) -> Result<()> {
+    append_segment(output_file, segment_path).await?;
+    fs::remove_file(segment_path)
+        .await
+        .map_err(|e| anyhow!("Failed to remove {:?}: {}", segment_path, e))
+}
+
+/// 边下载边拼接的后台任务
+///
+/// 拼接期间写入 `<output>.part`，全部分段拼接完成后才重命名为输出文件，中断的运行不会留下
+/// 看似完整的输出。未调用 [`StreamingMerge::finish`] 就被丢弃时（例如下载失败提前返回），
+/// 中止任务并删除不完整的文件。
+pub struct StreamingMerge {
+    task: Option<JoinHandle<Result<usize>>>,
+    part_path: PathBuf,
+    output_path: PathBuf,
+}
+
+impl StreamingMerge {
+    /// 创建临时输出文件并启动拼接任务，参数含义见 [`merge_while_downloading`]
+    pub async fn spawn(
+        segments_dir: PathBuf,
+        output: PathBuf,
+        segments: Vec<String>,
+        completed: UnboundedReceiver<usize>,
+        disk_flusher: Option<Arc<DiskFlusher>>,
+    ) -> Result<Self> {
+        let output_path = segments_dir.join(output);
+        let mut part_path = output_path.as_os_str().to_owned();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+        warn_native_container(&output_path);
+        let output_file = fs::File::create(&part_path).await?;
+        let task = tokio::spawn(merge_while_downloading(
+            segments_dir,
+            output_file,
+            segments,
+            completed,
+            disk_flusher,
+        ));
+        Ok(Self {
+            task: Some(task),
+            part_path,
+            output_path,
+        })
+    }
+
+    /// 等待拼接结束，成功时将临时文件重命名为输出文件，失败时删除临时文件
+    pub async fn finish(mut self) -> Result<usize> {
+        let Some(task) = self.task.take() else {
+            unreachable!("finish consumes the merge");
+        };
+        let result = match task.await {
+            Ok(result) => result,
+            Err(e) => Err(anyhow!("Merge task failed: {}", e)),
+        };
+        match result {
+            Ok(count) => {
+                fs::rename(&self.part_path, &self.output_path).await?;
+                Ok(count)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&self.part_path).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for StreamingMerge {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            if std::fs::remove_file(&self.part_path).is_ok() {
+                warn!("Removed incomplete output {:?}", self.part_path);
+            }
+        }
+    }
+}
+
+/// 边下载边直接拼接分段，每个分段追加到 `output_file` 后立即删除
+///
+/// `completed` 接收下载完成的分段索引，前面的分段都已拼接时才追加该分段，保证输出顺序与播放列表一致。
+/// 下载结束（通道关闭）后跳过下载失败的分段，拼接其后已完成的分段。返回拼接的分段数量。
+async fn merge_while_downloading(
+    segments_dir: PathBuf,
+    mut output_file: fs::File,
+    segments: Vec<String>,
+    mut completed: UnboundedReceiver<usize>,
+    disk_flusher: Option<Arc<DiskFlusher>>,
+) -> Result<usize> {
+    // 同步器只持有弱引用，此句柄在拼接结束时释放
+    let _sync_handle = match &disk_flusher {
+        Some(flusher) => {
+            let handle = Arc::new(output_file.try_clone().await?.into_std().await);
+            flusher.track_open(&handle);
+            Some(handle)
+        }
+        None => None,
+    };
+    let mut available = vec![false; segments.len()];
+    let mut next = 0;
+    let mut appended = 0;
+
+    while let Some(index) = completed.recv().await {
+        if let Some(flag) = available.get_mut(index) {
+            *flag = true;
+        }
+        while next < segments.len() && available[next] {
+            append_and_remove(&mut output_file, &segments_dir.join(&segments[next])).await?;
+            next += 1;
+            appended += 1;
+        }
+    }
+    // 下载结束后跳过失败的分段，拼接其余已完成的分段
+    for (file_name, _) in segments
+        .iter()
+        .zip(&available)
+        .skip(next)
+        .filter(|(_, a)| **a)
+    {
+        append_and_remove(&mut output_file, &segments_dir.join(file_name)).await?;
+        appended += 1;
+    }
+
+    output_file.flush().await?;
+    Ok(appended)
+}
+
+/// 分组输出文件路径：`<stem>_<NNN>.<ext>`
+pub fn group_output_path(output: &Path, group: usize) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file_name = match output.extension() {
+        Some(ext) => format!("{}_{:03}.{}", stem, group, ext.to_string_lossy()),
+        None => format!("{}_{:03}", stem, group),
+    };
+    output.with_file_name(file_name)
+}
+
+/// 按大小拆分的输出文件路径：`<stem>_part<NNN>.<ext>`
+pub fn part_output_path(output: &Path, part: usize) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file_name = match output.extension() {
+        Some(ext) => format!("{}_part{:03}.{}", stem, part, ext.to_string_lossy()),
+        None => format!("{}_part{:03}", stem, part),
+    };
+    output.with_file_name(file_name)
+}
+
+/// 按分段解压后的大小将分段依次划分为多个部分，每部分的总大小不超过 `max_bytes`
+///
+/// 单个分段超过上限时无法拆分，返回错误。
+pub async fn split_by_size(
+    segments_dir: &Path,
+    segments: &[String],
+    max_bytes: u64,
+) -> Result<Vec<Vec<String>>> {
+    let mut parts: Vec<Vec<String>> = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut current_size = 0;
+
+    for file_name in segments {
+        let size = segment_size(&segments_dir.join(file_name)).await?;
+        if size > max_bytes {
+            return Err(anyhow!(
+                "Segment {} ({} bytes) alone exceeds the split size of {} bytes",
+                file_name,
+                size,
+                max_bytes
+            ));
+        }
+        if !current.is_empty() && current_size + size > max_bytes {
+            parts.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current.push(file_name.clone());
+        current_size += size;
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    Ok(parts)
+}
+
+/// 分段合并时写入的字节数，压缩保存的分段按解压后的大小计算
+async fn segment_size(path: &Path) -> Result<u64> {
+    #[cfg(feature = "compress-segments")]
+    if crate::compress::is_compressed(path) {
+        return crate::compress::decompressed_size(path).await;
+    }
+    Ok(fs::metadata(path)
+        .await
+        .map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?
+        .len())
+}
+
+/// 依次将每个部分合并为 `<stem>_part<NNN>.<ext>`，返回输出路径（相对于分段目录）
+///
+/// 合并后的文件超过 `max_bytes`（如转码后变大）时返回错误，不会截断输出。
+pub async fn merge_parts(
+    merger: &dyn Merger,
+    segments_dir: &Path,
+    output: &Path,
+    parts: &[Vec<String>],
+    max_bytes: u64,
+) -> Result<Vec<PathBuf>> {
+    let mut outputs = Vec::with_capacity(parts.len());
+    for (i, segments) in parts.iter().enumerate() {
+        let path = part_output_path(output, i + 1);
+        info!(
+            "Merging part {}/{} ({} segments) into {:?}",
+            i + 1,
+            parts.len(),
+            segments.len(),
+            path
+        );
+        merger.merge(segments_dir, &path, segments).await?;
+        let size = fs::metadata(segments_dir.join(&path)).await?.len();
+        if size > max_bytes {
+            return Err(anyhow!(
+                "Part {:?} is {} bytes, over the split size of {} bytes; use a smaller --split-size",
+                path,
+                size,
+                max_bytes
+            ));
+        }
+        outputs.push(path);
+    }
+    Ok(outputs)
+}
+
+/// 将每个分组分别合并为独立的输出文件，最多同时运行 `parallelism` 个合并任务
+///
+/// 返回按分组顺序排列的输出路径（相对于分段目录）。
+pub async fn merge_groups(
+    merger: &dyn Merger,
+    segments_dir: &Path,
+    output: &Path,
+    groups: &[Vec<String>],
+    parallelism: usize,
+) -> Result<Vec<PathBuf>> {
+    let outputs: Vec<PathBuf> = (1..=groups.len())
+        .map(|group| group_output_path(output, group))
+        .collect();
+
+    {
+        let mut pending = groups.iter().zip(&outputs);
+        let mut running = FuturesUnordered::new();
+        for (segments, path) in pending.by_ref().take(parallelism.max(1)) {
+            running.push(merger.merge(segments_dir, path, segments));
+        }
+        while let Some(result) = running.next().await {
+            result?;
+            if let Some((segments, path)) = pending.next() {
+                running.push(merger.merge(segments_dir, path, segments));
+            }
+        }
+    }
+
+    info!("Merged {} groups.", outputs.len());
+    Ok(outputs)
+}
+
+/// 容器对某种编码的支持情况
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compatibility {
+    /// 需要指定视频标签，如 MP4 中的 HEVC 需标记为 `hvc1` 才能被 Apple 播放器识别
+    NeedsVideoTag(&'static str),
+    /// 容器无法存放该编码
+    Unsupported,
+}
+
+/// 容器与编码（ffprobe 的 `codec_name`）的兼容性，未列出的组合视为兼容
+const CODEC_COMPATIBILITY: &[(Container, &str, Compatibility)] = &[
+    (Container::Mp4, "hevc", Compatibility::NeedsVideoTag("hvc1")),
+    (Container::Mp4, "vp8", Compatibility::Unsupported),
+    (Container::Mp4, "vp9", Compatibility::Unsupported),
+    (Container::Ts, "vp8", Compatibility::Unsupported),
+    (Container::Ts, "vp9", Compatibility::Unsupported),
+];
+
+/// 根据 `--container` 或输出文件扩展名确定输出容器，无法识别时返回 `None`
+pub fn output_container(container: Option<Container>, output: &Path) -> Option<Container> {
+    container.or_else(
+        || match output.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "mp4" | "m4v" | "mov" => Some(Container::Mp4),
+            "mkv" => Some(Container::Mkv),
+            "ts" => Some(Container::Ts),
+            _ => None,
+        },
+    )
+}
+
+/// 检查输出容器能否存放分段中的编码，返回输出为 MP4 时需要附加的视频标签
+///
+/// 转码时视频按目标编码检查。不兼容时返回带有建议做法的错误。
+pub fn check_container_compatibility(
+    container: Container,
+    streams: &[ProbeStream],
+    video_codec: VideoCodec,
+) -> Result<Option<&'static str>> {
+    let target_video = match video_codec {
+        VideoCodec::Copy => None,
+        VideoCodec::H264 => Some("h264"),
+        VideoCodec::Hevc => Some("hevc"),
+        VideoCodec::Vp9 => Some("vp9"),
+    };
+    let codecs =
+        streams.iter().filter_map(
+            |stream| match (stream.codec_type.as_deref(), target_video) {
+                (Some("video"), Some(target)) => Some(target),
+                _ => stream.codec_name.as_deref(),
+            },
+        );
+
+    let mut video_tag = None;
+    for codec in codecs {
+        let entry = CODEC_COMPATIBILITY
+            .iter()
+            .find(|(c, name, _)| *c == container && *name == codec);
+        match entry {
+            Some((_, _, Compatibility::NeedsVideoTag(tag))) => video_tag = Some(*tag),
+            Some((_, _, Compatibility::Unsupported)) => {
+                return Err(anyhow!(
+                    "{} cannot be stored in {}; use --container mkv with an .mkv output name, or re-encode with --output-video-codec h264",
+                    codec,
+                    container.extension().to_uppercase()
+                ))
+            }
+            None => {}
+        }
+    }
+    Ok(video_tag)
+}
+
+/// 根据命令行参数创建合并器
+///
+/// `video_tag` 为合并前兼容性检查得出的视频标签。`full_output` 为假时合并的是不连续分组或按大小
+/// 拆分出的部分文件，章节按完整时间轴生成，因此不嵌入章节，也不生成 `--output-also` 的额外格式。
+pub fn create_merger(
+    args: &Args,
+    metadata: Option<VideoMetadata>,
+    video_tag: Option<&'static str>,
+    full_output: bool,
+) -> Box<dyn Merger> {
+    let hardware_accel = match (args.output_video_codec, args.ffmpeg_hardware_accel) {
+        (_, None) => None,
+        (VideoCodec::Copy, Some(accel)) => {
+            warn!(
+                "Ignoring --ffmpeg-hardware-accel {:?}: the video is copied without re-encoding (see --output-video-codec).",
+                accel
+            );
+            None
+        }
+        (codec, Some(accel)) if video_encoder(codec, Some(accel)).is_none() => {
+            warn!(
+                "No known {:?} encoder for {:?}; falling back to software encoding with {}.",
+                accel,
+                codec,
+                video_encoder(codec, None).unwrap_or_default()
+            );
+            None
+        }
+        (_, accel) => accel,
+    };
+
+    match args.merger {
+        MergerKind::Ffmpeg => Box::new(FfmpegMerger {
+            options: FfmpegOptions {
+                ffmpeg_path: args.ffmpeg_path.clone(),
+                container: args.container,
+                chapters_file: (full_output && args.output_chapters)
+                    .then(|| PathBuf::from(CHAPTERS_FILE_NAME)),
+                output_also: if full_output {
+                    args.output_also.clone()
+                } else {
+                    Vec::new()
+                },
+                metadata,
+                log_level: if args.silent {
+                    Some("quiet")
+                } else if args.quiet {
+                    Some("error")
+                } else {
+                    None
+                },
+                video_codec: args.output_video_codec,
+                hardware_accel,
+                resolution: args.output_video_resolution,
+                track_selection: match args.track_type {
+                    Some((kind, index)) => Some(TrackSelection::Nth(kind, index)),
+                    None if !args.track_id.is_empty() => {
+                        Some(TrackSelection::Pids(args.track_id.clone()))
+                    }
+                    None => None,
+                },
+                video_tag,
+            },
+        }),
+        MergerKind::Native => Box::new(NativeMerger),
+    }
+}
+
+/// 将下载的 WebVTT 字幕分段合并并转换为 SRT 文件，无需 FFmpeg
+pub async fn generate_srt_from_vtt_segments(
+    segments_dir: &Path,
+    count: usize,
+    output_path: &Path,
+) -> Result<()> {
+    let vtt = merge_vtt_segments(segments_dir, count).await?;
+    fs::write(output_path, vtt_to_srt(&vtt)?).await?;
+    info!("Subtitles written to {:?}", output_path);
+    Ok(())
+}
+
+/// 由本地分段重建的播放列表文件名
+pub const LOCAL_PLAYLIST_NAME: &str = "playlist.m3u8";
+
+/// 为目录中的 `index{N}.ts` 分段重建播放列表，写入该目录下的 `playlist.m3u8`
+///
+/// 分段按序号的数值排序（兼容补零和未补零的文件名），时长通过解析分段获得；
+/// 序号不连续处插入 `#EXT-X-DISCONTINUITY`。
+pub async fn generate_playlist_from_segments(dir: &Path, ffprobe: &Path) -> Result<PathBuf> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(|e| anyhow!("Failed to read segment directory {:?}: {}", dir, e))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let index = name
+            .strip_prefix("index")
+            .and_then(|rest| rest.strip_suffix(".ts"))
+            .and_then(|n| n.parse::<usize>().ok());
+        if let Some(index) = index {
+            files.push((index, name));
+        }
+    }
+    if files.is_empty() {
+        return Err(anyhow!("No index{{N}}.ts segments found in {:?}", dir));
+    }
+    files.sort_unstable();
+
+    let mut segments = Vec::with_capacity(files.len());
+    let mut previous = None;
+    for (index, uri) in files {
+        let duration = segment_duration_secs(ffprobe, &dir.join(&uri)).await?;
+        let discontinuity = previous.is_some_and(|p| index != p + 1);
+        if discontinuity {
+            warn!(
+                "Segments before {} are missing, marking a discontinuity.",
+                uri
+            );
+        }
+        previous = Some(index);
+        segments.push(MediaSegment {
+            uri,
+            duration: duration as f32,
+            discontinuity,
+            ..Default::default()
+        });
+    }
+
+    let playlist = MediaPlaylist {
+        version: Some(3),
+        target_duration: segments
+            .iter()
+            .map(|s| s.duration.ceil() as u64)
+            .max()
+            .unwrap_or_default(),
+        segments,
+        end_list: true,
+        playlist_type: Some(MediaPlaylistType::Vod),
+        ..Default::default()
+    };
+    let mut content = Vec::new();
+    playlist.write_to(&mut content)?;
+
+    let output = dir.join(LOCAL_PLAYLIST_NAME);
+    fs::write(&output, content).await?;
+    info!(
+        "Playlist with {} segments written to {:?}",
+        playlist.segments.len(),
+        output
+    );
+    Ok(output)
+}
+
+/// 本地 HLS 播放列表的文件名
+pub const LOCAL_HLS_PLAYLIST_NAME: &str = "output.m3u8";
+
+/// 将已下载的分段重新打包为本地 HLS 播放列表，写入分段目录下的 `output.m3u8`
+///
+/// `pl` 中分段和 `EXT-X-MAP` 的 URI 须已替换为分段目录中的文件名。分段已解密，因此去掉密钥、
+/// 字节范围等指向原始资源的标签，只保留时长、标题、不连续标记、节目时间和初始化分段。
+pub async fn write_local_hls_playlist(pl: &MediaPlaylist, segments_dir: &Path) -> Result<()> {
+    let segments: Vec<MediaSegment> = pl
+        .segments
+        .iter()
+        .map(|segment| MediaSegment {
+            uri: segment.uri.clone(),
+            duration: segment.duration,
+            title: segment.title.clone(),
+            discontinuity: segment.discontinuity,
+            program_date_time: segment.program_date_time,
+            map: segment.map.clone(),
+            ..Default::default()
+        })
+        .collect();
+    // 媒体播放列表中的 EXT-X-MAP 需要版本 6
+    let has_map = segments.iter().any(|s| s.map.is_some());
+    let playlist = MediaPlaylist {
+        version: Some(if has_map { 6 } else { 3 }),
+        target_duration: segments
+            .iter()
+            .map(|s| s.duration.ceil() as u64)
+            .max()
+            .unwrap_or(pl.target_duration),
+        media_sequence: pl.media_sequence,
+        discontinuity_sequence: pl.discontinuity_sequence,
+        segments,
+        end_list: true,
+        playlist_type: Some(MediaPlaylistType::Vod),
+        independent_segments: pl.independent_segments,
+        ..Default::default()
+    };
+    let mut content = Vec::new();
+    playlist.write_to(&mut content)?;
+
+    let output = segments_dir.join(LOCAL_HLS_PLAYLIST_NAME);
+    fs::write(&output, content).await?;
+    info!(
+        "Local HLS playlist with {} segments written to {:?}",
+        playlist.segments.len(),
+        output
+    );
+    Ok(())
+}
 
 /// 合并下载的分段
 pub async fn merge_segments(
     segments_dir: &Path,
-    output_path: &String,
-    ffmpeg_path: Option<&Path>,
-    segment_count: usize,
+    output_path: &Path,
+    segments: &[String],
+    options: &FfmpegOptions,
 ) -> Result<()> {
-    // 创建一个临时文件列表
-    let file_list_path = segments_dir.join("filelist.txt");
+    // 创建一个临时文件列表，以输出文件命名，避免并行合并时冲突
+    let output_name = output_path
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file_list_name = format!("{}.filelist.txt", output_name);
+    // FFmpeg 无法直接读取压缩的分段，先解压到临时目录
+    #[cfg(feature = "compress-segments")]
+    let uncompressed_dir = format!("{}.uncompressed", output_name);
+    let file_list_path = segments_dir.join(&file_list_name);
     let mut file_list = fs::File::create(&file_list_path).await?;
 
     // 写入文件列表
-    for i in 0..segment_count {
-        let segment_path = format!("index{}.ts", i);
+    for file_name in segments {
+        #[cfg(feature = "compress-segments")]
+        let file_name = &uncompressed_copy(segments_dir, file_name, &uncompressed_dir).await?;
+        // concat 格式中单引号需转义为 '\''
         file_list
-            .write_all(format!("file '{}'", segment_path).as_bytes())
+            .write_all(format!("file '{}'", file_name.replace('\'', "'\\''")).as_bytes())
             .await?;
         file_list.write_all(b"\n").await?;
     }
     file_list.flush().await?;
 
+    // 先生成主输出，再依次生成额外格式
+    let mut outputs = vec![(output_path.to_path_buf(), options.container)];
+    outputs.extend(
+        options
+            .output_also
+            .iter()
+            .map(|&format| (output_path.with_extension(format.extension()), Some(format))),
+    );
+
+    let mut result = Ok(());
+    for (path, container) in &outputs {
+        result = run_ffmpeg(segments_dir, &file_list_name, path, *container, options).await;
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // 删除临时文件列表
+    let _ = fs::remove_file(&file_list_path).await;
+    #[cfg(feature = "compress-segments")]
+    let _ = fs::remove_dir_all(segments_dir.join(&uncompressed_dir)).await;
+
+    result
+}
+
+/// 将压缩的分段解压到临时目录，返回供 FFmpeg 使用的相对路径；未压缩的分段原样返回
+#[cfg(feature = "compress-segments")]
+async fn uncompressed_copy(segments_dir: &Path, file_name: &str, temp_dir: &str) -> Result<String> {
+    let path = segments_dir.join(file_name);
+    if !crate::compress::is_compressed(&path) {
+        return Ok(file_name.to_string());
+    }
+
+    let data = crate::compress::read_decompressed(&path).await?;
+    // 去掉 .gz 扩展名
+    let relative = Path::new(temp_dir).join(Path::new(file_name).with_extension(""));
+    fs::create_dir_all(segments_dir.join(temp_dir)).await?;
+    fs::write(segments_dir.join(&relative), data).await?;
+    Ok(relative.to_string_lossy().to_string())
+}
+
+/// 编码与硬件加速后端对应的 FFmpeg 编码器，`Copy` 或没有对应编码器时返回 `None`
+fn video_encoder(codec: VideoCodec, accel: Option<HardwareAccel>) -> Option<&'static str> {
+    match (codec, accel) {
+        (VideoCodec::Copy, _) => None,
+        (VideoCodec::H264, None) => Some("libx264"),
+        (VideoCodec::H264, Some(HardwareAccel::Vaapi)) => Some("h264_vaapi"),
+        (VideoCodec::H264, Some(HardwareAccel::Nvenc)) => Some("h264_nvenc"),
+        (VideoCodec::H264, Some(HardwareAccel::Qsv)) => Some("h264_qsv"),
+        (VideoCodec::Hevc, None) => Some("libx265"),
+        (VideoCodec::Hevc, Some(HardwareAccel::Vaapi)) => Some("hevc_vaapi"),
+        (VideoCodec::Hevc, Some(HardwareAccel::Nvenc)) => Some("hevc_nvenc"),
+        (VideoCodec::Hevc, Some(HardwareAccel::Qsv)) => Some("hevc_qsv"),
+        (VideoCodec::Vp9, None) => Some("libvpx-vp9"),
+        (VideoCodec::Vp9, Some(HardwareAccel::Vaapi)) => Some("vp9_vaapi"),
+        (VideoCodec::Vp9, Some(HardwareAccel::Nvenc)) => None,
+        (VideoCodec::Vp9, Some(HardwareAccel::Qsv)) => Some("vp9_qsv"),
+    }
+}
+
+/// 硬件加速的输入参数，解码后的帧保留在显存中直接交给硬件编码器
+fn hwaccel_input_args(accel: HardwareAccel) -> &'static [&'static str] {
+    match accel {
+        HardwareAccel::Vaapi => &[
+            "-hwaccel",
+            "vaapi",
+            "-hwaccel_device",
+            "/dev/dri/renderD128",
+            "-hwaccel_output_format",
+            "vaapi",
+        ],
+        HardwareAccel::Nvenc => &["-hwaccel", "cuda", "-hwaccel_output_format", "cuda"],
+        HardwareAccel::Qsv => &["-hwaccel", "qsv", "-hwaccel_output_format", "qsv"],
+    }
+}
+
+/// 缩放滤镜名称，硬件解码的帧留在显存中，需要使用对应后端的滤镜
+fn scale_filter(accel: Option<HardwareAccel>) -> &'static str {
+    match accel {
+        None => "scale",
+        Some(HardwareAccel::Vaapi) => "scale_vaapi",
+        Some(HardwareAccel::Nvenc) => "scale_cuda",
+        Some(HardwareAccel::Qsv) => "scale_qsv",
+    }
+}
+
+/// 以文件列表为输入运行一次 FFmpeg
+async fn run_ffmpeg(
+    segments_dir: &Path,
+    file_list_name: &str,
+    output_path: &Path,
+    container: Option<Container>,
+    options: &FfmpegOptions,
+) -> Result<()> {
     // 确定ffmpeg路径
-    let ffmpeg = match ffmpeg_path {
+    let ffmpeg = match &options.ffmpeg_path {
         Some(path) => path.to_path_buf(),
         None => PathBuf::from("ffmpeg"), // 默认使用系统PATH中的ffmpeg
     };
 
     // 构建ffmpeg命令
-    let status = Command::new(&ffmpeg)
+    let mut command = Command::new(&ffmpeg);
+    if let Some(log_level) = options.log_level {
+        command.arg("-hide_banner").arg("-loglevel").arg(log_level);
+    }
+    if let Some(accel) = options.hardware_accel {
+        command.args(hwaccel_input_args(accel));
+    }
+    command
         .current_dir(segments_dir) // 设置工作目录为分段目录
         .arg("-f")
         .arg("concat")
         .arg("-safe")
         .arg("0")
         .arg("-i")
-        .arg("filelist.txt")
-        .arg("-c")
-        .arg("copy")
-        .arg("-bsf:a")
-        .arg("aac_adtstoasc")
-        .arg("-movflags")
-        .arg("+faststart")
-        .arg("-y")
-        .arg(output_path)
+        .arg(file_list_name);
+
+    // 章节元数据作为第二个输入
+    if let Some(chapters_file) = &options.chapters_file {
+        command
+            .arg("-i")
+            .arg(chapters_file)
+            .arg("-map_chapters")
+            .arg("1");
+    }
+
+    // 有第二个输入或选择了轨道时需要显式映射
+    match &options.track_selection {
+        Some(selection) => {
+            for specifier in selection.map_specifiers() {
+                command.arg("-map").arg(specifier);
+            }
+        }
+        None if options.chapters_file.is_some() => {
+            command.arg("-map").arg("0");
+        }
+        None => {}
+    }
+
+    match video_encoder(options.video_codec, options.hardware_accel) {
+        Some(encoder) => {
+            command.args(["-c:v", encoder, "-c:a", "copy", "-c:s", "copy"]);
+            if let Some([width, height]) = options.resolution {
+                command.arg("-vf").arg(format!(
+                    "{}={}:{}",
+                    scale_filter(options.hardware_accel),
+                    width,
+                    height
+                ));
+            }
+        }
+        None => {
+            command.arg("-c").arg("copy");
+        }
+    }
+    command.arg("-bsf:a").arg("aac_adtstoasc");
+    if let Some(tag) = options.video_tag {
+        if output_container(container, output_path) == Some(Container::Mp4) {
+            command.arg("-tag:v").arg(tag);
+        }
+    }
+
+    if let Some(metadata) = &options.metadata {
+        for tag in metadata.ffmpeg_tags() {
+            command.arg("-metadata").arg(tag);
+        }
+    }
+
+    match container {
+        None => {
+            command.arg("-movflags").arg("+faststart");
+        }
+        Some(Container::Mp4) => {
+            command.args(["-movflags", "+faststart", "-f", "mp4"]);
+        }
+        Some(Container::Mkv) => {
+            command.args(["-f", "matroska"]);
+        }
+        Some(Container::Ts) => {
+            command.args(["-f", "mpegts"]);
+        }
+    }
+
+    let status = command.arg("-y").arg(output_path).status().await?;
+    if !status.success() {
+        return Err(anyhow!(
+            "FFmpeg failed for {:?} with exit code: {:?}",
+            output_path,
+            status.code()
+        ));
+    }
+
+    Ok(())
+}
+
+/// 检查 FFmpeg 能否运行
+pub async fn check_ffmpeg(ffmpeg_path: Option<&Path>) -> Result<()> {
+    let ffmpeg = ffmpeg_path.unwrap_or(Path::new("ffmpeg"));
+    let status = Command::new(ffmpeg)
+        .arg("-version")
+        .stdout(std::process::Stdio::null())
         .status()
-        .await?;
+        .await
+        .map_err(|e| anyhow!("Failed to run {:?}: {}", ffmpeg, e))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "{:?} -version failed with exit code: {:?}",
+            ffmpeg,
+            status.code()
+        ));
+    }
+    Ok(())
+}
 
-    // 删除临时文件列表
-    let _ = fs::remove_file(&file_list_path).await;
+/// 间隙填充分段在无法探测相邻分段时使用的分辨率
+const GAP_FILLER_RESOLUTION: [u32; 2] = [1280, 720];
+
+/// 使用 FFmpeg 生成时长为 `duration_secs` 的黑屏静音 MPEG-TS 分段，填补播放列表中的间隙
+///
+/// 分辨率与 `reference` 分段的视频流一致，探测失败时使用 1280x720。与空包占位文件不同，
+/// 生成的分段带有时间戳，合并后输出的时长包含间隙。
+pub async fn generate_gap_filler(
+    ffmpeg_path: Option<&Path>,
+    reference: Option<&Path>,
+    output: &Path,
+    duration_secs: f64,
+) -> Result<()> {
+    let mut resolution = GAP_FILLER_RESOLUTION;
+    if let Some(reference) = reference {
+        match probe_streams(&ffprobe_path(ffmpeg_path), reference).await {
+            Ok(streams) => {
+                if let Some((Some(width), Some(height))) = streams
+                    .iter()
+                    .find(|s| s.codec_type.as_deref() == Some("video"))
+                    .map(|s| (s.width, s.height))
+                {
+                    resolution = [width, height];
+                }
+            }
+            Err(e) => warn!(
+                "Could not probe {:?} for the gap filler resolution: {}",
+                reference, e
+            ),
+        }
+    }
 
+    let ffmpeg = ffmpeg_path.unwrap_or(Path::new("ffmpeg"));
+    let duration = format!("{:.3}", duration_secs);
+    let status = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-f", "lavfi", "-i"])
+        .arg(format!(
+            "color=c=black:s={}x{}:r=25:d={}",
+            resolution[0], resolution[1], duration
+        ))
+        .args(["-f", "lavfi", "-i", "anullsrc=r=48000:cl=stereo"])
+        .arg("-t")
+        .arg(&duration)
+        .args([
+            "-c:v", "libx264", "-pix_fmt", "yuv420p", "-c:a", "aac", "-f", "mpegts", "-y",
+        ])
+        .arg(output)
+        .status()
+        .await
+        .map_err(|e| anyhow!("Failed to run {:?}: {}", ffmpeg, e))?;
     if !status.success() {
-        return Err(anyhow!("FFmpeg failed with exit code: {:?}", status.code()));
+        return Err(anyhow!(
+            "FFmpeg failed to generate the gap filler {:?} with exit code: {:?}",
+            output,
+            status.code()
+        ));
     }
+    Ok(())
+}
 
+/// 使用 FFmpeg 从分段中截取一帧保存为 JPEG 缩略图
+///
+/// `offset_secs` 为截取位置（相对于分段开头），`size` 为缩放后的 [宽, 高]。
+pub async fn extract_thumbnail(
+    ffmpeg_path: Option<&Path>,
+    segment: &Path,
+    output: &Path,
+    offset_secs: f64,
+    size: Option<[u32; 2]>,
+) -> Result<()> {
+    let ffmpeg = ffmpeg_path.unwrap_or(Path::new("ffmpeg"));
+    let mut command = Command::new(ffmpeg);
+    command.args(["-hide_banner", "-loglevel", "error"]);
+    if offset_secs > 0.0 {
+        command.arg("-ss").arg(offset_secs.to_string());
+    }
+    command
+        .arg("-i")
+        .arg(segment)
+        .args(["-frames:v", "1", "-q:v", "2"]);
+    if let Some([width, height]) = size {
+        command
+            .arg("-vf")
+            .arg(format!("scale={}:{}", width, height));
+    }
+
+    let status = command.arg("-y").arg(output).status().await?;
+    if !status.success() {
+        return Err(anyhow!(
+            "FFmpeg failed to extract a thumbnail from {:?} with exit code: {:?}",
+            segment,
+            status.code()
+        ));
+    }
+    Ok(())
+}
+
+/// 使用 FFmpeg 裁剪出拼图左上角的第一张缩略图，`tile` 为单张缩略图的 [宽, 高]
+pub async fn extract_first_tile(
+    ffmpeg_path: Option<&Path>,
+    image: &Path,
+    output: &Path,
+    tile: [u32; 2],
+) -> Result<()> {
+    let ffmpeg = ffmpeg_path.unwrap_or(Path::new("ffmpeg"));
+    let status = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(image)
+        .arg("-vf")
+        .arg(format!("crop={}:{}:0:0", tile[0], tile[1]))
+        .args(["-frames:v", "1", "-q:v", "2", "-y"])
+        .arg(output)
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(anyhow!(
+            "FFmpeg failed to crop the first tile of {:?} with exit code: {:?}",
+            image,
+            status.code()
+        ));
+    }
     Ok(())
 }
 
 /// 清理下载的分段文件
-pub async fn cleanup_segments(segments_dir: &Path) -> Result<()> {
-    let mut read_dir = fs::read_dir(segments_dir).await?;
+///
+/// 只删除 `segments` 中列出的分段，避免误删同目录下的输出文件。
+pub async fn cleanup_segments(segments_dir: &Path, segments: &[String]) -> Result<()> {
     let mut errors = Vec::new();
 
-    while let Some(entry) = read_dir.next_entry().await? {
-        let path = entry.path();
-        if let Some(ext) = path.extension() {
-            if ext == "ts" {
-                if let Err(e) = fs::remove_file(&path).await {
-                    errors.push(format!("Failed to remove {:?}: {}", path, e));
-                }
-            }
+    for file_name in segments {
+        let path = segments_dir.join(file_name);
+        match fs::remove_file(&path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => errors.push(format!("Failed to remove {:?}: {}", path, e)),
         }
     }
 
@@ -0,0 +1,531 @@
+use anyhow::{anyhow, Result};
+use m3u8_rs::{MediaPlaylist, MediaSegment, SessionData, SessionDataField};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use url::Url;
+
+use crate::downloader::SegmentTiming;
+use crate::playlist::{parse_attribute_list, playlist_title, tag_name, KeyInfo};
+
+/// 写入分段目录的 FFmpeg 章节元数据文件名
+pub const CHAPTERS_FILE_NAME: &str = "chapters.ffmeta";
+
+/// 写入分段目录的分段清单文件名
+pub const SEGMENTS_MANIFEST_NAME: &str = "segments.json";
+
+/// 与分段清单一起写入的分段URL索引文件名，供 `--reuse-segments-from` 查找可复用的分段
+pub const URL_HASH_INDEX_NAME: &str = "url_to_hash.json";
+
+/// 广告/插播类 `EXT-X-DATERANGE` 的 CLASS 值
+const AD_CLASSES: &[&str] = &["com.apple.hls.interstitial", "AD"];
+
+/// 推导流的标题，依次尝试 `#EXT-X-TITLE`、URL路径和主机名
+pub fn stream_title(playlist: &MediaPlaylist, url: &Url) -> Option<String> {
+    playlist_title(playlist)
+        .or_else(|| {
+            url.path_segments()?
+                .rev()
+                .find(|s| !s.is_empty())
+                .map(|s| s.rsplit_once('.').map_or(s, |(stem, _)| stem).to_string())
+                .filter(|s| !s.is_empty())
+        })
+        .or_else(|| url.host_str().map(str::to_string))
+}
+
+/// 可序列化的 `#EXT-X-SESSION-DATA` 条目
+#[derive(Debug, Serialize)]
+struct SessionDataEntry<'a> {
+    data_id: &'a str,
+    value: Option<&'a str>,
+    uri: Option<&'a str>,
+    language: Option<&'a str>,
+}
+
+/// 将 `#EXT-X-SESSION-DATA` 条目写入 `<name>.description.json`
+pub async fn write_description(
+    session_data: &[SessionData],
+    output_dir: &Path,
+    name: &str,
+) -> Result<()> {
+    let entries: Vec<_> = session_data
+        .iter()
+        .map(|sd| {
+            let (value, uri) = match &sd.field {
+                SessionDataField::Value(v) => (Some(v.as_str()), None),
+                SessionDataField::Uri(u) => (None, Some(u.as_str())),
+            };
+            SessionDataEntry {
+                data_id: &sd.data_id,
+                value,
+                uri,
+                language: sd.language.as_deref(),
+            }
+        })
+        .collect();
+
+    let path = output_dir.join(format!("{}.description.json", name));
+    fs::write(path, serde_json::to_vec_pretty(&entries)?).await?;
+    Ok(())
+}
+
+/// 将 `#EXT-X-TITLE` 以纯文本写入 `<name>.description.txt`
+pub async fn write_title_description(title: &str, output_dir: &Path, name: &str) -> Result<()> {
+    let path = output_dir.join(format!("{}.description.txt", name));
+    fs::write(path, format!("{}\n", title)).await?;
+    Ok(())
+}
+
+/// `#EXT-X-DATERANGE` 标签描述的时间段
+#[derive(Debug, Clone, Serialize)]
+pub struct DateRange {
+    pub id: String,
+    pub class: Option<String>,
+    pub start_date: String,
+    pub duration: Option<f64>,
+    pub end_date: Option<String>,
+    pub attributes: HashMap<String, String>,
+    /// 标签在播放列表时间轴上的起始位置（秒）
+    #[serde(skip)]
+    pub playlist_offset: f64,
+}
+
+impl DateRange {
+    /// 是否为广告或插播内容
+    pub fn is_ad(&self) -> bool {
+        self.class
+            .as_deref()
+            .is_some_and(|class| AD_CLASSES.contains(&class))
+    }
+}
+
+/// 从媒体播放列表中解析所有 `#EXT-X-DATERANGE` 标签
+pub fn parse_date_ranges(playlist: &MediaPlaylist) -> Vec<DateRange> {
+    let mut ranges = Vec::new();
+    let mut offset = 0.0;
+
+    for segment in &playlist.segments {
+        // m3u8_rs 能识别的标签会被解析到 daterange 字段
+        if let Some(dr) = &segment.daterange {
+            ranges.push(DateRange {
+                id: dr.id.clone(),
+                class: dr.class.clone(),
+                start_date: dr.start_date.to_rfc3339(),
+                duration: dr.duration,
+                end_date: dr.end_date.map(|d| d.to_rfc3339()),
+                attributes: HashMap::new(),
+                playlist_offset: offset,
+            });
+        }
+
+        // 其余的以未知标签形式保留
+        for tag in segment
+            .unknown_tags
+            .iter()
+            .filter(|t| tag_name(t) == "X-DATERANGE")
+        {
+            let mut attributes = parse_attribute_list(tag.rest.as_deref().unwrap_or_default());
+            ranges.push(DateRange {
+                id: attributes.remove("ID").unwrap_or_default(),
+                class: attributes.remove("CLASS"),
+                start_date: attributes.remove("START-DATE").unwrap_or_default(),
+                duration: attributes.remove("DURATION").and_then(|d| d.parse().ok()),
+                end_date: attributes.remove("END-DATE"),
+                attributes,
+                playlist_offset: offset,
+            });
+        }
+
+        offset += segment.duration as f64;
+    }
+
+    ranges
+}
+
+/// 返回完全落在广告时间段内的分段索引
+///
+/// 时间段从标签所在分段开始，持续 `DURATION` 秒；没有时长的时间段不会跳过任何分段。
+pub fn ad_segment_indices(playlist: &MediaPlaylist, ranges: &[DateRange]) -> Vec<usize> {
+    let ad_spans: Vec<(f64, f64)> = ranges
+        .iter()
+        .filter(|r| r.is_ad())
+        .filter_map(|r| Some((r.playlist_offset, r.playlist_offset + r.duration?)))
+        .collect();
+
+    let mut indices = Vec::new();
+    let mut offset = 0.0;
+    for (i, segment) in playlist.segments.iter().enumerate() {
+        let end = offset + segment.duration as f64;
+        if ad_spans
+            .iter()
+            .any(|(start, stop)| offset >= *start && end <= *stop)
+        {
+            indices.push(i);
+        }
+        offset = end;
+    }
+
+    indices
+}
+
+/// 将解析出的时间段写入JSON文件
+pub async fn write_date_ranges(ranges: &[DateRange], path: &Path) -> Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(ranges)?).await?;
+    Ok(())
+}
+
+/// 转义CSV字段
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// 将每个分段的计时信息写入CSV文件
+pub async fn write_timing_csv(timings: &[SegmentTiming], path: &Path) -> Result<()> {
+    let mut csv = String::from(
+        "index,uri,duration_secs,program_date_time,bytes_downloaded,download_time_ms\n",
+    );
+    for t in timings {
+        writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            t.index,
+            csv_field(&t.uri),
+            t.duration_secs,
+            t.program_date_time.as_deref().unwrap_or_default(),
+            t.bytes_downloaded,
+            t.download_time_ms
+        )?;
+    }
+    fs::write(path, csv).await?;
+    Ok(())
+}
+
+/// 分段清单中的一个分段
+#[derive(Serialize)]
+struct SegmentManifestEntry<'a> {
+    index: usize,
+    uri: &'a str,
+    file: String,
+    size: Option<u64>,
+    duration: f32,
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iv: Option<&'a str>,
+}
+
+/// 将已下载分段的索引、原始URI、本地文件名、大小、时长和加密信息写入JSON清单
+///
+/// 文件不存在的分段（如下载失败后被跳过）大小记为 `null`。
+pub async fn write_segments_manifest(
+    segments: &[(usize, MediaSegment, PathBuf, Option<KeyInfo>)],
+    output: &Path,
+) -> Result<()> {
+    let mut entries = Vec::with_capacity(segments.len());
+    for (index, segment, path, key) in segments {
+        let key = key
+            .as_ref()
+            .filter(|k| !k.method.eq_ignore_ascii_case("NONE"));
+        entries.push(SegmentManifestEntry {
+            index: *index,
+            uri: &segment.uri,
+            file: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            size: fs::metadata(path).await.ok().map(|m| m.len()),
+            duration: segment.duration,
+            encrypted: key.is_some(),
+            iv: key.and_then(|k| k.iv.as_deref()),
+        });
+    }
+    fs::write(output, serde_json::to_vec_pretty(&entries)?).await?;
+    Ok(())
+}
+
+/// 分段URL的哈希，作为跨下载复用分段的键
+pub fn segment_url_hash(url: &Url) -> String {
+    sha256::digest(url.as_str())
+}
+
+/// 将已下载分段的URL哈希与本地文件名写入索引，只记录存在的分段文件
+pub async fn write_url_hash_index(segments: &[(Url, PathBuf)], output: &Path) -> Result<()> {
+    let mut index = BTreeMap::new();
+    for (url, path) in segments {
+        if fs::metadata(path).await.is_ok_and(|m| m.len() > 0) {
+            if let Some(name) = path.file_name() {
+                index.insert(segment_url_hash(url), name.to_string_lossy().to_string());
+            }
+        }
+    }
+    fs::write(output, serde_json::to_vec_pretty(&index)?).await?;
+    Ok(())
+}
+
+/// 读取分段目录中的URL哈希索引
+pub async fn read_url_hash_index(dir: &Path) -> Result<HashMap<String, String>> {
+    let path = dir.join(URL_HASH_INDEX_NAME);
+    let content = fs::read(&path)
+        .await
+        .map_err(|e| anyhow!("Failed to read segment index {:?}: {}", path, e))?;
+    serde_json::from_slice(&content).map_err(|e| anyhow!("Invalid segment index {:?}: {}", path, e))
+}
+
+/// 合并时写入输出文件容器元数据的下载信息
+#[derive(Debug, Clone)]
+pub struct VideoMetadata {
+    pub title: String,
+    pub comment: String,
+    pub date: String,
+    pub encoder: String,
+}
+
+impl VideoMetadata {
+    /// 转换为 `key=value` 形式的 FFmpeg `-metadata` 参数值
+    pub fn ffmpeg_tags(&self) -> Vec<String> {
+        [
+            ("title", &self.title),
+            ("comment", &self.comment),
+            ("date", &self.date),
+            ("encoder", &self.encoder),
+        ]
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect()
+    }
+}
+
+/// 输出视频中的一个章节
+#[derive(Debug, Clone, Serialize)]
+pub struct Chapter {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub title: String,
+}
+
+/// 分段上可作为章节标题的文本，依次尝试 DATERANGE ID、`#EXT-X-TITLE` 和 `#EXTINF` 标题
+fn segment_chapter_title(segment: &MediaSegment) -> Option<String> {
+    segment
+        .daterange
+        .as_ref()
+        .map(|dr| dr.id.clone())
+        .or_else(|| {
+            segment.unknown_tags.iter().find_map(|t| match tag_name(t) {
+                "X-DATERANGE" => {
+                    parse_attribute_list(t.rest.as_deref().unwrap_or_default()).remove("ID")
+                }
+                "X-TITLE" => t.rest.as_deref().map(|r| r.trim_matches('"').to_string()),
+                _ => None,
+            })
+        })
+        .or_else(|| segment.title.clone())
+        .filter(|t| !t.trim().is_empty())
+}
+
+/// 根据播放列表生成章节
+///
+/// 指定 `interval_secs` 时按固定间隔切分，否则在每个 `#EXT-X-DISCONTINUITY` 处开始新章节。
+pub fn build_chapters(playlist: &MediaPlaylist, interval_secs: Option<f64>) -> Vec<Chapter> {
+    let total: f64 = playlist.segments.iter().map(|s| s.duration as f64).sum();
+    let mut starts: Vec<(f64, Option<String>)> = Vec::new();
+
+    match interval_secs.filter(|i| *i > 0.0) {
+        Some(interval) => {
+            let mut start = 0.0;
+            while start < total {
+                starts.push((start, None));
+                start += interval;
+            }
+        }
+        None => {
+            let mut offset = 0.0;
+            for (i, segment) in playlist.segments.iter().enumerate() {
+                if i == 0 || segment.discontinuity {
+                    starts.push((offset, segment_chapter_title(segment)));
+                }
+                offset += segment.duration as f64;
+            }
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, (start, title))| Chapter {
+            start_secs: *start,
+            end_secs: starts.get(i + 1).map_or(total, |(next, _)| *next),
+            title: title
+                .clone()
+                .unwrap_or_else(|| format!("Chapter {}", i + 1)),
+        })
+        .collect()
+}
+
+/// 转义 ffmetadata 中的特殊字符
+fn ffmetadata_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 以 FFmpeg 元数据格式写入章节，供合并时通过 `-map_chapters` 嵌入
+pub async fn write_ffmetadata_chapters(chapters: &[Chapter], path: &Path) -> Result<()> {
+    let mut content = String::from(";FFMETADATA1\n");
+    for chapter in chapters {
+        writeln!(content, "[CHAPTER]")?;
+        writeln!(content, "TIMEBASE=1/1000")?;
+        writeln!(
+            content,
+            "START={}",
+            (chapter.start_secs * 1000.0).round() as u64
+        )?;
+        writeln!(
+            content,
+            "END={}",
+            (chapter.end_secs * 1000.0).round() as u64
+        )?;
+        writeln!(content, "title={}", ffmetadata_escape(&chapter.title))?;
+    }
+    fs::write(path, content).await?;
+    Ok(())
+}
+
+/// 将秒数格式化为 WebVTT 时间戳（hh:mm:ss.ttt）
+pub(crate) fn vtt_timestamp(secs: f64) -> String {
+    let millis = (secs.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// 将章节写为 WebVTT 文件，可作为 HTML5 `<track kind="chapters">` 使用
+pub async fn write_chapters_vtt(chapters: &[Chapter], path: &Path) -> Result<()> {
+    let mut content = String::from("WEBVTT\n");
+    for (i, chapter) in chapters.iter().enumerate() {
+        writeln!(content)?;
+        writeln!(content, "{}", i + 1)?;
+        writeln!(
+            content,
+            "{} --> {}",
+            vtt_timestamp(chapter.start_secs),
+            vtt_timestamp(chapter.end_secs)
+        )?;
+        // 提示文本中不能出现换行，且需转义 & < >
+        let title = chapter
+            .title
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace(['\r', '\n'], " ");
+        writeln!(content, "{}", title)?;
+    }
+    fs::write(path, content).await?;
+    Ok(())
+}
+
+/// XSPF 播放列表中的一个条目
+#[derive(Debug, Clone)]
+pub struct PlaylistEntry {
+    pub title: String,
+    pub location: PathBuf,
+    pub duration_ms: u64,
+}
+
+/// 转义XML文本
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// 将文件路径转换为播放列表中的位置，位于播放列表所在目录下时使用相对路径
+fn playlist_location(path: &Path, base_dir: &Path) -> String {
+    let relative = path.strip_prefix(base_dir).unwrap_or(path);
+    let encoded: Vec<String> = relative
+        .components()
+        .map(|c| urlencoding::encode(&c.as_os_str().to_string_lossy()).into_owned())
+        .collect();
+    let joined = encoded.join("/");
+    if relative.is_absolute() {
+        // 绝对路径使用 file:// URI，编码时根目录分隔符已被转义，这里还原
+        format!(
+            "file:///{}",
+            joined.trim_start_matches("%2F").trim_start_matches('/')
+        )
+    } else {
+        joined
+    }
+}
+
+/// 将下载的文件写入 XSPF 播放列表，可被 VLC、Kodi 等播放器打开
+pub async fn write_xspf_playlist(entries: &[PlaylistEntry], output: &Path) -> Result<()> {
+    let base_dir = output.parent().unwrap_or(Path::new(""));
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+    for entry in entries {
+        writeln!(xml, "    <track>")?;
+        writeln!(
+            xml,
+            "      <location>{}</location>",
+            xml_escape(&playlist_location(&entry.location, base_dir))
+        )?;
+        writeln!(xml, "      <title>{}</title>", xml_escape(&entry.title))?;
+        writeln!(xml, "      <duration>{}</duration>", entry.duration_ms)?;
+        writeln!(xml, "    </track>")?;
+    }
+    xml.push_str("  </trackList>\n</playlist>\n");
+    fs::write(output, xml).await?;
+    Ok(())
+}
+
+/// 将批量下载的输出文件写为 M3U8 媒体播放列表，每个文件作为一个分段
+///
+/// 条目为 `(标题, 路径, 时长秒数)`，位于播放列表所在目录下的文件使用相对路径；
+/// 文件之间插入 `#EXT-X-DISCONTINUITY`。
+pub async fn write_batch_playlist(entries: &[(String, PathBuf, f32)], output: &Path) -> Result<()> {
+    let base_dir = output.parent().unwrap_or(Path::new(""));
+    let target_duration = entries
+        .iter()
+        .map(|(_, _, duration)| duration.ceil() as u64)
+        .max()
+        .unwrap_or(0);
+
+    let mut m3u8 = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", target_duration)?;
+    writeln!(m3u8, "#EXT-X-PLAYLIST-TYPE:VOD")?;
+    for (i, (title, path, duration)) in entries.iter().enumerate() {
+        // 各个文件的时间戳和编码参数互不相关
+        if i > 0 {
+            writeln!(m3u8, "#EXT-X-DISCONTINUITY")?;
+        }
+        // 标题中不能出现换行
+        writeln!(
+            m3u8,
+            "#EXTINF:{:.3},{}",
+            duration,
+            title.replace(['\r', '\n'], " ")
+        )?;
+        writeln!(m3u8, "{}", playlist_location(path, base_dir))?;
+    }
+    m3u8.push_str("#EXT-X-ENDLIST\n");
+    fs::write(output, m3u8).await?;
+    Ok(())
+}
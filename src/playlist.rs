@@ -1,62 +1,1067 @@
 use anyhow::{anyhow, Result};
-use hex;
-use log::info;
-use m3u8_rs::{MediaPlaylist, Playlist};
-use reqwest::Client;
+use encoding_rs::Encoding;
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{debug, info, trace, warn};
+use m3u8_rs::{
+    AlternativeMedia, AlternativeMediaType, ExtTag, KeyMethod, MasterPlaylist, MediaPlaylist,
+    MediaSegment, Playlist, QuotedOrUnquoted, SessionData, VariantStream,
+};
+use rand::Rng;
+use reqwest::header::{
+    CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use url::Url;
 
-#[derive(Debug, Clone)]
+use crate::hooks::run_playlist_hook;
+use crate::http::{fetch_with_retry, retry_with_backoff, CachingClient, RetryPolicy};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KeyInfo {
     pub method: String,
     pub uri: String,
     pub iv: Option<String>,
 }
 
+/// 获取并解析后的媒体播放列表
+#[derive(Debug, Clone)]
+pub struct FetchedPlaylist {
+    pub media_playlist: MediaPlaylist,
+    /// 媒体播放列表跟随重定向后的URL，直播录制时重新获取此URL
+    pub url: Url,
+    /// 用于解析分段相对URL的基础URL
+    pub base_url: Url,
+    /// 每个分段使用的密钥，与 `media_playlist.segments` 一一对应
+    pub segment_keys: Vec<Option<KeyInfo>>,
+    /// 主播放列表中的 `#EXT-X-SESSION-DATA` 条目
+    pub session_data: Vec<SessionData>,
+    /// 所选变体流的 `NAME` 属性（非标准，部分服务器会提供）
+    pub variant_name: Option<String>,
+    /// 所选变体流可用的字幕轨道
+    pub subtitles: Vec<SubtitleRendition>,
+}
+
+/// 主播放列表中的字幕轨道（`#EXT-X-MEDIA:TYPE=SUBTITLES`）
+#[derive(Debug, Clone)]
+pub struct SubtitleRendition {
+    /// 字幕媒体播放列表的绝对URL
+    pub url: Url,
+    pub language: Option<String>,
+    pub name: String,
+    pub default: bool,
+}
+
+/// 低延迟HLS中的部分分段（`#EXT-X-PART`）
+#[derive(Debug, Clone)]
+pub struct PartInfo {
+    pub uri: String,
+    pub duration: f32,
+    pub independent: bool,
+}
+
+/// 播放列表获取选项
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistOptions {
+    /// 解析前用于改写播放列表文本的命令
+    pub playlist_hook: Option<String>,
+    /// 强制使用的播放列表字符集，未设置时按响应的 Content-Type 解码
+    pub encoding: Option<&'static Encoding>,
+    /// 播放列表返回404时持续重试的最长时间，用于尚未开播的直播
+    pub wait_for_playlist: Option<Duration>,
+    /// 每次重新请求播放列表前额外等待的随机时长上限，避免多个实例同步轮询
+    pub refresh_jitter: Duration,
+    /// 直播录制时重新获取播放列表的间隔，未设置时使用目标时长
+    pub live_poll_interval: Option<Duration>,
+    /// 覆盖用于解析分段和密钥相对URL的基础URL，未设置时使用媒体播放列表的最终URL
+    pub base_url: Option<Url>,
+    /// 播放列表响应体的最大字节数，未设置时不限制
+    pub max_size: Option<u64>,
+    /// 解析前从播放列表中删除的标签（不含 `#`，如 `EXT-X-TIMESTAMP`）
+    pub strip_tags: Vec<String>,
+    /// 获取播放列表的重试策略，与分段重试相互独立
+    pub retry_policy: RetryPolicy,
+}
+
+/// 等待播放列表时的重试间隔
+const WAIT_FOR_PLAYLIST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 缓存的播放列表响应头信息
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedHeaders {
+    final_url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    cache_control: Option<String>,
+    #[serde(default)]
+    content_type: Option<String>,
+    fetched_at: u64,
+}
+
+impl CachedHeaders {
+    /// 根据 `Cache-Control: max-age` 判断缓存是否仍然新鲜
+    fn is_fresh(&self, now: u64) -> bool {
+        let Some(cache_control) = &self.cache_control else {
+            return false;
+        };
+        if cache_control_has(cache_control, "no-cache") {
+            return false;
+        }
+        cache_control
+            .split(',')
+            .filter_map(|d| d.trim().strip_prefix("max-age="))
+            .filter_map(|v| v.trim().parse::<u64>().ok())
+            .any(|max_age| self.fetched_at + max_age > now)
+    }
+}
+
+/// 检查 `Cache-Control` 是否包含指定指令
+fn cache_control_has(cache_control: &str, directive: &str) -> bool {
+    cache_control
+        .split(',')
+        .any(|d| d.trim().eq_ignore_ascii_case(directive))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// 返回去掉 `EXT-` 前缀后的标签名，例如 `X-PART`
+pub fn tag_name(tag: &ExtTag) -> &str {
+    tag.tag.trim_start_matches('#').trim_start_matches("EXT-")
+}
+
+/// 解析HLS属性列表，例如 `DURATION=0.333,URI="part1.ts"`，属性值中的引号会被去除
+pub fn parse_attribute_list(input: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = input.trim();
+
+    while !rest.is_empty() {
+        let Some((key, after_key)) = rest.split_once('=') else {
+            break;
+        };
+        let key = key.trim().to_string();
+
+        let (value, after_value) = if let Some(quoted) = after_key.strip_prefix('"') {
+            match quoted.split_once('"') {
+                Some((value, after)) => (value.to_string(), after),
+                None => (quoted.to_string(), ""),
+            }
+        } else {
+            match after_key.split_once(',') {
+                Some((value, after)) => (value.trim().to_string(), after),
+                None => (after_key.trim().to_string(), ""),
+            }
+        };
+
+        attributes.insert(key, value);
+        rest = after_value.trim_start_matches(',').trim_start();
+    }
+
+    attributes
+}
+
+/// 从分段的未知标签中解析 `#EXT-X-PART` 部分分段，按出现顺序返回
+pub fn segment_parts(segment: &MediaSegment) -> Vec<PartInfo> {
+    segment
+        .unknown_tags
+        .iter()
+        .filter(|tag| tag_name(tag) == "X-PART")
+        .filter_map(|tag| {
+            let attributes = parse_attribute_list(tag.rest.as_deref().unwrap_or_default());
+            Some(PartInfo {
+                uri: attributes.get("URI")?.clone(),
+                duration: attributes
+                    .get("DURATION")
+                    .and_then(|d| d.parse().ok())
+                    .unwrap_or_default(),
+                independent: attributes.get("INDEPENDENT").is_some_and(|v| v == "YES"),
+            })
+        })
+        .collect()
+}
+
+/// 读取播放列表中的 `#EXT-X-TITLE` 标签
+pub fn playlist_title(playlist: &MediaPlaylist) -> Option<String> {
+    playlist
+        .unknown_tags
+        .iter()
+        .chain(playlist.segments.iter().flat_map(|s| s.unknown_tags.iter()))
+        .find(|tag| tag_name(tag) == "X-TITLE")
+        .and_then(|tag| tag.rest.as_deref())
+        .map(|title| title.trim().trim_matches('"').trim().to_string())
+        .filter(|title| !title.is_empty())
+}
+
+/// 是否为只含图像分段的播放列表（`#EXT-X-IMAGES-ONLY`），常见于拖动预览的缩略图轨道
+pub fn is_images_only(playlist: &MediaPlaylist) -> bool {
+    playlist
+        .unknown_tags
+        .iter()
+        .chain(playlist.segments.iter().flat_map(|s| s.unknown_tags.iter()))
+        .any(|tag| tag_name(tag) == "X-IMAGES-ONLY")
+}
+
+/// 图像分段的拼图布局（`#EXT-X-TILES`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileLayout {
+    /// 单张缩略图的 [宽, 高]
+    pub resolution: [u32; 2],
+    /// 拼图的 [列数, 行数]
+    pub layout: [u32; 2],
+}
+
+/// 解析分段上的 `#EXT-X-TILES` 标签，如 `RESOLUTION=416x234,LAYOUT=5x5,DURATION=2.0`
+pub fn segment_tile_layout(segment: &MediaSegment) -> Option<TileLayout> {
+    let tag = segment
+        .unknown_tags
+        .iter()
+        .find(|tag| tag_name(tag) == "X-TILES")?;
+    let attributes = parse_attribute_list(tag.rest.as_deref().unwrap_or_default());
+    let dimensions = |key: &str| -> Option<[u32; 2]> {
+        let (a, b) = attributes.get(key)?.split_once(['x', 'X'])?;
+        Some([a.trim().parse().ok()?, b.trim().parse().ok()?])
+    };
+    Some(TileLayout {
+        resolution: dimensions("RESOLUTION")?,
+        layout: dimensions("LAYOUT")?,
+    })
+}
+
+/// 删除以指定标签开头的行，标签名须完整匹配（`EXT-X-FOO` 不会删除 `#EXT-X-FOO-BAR`）
+pub fn strip_extension_tags(content: &str, tags: &[String]) -> String {
+    let is_stripped = |line: &str| {
+        let Some(rest) = line.trim_start().strip_prefix('#') else {
+            return false;
+        };
+        tags.iter().any(|tag| {
+            rest.strip_prefix(tag.trim_start_matches('#'))
+                .is_some_and(|after| after.is_empty() || after.starts_with([':', ' ', '\t', '\r']))
+        })
+    };
+
+    let mut stripped = 0;
+    let mut result = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        if is_stripped(line.trim_end_matches(['\r', '\n'])) {
+            stripped += 1;
+        } else {
+            result.push_str(line);
+        }
+    }
+    if stripped > 0 {
+        debug!("Stripped {} tag lines from the playlist", stripped);
+    }
+    result
+}
+
+/// 按 `#EXT-X-DISCONTINUITY` 将分段划分为连续的分组，返回每组的索引范围
+pub fn discontinuity_groups(segments: &[MediaSegment]) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for (i, segment) in segments.iter().enumerate().skip(1) {
+        if segment.discontinuity {
+            groups.push(start..i);
+            start = i;
+        }
+    }
+    if start < segments.len() {
+        groups.push(start..segments.len());
+    }
+    groups
+}
+
+/// 相邻分段之间的时间间隙
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentGap {
+    /// 间隙之后的分段索引
+    pub index: usize,
+    /// 间隙时长（秒）
+    pub gap_secs: f64,
+}
+
+/// 按 `#EXT-X-PROGRAM-DATE-TIME` 查找超过 `max_gap` 秒的分段间隙
+///
+/// 分段的开始时间应等于上一个分段的开始时间加上其时长，实际差值超出 `max_gap` 即视为间隙；
+/// 任一分段缺少节目时间时跳过该处。
+pub fn find_segment_gaps(segments: &[MediaSegment], max_gap: f64) -> Vec<SegmentGap> {
+    segments
+        .windows(2)
+        .enumerate()
+        .filter_map(|(i, pair)| {
+            let start = pair[0].program_date_time?;
+            let next = pair[1].program_date_time?;
+            let expected_secs = pair[0].duration as f64;
+            let actual_secs = (next - start).num_milliseconds() as f64 / 1000.0;
+            let gap_secs = actual_secs - expected_secs;
+            (gap_secs > max_gap).then_some(SegmentGap {
+                index: i + 1,
+                gap_secs,
+            })
+        })
+        .collect()
+}
+
+/// 在播放列表URL上添加 `_HLS_skip=YES`，请求服务器返回增量更新
+pub fn delta_update_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.query_pairs_mut().append_pair("_HLS_skip", "YES");
+    url
+}
+
+/// 移除播放列表中的 `#EXT-X-SKIP` 标签，返回其 `SKIPPED-SEGMENTS` 数量
+fn take_skipped_segments(playlist: &mut MediaPlaylist) -> u64 {
+    let mut skipped = 0;
+    let tags = std::iter::once(&mut playlist.unknown_tags)
+        .chain(playlist.segments.iter_mut().map(|s| &mut s.unknown_tags));
+    for tags in tags {
+        tags.retain(|tag| {
+            if tag_name(tag) != "X-SKIP" {
+                return true;
+            }
+            let attributes = parse_attribute_list(tag.rest.as_deref().unwrap_or_default());
+            skipped = attributes
+                .get("SKIPPED-SEGMENTS")
+                .and_then(|n| n.parse().ok())
+                .unwrap_or_default();
+            false
+        });
+    }
+    skipped
+}
+
+/// 直播模式下反复获取的媒体播放列表的状态
+///
+/// 低延迟HLS的增量更新（`#EXT-X-SKIP:SKIPPED-SEGMENTS=N`）省略了开头的 N 个分段，
+/// 这里缓存上一次的完整分段列表，用于将增量更新还原为完整的播放列表。
+#[derive(Debug, Default)]
+pub struct LivePlaylistState {
+    /// `segments` 中第一个分段的媒体序列号
+    first_sequence: u64,
+    segments: VecDeque<MediaSegment>,
+    /// 服务器在 `#EXT-X-SERVER-CONTROL` 中声明了 `CAN-SKIP-UNTIL`，可以请求增量更新
+    can_skip: bool,
+}
+
+/// 直播播放列表重新获取后新出现的分段
+#[derive(Debug, Default)]
+pub struct LiveUpdate {
+    pub segments: Vec<MediaSegment>,
+    /// 每个新分段使用的密钥，与 `segments` 一一对应
+    pub segment_keys: Vec<Option<KeyInfo>>,
+    /// 第一个新分段的媒体序列号
+    pub first_sequence: u64,
+    pub target_duration: u64,
+    /// 播放列表已出现 `#EXT-X-ENDLIST`，直播结束
+    pub end_list: bool,
+}
+
+impl LivePlaylistState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 用新获取的播放列表更新状态；若为增量更新，则将省略的分段补回 `playlist`
+    pub fn apply_update(&mut self, playlist: &mut MediaPlaylist) -> Result<()> {
+        let skipped = take_skipped_segments(playlist);
+        let sequence = playlist.media_sequence;
+        self.can_skip = playlist.unknown_tags.iter().any(|tag| {
+            tag_name(tag) == "X-SERVER-CONTROL"
+                && parse_attribute_list(tag.rest.as_deref().unwrap_or_default())
+                    .contains_key("CAN-SKIP-UNTIL")
+        });
+
+        // 两次获取之间滑出窗口的分段再也无法下载
+        let next_sequence = self.first_sequence + self.segments.len() as u64;
+        if !self.segments.is_empty() && sequence > next_sequence {
+            let missed = sequence - next_sequence;
+            warn!(
+                "{} segments (about {}s) left the live window before they were fetched; the recording has a gap.",
+                missed,
+                missed * playlist.target_duration
+            );
+        }
+
+        if skipped == 0 {
+            self.first_sequence = sequence;
+            self.segments = playlist.segments.iter().cloned().collect();
+            return Ok(());
+        }
+
+        // 丢弃已滑出播放列表窗口的分段
+        while self.first_sequence < sequence && self.segments.pop_front().is_some() {
+            self.first_sequence += 1;
+        }
+        if self.first_sequence != sequence || (self.segments.len() as u64) < skipped {
+            return Err(anyhow!(
+                "#EXT-X-SKIP omitted {} segments from media sequence {}, but only {} cached segments start at {}",
+                skipped,
+                sequence,
+                self.segments.len(),
+                self.first_sequence
+            ));
+        }
+
+        self.segments.truncate(skipped as usize);
+        self.segments.extend(playlist.segments.drain(..));
+        playlist.segments = self.segments.iter().cloned().collect();
+        debug!(
+            "Restored {} skipped segments from the delta playlist update",
+            skipped
+        );
+        Ok(())
+    }
+
+    /// 在后台任务中按间隔重新获取直播播放列表，最多提前缓存 `prefetch` 次更新
+    ///
+    /// 更新在当前批次的分段下载期间获取，下载完成后即可从通道中取出，省去一次播放列表请求的延迟。
+    /// 任务在直播结束、获取失败或接收端被丢弃后退出。
+    pub fn spawn_reloader(
+        mut self,
+        client: Arc<CachingClient>,
+        url: Url,
+        mut next_sequence: u64,
+        options: PlaylistOptions,
+        mut target_duration: u64,
+        prefetch: usize,
+    ) -> (mpsc::Receiver<Result<LiveUpdate>>, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(prefetch.max(1));
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(live_poll_delay(target_duration, &options)).await;
+                let update = self
+                    .reload(client.clone(), &url, next_sequence, &options)
+                    .await;
+                let done = match &update {
+                    Ok(update) => {
+                        next_sequence = update.first_sequence + update.segments.len() as u64;
+                        target_duration = update.target_duration;
+                        update.end_list
+                    }
+                    Err(_) => true,
+                };
+                if sender.send(update).await.is_err() || done {
+                    break;
+                }
+            }
+        });
+        (receiver, task)
+    }
+
+    /// 重新获取直播媒体播放列表，返回媒体序列号不小于 `next_sequence` 的分段
+    ///
+    /// 上一次获取的播放列表允许跳过分段时请求增量更新，省略的分段由缓存补回。
+    pub async fn reload(
+        &mut self,
+        client: Arc<CachingClient>,
+        url: &Url,
+        next_sequence: u64,
+        options: &PlaylistOptions,
+    ) -> Result<LiveUpdate> {
+        let url = if self.can_skip {
+            delta_update_url(url)
+        } else {
+            url.clone()
+        };
+        let mut playlist = fetch_and_parse_playlist(client, url, options)
+            .await?
+            .media_playlist;
+        self.apply_update(&mut playlist)?;
+
+        let keys = segment_keys(&playlist.segments);
+        let skip = next_sequence.saturating_sub(playlist.media_sequence) as usize;
+        Ok(LiveUpdate {
+            first_sequence: playlist.media_sequence + skip as u64,
+            segment_keys: keys.into_iter().skip(skip).collect(),
+            segments: playlist.segments.into_iter().skip(skip).collect(),
+            target_duration: playlist.target_duration,
+            end_list: playlist.end_list,
+        })
+    }
+}
+
+/// URL路径以 `.mpd` 结尾时视为 MPEG-DASH 清单
+pub fn is_mpd_url(url: &Url) -> bool {
+    url.path().to_ascii_lowercase().ends_with(".mpd")
+}
+
 /// 获取并解析M3U8播放列表
 pub async fn fetch_and_parse_playlist(
-    client: Arc<Client>,
+    client: Arc<CachingClient>,
     url: Url,
-) -> Result<(MediaPlaylist, Url, Option<KeyInfo>)> {
-    info!("Fetching playlist from {}", url);
+    options: &PlaylistOptions,
+) -> Result<FetchedPlaylist> {
+    fetch_playlist_waiting(client, url, None, options).await
+}
 
-    let response = client.get(url.clone()).send().await?.error_for_status()?;
-    let final_url = response.url().clone();
-    let content = response.text().await?;
+/// 获取并解析M3U8播放列表，使用本地缓存和条件请求避免重复下载
+///
+/// 播放列表内容保存在 `cache_dir/<url_hash>.m3u8`，响应头保存在 `cache_dir/<url_hash>.headers.json`。
+pub async fn fetch_with_cache(
+    client: Arc<CachingClient>,
+    url: Url,
+    cache_dir: &Path,
+    options: &PlaylistOptions,
+) -> Result<FetchedPlaylist> {
+    fs::create_dir_all(cache_dir).await?;
+    fetch_playlist_waiting(client, url, Some(cache_dir), options).await
+}
+
+/// 获取播放列表，若设置了等待时间，则在返回404期间每隔5秒（加上随机抖动）重试一次
+async fn fetch_playlist_waiting(
+    client: Arc<CachingClient>,
+    url: Url,
+    cache_dir: Option<&Path>,
+    options: &PlaylistOptions,
+) -> Result<FetchedPlaylist> {
+    let Some(wait) = options.wait_for_playlist else {
+        return fetch_playlist(client, url, cache_dir, options).await;
+    };
 
-    let playlist = m3u8_rs::parse_playlist_res(content.as_bytes())
+    let started = Instant::now();
+    let mut attempt = 1;
+    loop {
+        let delay = WAIT_FOR_PLAYLIST_INTERVAL + refresh_jitter(options.refresh_jitter);
+        match fetch_playlist(client.clone(), url.clone(), cache_dir, options).await {
+            Err(e) if is_not_found(&e) && started.elapsed() + delay <= wait => {
+                info!(
+                    "Playlist not available yet (attempt {}, {}s of {}s elapsed), retrying in {:.1}s...",
+                    attempt,
+                    started.elapsed().as_secs(),
+                    wait.as_secs(),
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// 直播录制时两次获取播放列表之间的等待时间，未指定间隔时使用目标时长，并加上随机抖动
+pub fn live_poll_delay(target_duration: u64, options: &PlaylistOptions) -> Duration {
+    options
+        .live_poll_interval
+        .unwrap_or(Duration::from_secs(target_duration.max(1)))
+        + refresh_jitter(options.refresh_jitter)
+}
+
+/// 在 `[0, max]` 内均匀取一个随机时长
+fn refresh_jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..=max.as_secs_f64()))
+}
+
+/// 判断错误是否为HTTP 404
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|status| status == StatusCode::NOT_FOUND)
+}
+
+async fn fetch_playlist(
+    client: Arc<CachingClient>,
+    url: Url,
+    cache_dir: Option<&Path>,
+    options: &PlaylistOptions,
+) -> Result<FetchedPlaylist> {
+    let (final_url, content) = fetch_playlist_text(&client, &url, cache_dir, options).await?;
+
+    let playlist = m3u8_rs::parse_playlist_res(&content)
         .map_err(|e| anyhow!("Failed to parse M3U8 playlist: {}", e))?;
 
     match playlist {
         Playlist::MasterPlaylist(pl) => {
-            info!("Master playlist found with {} variants.", pl.variants.len());
+            let best_variant = select_variant(&pl)?;
+            let media_playlist_url = final_url.join(&best_variant.uri)?;
+
+            let mut fetched = Box::pin(fetch_playlist(
+                client,
+                media_playlist_url,
+                cache_dir,
+                options,
+            ))
+            .await?;
+            fetched.session_data = pl.session_data.clone();
+            fetched.variant_name = variant_name(best_variant).or(fetched.variant_name);
+            fetched.subtitles = subtitle_renditions(&pl.alternatives, best_variant, &final_url);
+            Ok(fetched)
+        }
+        Playlist::MediaPlaylist(pl) => {
+            info!("Media playlist found.");
+            let segment_keys = segment_keys(&pl.segments);
+            Ok(media_fetched_playlist(pl, segment_keys, final_url, options))
+        }
+    }
+}
+
+/// 选择带宽最高的变体流
+fn select_variant(playlist: &MasterPlaylist) -> Result<&VariantStream> {
+    info!(
+        "Master playlist found with {} variants.",
+        playlist.variants.len()
+    );
+    let best_variant = playlist
+        .variants
+        .iter()
+        .max_by_key(|v| v.bandwidth)
+        .ok_or_else(|| anyhow!("No variants found in master playlist"))?;
+    info!(
+        "Selected variant with bandwidth: {}",
+        best_variant.bandwidth
+    );
+    Ok(best_variant)
+}
+
+/// 以媒体播放列表的最终URL构造获取结果，主播放列表中的信息由调用方补充
+fn media_fetched_playlist(
+    media_playlist: MediaPlaylist,
+    segment_keys: Vec<Option<KeyInfo>>,
+    url: Url,
+    options: &PlaylistOptions,
+) -> FetchedPlaylist {
+    let base_url = options.base_url.clone().unwrap_or(url.clone());
+    info!("Resolving segment URIs against: {}", base_url);
+    FetchedPlaylist {
+        media_playlist,
+        url,
+        base_url,
+        segment_keys,
+        session_data: Vec::new(),
+        variant_name: None,
+        subtitles: Vec::new(),
+    }
+}
+
+/// 逐个产出分段的媒体播放列表，见 [`fetch_segments_lazy`]
+pub struct LazyPlaylist {
+    /// 播放列表信息；`media_playlist` 只含播放列表级别的标签，`segments` 和 `segment_keys` 为空
+    pub fetched: FetchedPlaylist,
+    /// 按播放列表顺序产出的分段
+    pub segments: BoxStream<'static, Result<MediaSegment>>,
+}
+
+/// 逐个产出分段时通道中缓冲的分段数
+const LAZY_SEGMENT_BUFFER: usize = 64;
+
+/// 属于整个播放列表而非某个分段的标签
+const PLAYLIST_LEVEL_TAGS: [&str; 10] = [
+    "#EXTM3U",
+    "#EXT-X-VERSION",
+    "#EXT-X-TARGETDURATION",
+    "#EXT-X-MEDIA-SEQUENCE",
+    "#EXT-X-DISCONTINUITY-SEQUENCE",
+    "#EXT-X-ENDLIST",
+    "#EXT-X-PLAYLIST-TYPE",
+    "#EXT-X-I-FRAMES-ONLY",
+    "#EXT-X-INDEPENDENT-SEGMENTS",
+    "#EXT-X-START",
+];
+
+fn is_playlist_level_tag(line: &str) -> bool {
+    PLAYLIST_LEVEL_TAGS.iter().any(|tag| {
+        line.strip_prefix(tag)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(':'))
+    })
+}
+
+/// 获取媒体播放列表，通过有界通道逐个解析并产出分段，供数万个分段的多日录制使用
+///
+/// 播放列表只获取一次，播放列表级别的标签先行解析；每个分段连同其前面的标签单独交给
+/// `m3u8_rs` 解析，消费者取走前一个分段后才解析下一个，因此不会同时持有完整的分段列表。
+/// 分段上的 `key` 与整体解析时相同，只记在密钥标签之后的第一个分段上。
+/// 不经过 `--playlist-cache-dir`，也不等待尚未开播的直播。
+pub async fn fetch_segments_lazy(
+    client: Arc<CachingClient>,
+    url: Url,
+    options: &PlaylistOptions,
+) -> Result<LazyPlaylist> {
+    let (final_url, content) = fetch_playlist_text(&client, &url, None, options).await?;
+    let text = String::from_utf8_lossy(&content).into_owned();
+    drop(content);
+
+    let is_master = text.lines().any(|line| {
+        let line = line.trim_start();
+        line.starts_with("#EXT-X-STREAM-INF") || line.starts_with("#EXT-X-I-FRAME-STREAM-INF")
+    });
+    if is_master {
+        let master = m3u8_rs::parse_master_playlist_res(text.as_bytes())
+            .map_err(|e| anyhow!("Failed to parse M3U8 playlist: {}", e))?;
+        let best_variant = select_variant(&master)?;
+        let media_playlist_url = final_url.join(&best_variant.uri)?;
+        let mut lazy = Box::pin(fetch_segments_lazy(client, media_playlist_url, options)).await?;
+        lazy.fetched.session_data = master.session_data.clone();
+        lazy.fetched.variant_name = variant_name(best_variant).or(lazy.fetched.variant_name);
+        lazy.fetched.subtitles =
+            subtitle_renditions(&master.alternatives, best_variant, &final_url);
+        return Ok(lazy);
+    }
+
+    info!("Media playlist found, parsing segments as they are downloaded.");
+    let header: String = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| is_playlist_level_tag(line))
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    let media_playlist = m3u8_rs::parse_media_playlist_res(header.as_bytes())
+        .map_err(|e| anyhow!("Failed to parse M3U8 playlist: {}", e))?;
+    // 每个分段前附加的标签，`#EXT-X-ENDLIST` 只出现在播放列表末尾
+    let segment_header: String = header
+        .lines()
+        .filter(|line| !line.starts_with("#EXT-X-ENDLIST"))
+        .flat_map(|line| [line, "\n"])
+        .collect();
+
+    let (sender, receiver) = mpsc::channel(LAZY_SEGMENT_BUFFER);
+    tokio::spawn(async move {
+        let mut block = String::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || is_playlist_level_tag(line) {
+                continue;
+            }
+            block.push_str(line);
+            block.push('\n');
+            // 分段URI结束一个分段的标签块
+            if line.starts_with('#') {
+                continue;
+            }
+            let segment = parse_segment_block(&segment_header, &block);
+            block.clear();
+            let failed = segment.is_err();
+            // 接收端已关闭，说明消费者不再需要后续分段
+            if sender.send(segment).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let segments = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+    .boxed();
+
+    Ok(LazyPlaylist {
+        fetched: media_fetched_playlist(media_playlist, Vec::new(), final_url, options),
+        segments,
+    })
+}
+
+/// 解析播放列表级别的标签加上一个分段的标签块，返回其中的分段
+fn parse_segment_block(header: &str, block: &str) -> Result<MediaSegment> {
+    let text = format!("{}{}", header, block);
+    m3u8_rs::parse_media_playlist_res(text.as_bytes())
+        .map_err(|e| anyhow!("Failed to parse M3U8 segment {:?}: {}", block.trim(), e))?
+        .segments
+        .pop()
+        .ok_or_else(|| anyhow!("No segment found in {:?}", block.trim()))
+}
 
-            let best_variant = pl
-                .variants
-                .iter()
-                .max_by_key(|v| v.bandwidth)
-                .ok_or_else(|| anyhow!("No variants found in master playlist"))?;
+/// 下载播放列表文本，按需转换字符集、交给钩子改写并删除指定的标签
+async fn fetch_playlist_text(
+    client: &CachingClient,
+    url: &Url,
+    cache_dir: Option<&Path>,
+    options: &PlaylistOptions,
+) -> Result<(Url, Vec<u8>)> {
+    info!("Fetching playlist from {}", url);
+
+    let (final_url, mut content) = match cache_dir {
+        Some(dir) => {
+            retry_with_backoff(&options.retry_policy, url, || {
+                fetch_cached_content(
+                    client,
+                    url,
+                    dir,
+                    options.encoding.is_some(),
+                    options.max_size,
+                )
+            })
+            .await?
+        }
+        None => fetch_content(client, url, options.encoding.is_some(), options).await?,
+    };
 
-            info!(
-                "Selected variant with bandwidth: {}",
-                best_variant.bandwidth
+    // 按指定字符集将非UTF-8的播放列表转为UTF-8
+    if let Some(encoding) = options.encoding {
+        let (text, _, had_errors) = encoding.decode(&content);
+        if had_errors {
+            warn!(
+                "Playlist contains bytes that are invalid in {}; they were replaced.",
+                encoding.name()
             );
+        }
+        content = text.into_owned().into_bytes();
+    }
 
-            let media_playlist_url = final_url.join(&best_variant.uri)?;
+    // 通过用户命令改写非标准的播放列表
+    if let Some(hook) = &options.playlist_hook {
+        let original = String::from_utf8_lossy(&content).to_string();
+        trace!("Original playlist:\n{}", original);
+        let rewritten = run_playlist_hook(hook, &original)
+            .await
+            .map_err(|e| anyhow!("Playlist hook failed: {}", e))?;
+        trace!("Rewritten playlist:\n{}", rewritten);
+        content = rewritten.into_bytes();
+    }
+
+    if !options.strip_tags.is_empty() {
+        let original = String::from_utf8_lossy(&content);
+        content = strip_extension_tags(&original, &options.strip_tags).into_bytes();
+    }
+
+    Ok((final_url, content))
+}
+
+/// 收集所选变体流 `SUBTITLES` 组中带有URI的字幕轨道，变体流未指定组时收集全部字幕轨道
+fn subtitle_renditions(
+    alternatives: &[AlternativeMedia],
+    variant: &VariantStream,
+    base_url: &Url,
+) -> Vec<SubtitleRendition> {
+    alternatives
+        .iter()
+        .filter(|media| media.media_type == AlternativeMediaType::Subtitles)
+        .filter(|media| {
+            variant
+                .subtitles
+                .as_ref()
+                .is_none_or(|group| *group == media.group_id)
+        })
+        .filter_map(|media| {
+            Some(SubtitleRendition {
+                url: base_url.join(media.uri.as_deref()?).ok()?,
+                language: media.language.clone(),
+                name: media.name.clone(),
+                default: media.default,
+            })
+        })
+        .collect()
+}
+
+/// 按语言选择字幕轨道，`en` 可匹配 `en-US`；未指定语言时选择默认轨道，没有默认轨道时选择第一个
+pub fn select_subtitle<'a>(
+    subtitles: &'a [SubtitleRendition],
+    language: Option<&str>,
+) -> Option<&'a SubtitleRendition> {
+    match language {
+        Some(wanted) => subtitles.iter().find(|s| {
+            s.language.as_deref().is_some_and(|lang| {
+                lang.eq_ignore_ascii_case(wanted)
+                    || lang
+                        .split('-')
+                        .next()
+                        .is_some_and(|primary| primary.eq_ignore_ascii_case(wanted))
+            })
+        }),
+        None => subtitles
+            .iter()
+            .find(|s| s.default)
+            .or_else(|| subtitles.first()),
+    }
+}
+
+/// 按顺序跟踪 `#EXT-X-KEY`，返回每个分段使用的密钥
+///
+/// `m3u8_rs` 只把密钥标签记在其后的第一个分段上，之后的分段沿用最近一次出现的密钥，
+/// `METHOD=NONE` 清除之前的密钥。
+pub fn segment_keys(segments: &[MediaSegment]) -> Vec<Option<KeyInfo>> {
+    let mut current = None;
+    segments
+        .iter()
+        .map(|segment| next_segment_key(&mut current, segment))
+        .collect()
+}
+
+/// [`segment_keys`] 的单步版本：用分段上的密钥标签更新 `current`，返回该分段使用的密钥
+pub fn next_segment_key(current: &mut Option<KeyInfo>, segment: &MediaSegment) -> Option<KeyInfo> {
+    if let Some(key) = &segment.key {
+        *current = (key.method != KeyMethod::None).then(|| KeyInfo {
+            method: key.method.to_string(),
+            uri: key.uri.clone().unwrap_or_default(),
+            iv: key.iv.clone(),
+        });
+    }
+    current.clone()
+}
+
+/// 读取变体流的 `NAME` 属性
+fn variant_name(variant: &VariantStream) -> Option<String> {
+    let value = match variant.other_attributes.as_ref()?.get("NAME")? {
+        QuotedOrUnquoted::Quoted(s) | QuotedOrUnquoted::Unquoted(s) => s.trim(),
+    };
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// 直接下载播放列表内容
+///
+/// `raw` 为真时返回未解码的原始字节，由调用方按指定字符集解码。
+/// 播放列表不经过 `--http-cache-dir` 缓存，直播重新获取时总能拿到最新内容。
+pub(crate) async fn fetch_content(
+    client: &CachingClient,
+    url: &Url,
+    raw: bool,
+    options: &PlaylistOptions,
+) -> Result<(Url, Vec<u8>)> {
+    let max_size = options.max_size;
+    let response = fetch_with_retry(client, url, &options.retry_policy).await?;
+    let final_url = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body = read_limited_body(response, max_size).await?;
+    Ok((final_url, decode_body(body, content_type.as_deref(), raw)))
+}
+
+/// 与 `Response::text` 相同：按 Content-Type 中的字符集解码，默认 UTF-8
+///
+/// `raw` 为真时原样返回。
+fn decode_body(body: Vec<u8>, content_type: Option<&str>, raw: bool) -> Vec<u8> {
+    if raw {
+        return body;
+    }
+    let encoding = content_type
+        .and_then(|v| {
+            v.split(';')
+                .find_map(|param| param.trim().strip_prefix("charset="))
+                .and_then(|charset| Encoding::for_label(charset.trim_matches('"').as_bytes()))
+        })
+        .unwrap_or(encoding_rs::UTF_8);
+    encoding.decode(&body).0.into_owned().into_bytes()
+}
+
+/// 播放列表超过大小限制时返回错误
+fn check_playlist_size(received: u64, max_size: Option<u64>) -> Result<()> {
+    match max_size {
+        Some(limit) if received > limit => Err(anyhow!(
+            "Playlist size exceeds limit; received {} bytes, limit is {} bytes",
+            received,
+            limit
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// 读取响应体，超过 `max_size` 时立即停止读取并返回错误
+async fn read_limited_body(
+    mut response: reqwest::Response,
+    max_size: Option<u64>,
+) -> Result<Vec<u8>> {
+    if let Some(length) = response.content_length() {
+        check_playlist_size(length, max_size)?;
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        check_playlist_size(body.len() as u64, max_size)?;
+    }
+    Ok(body)
+}
 
-            Box::pin(fetch_and_parse_playlist(client, media_playlist_url)).await
+/// 通过本地缓存下载播放列表内容
+///
+/// 缓存中保存原始字节，返回前与 [`fetch_content`] 一样按字符集解码并检查大小。
+async fn fetch_cached_content(
+    client: &CachingClient,
+    url: &Url,
+    cache_dir: &Path,
+    raw: bool,
+    max_size: Option<u64>,
+) -> Result<(Url, Vec<u8>)> {
+    let url_hash = &sha256::digest(url.as_str())[..12];
+    let body_path = cache_dir.join(format!("{}.m3u8", url_hash));
+    let headers_path = cache_dir.join(format!("{}.headers.json", url_hash));
+
+    // 读取已有的缓存条目
+    let cached = match (fs::read(&body_path).await, fs::read(&headers_path).await) {
+        (Ok(body), Ok(headers)) => serde_json::from_slice::<CachedHeaders>(&headers)
+            .ok()
+            .map(|h| (body, h)),
+        _ => None,
+    };
+
+    let mut request = client.get(url.clone());
+    if let Some((body, headers)) = &cached {
+        if headers.is_fresh(unix_now()) {
+            info!("Serving playlist from cache: {:?}", body_path);
+            check_playlist_size(body.len() as u64, max_size)?;
+            return Ok((
+                Url::parse(&headers.final_url)?,
+                decode_body(body.clone(), headers.content_type.as_deref(), raw),
+            ));
         }
-        Playlist::MediaPlaylist(pl) => {
-            info!("Media playlist found.");
-            let key_info = pl.segments.iter().find_map(|s| s.key.as_ref()).map(|k| {
-                let uri = k.uri.clone().unwrap_or_default();
-                KeyInfo {
-                    method: k.method.to_string(),
-                    uri,
-                    iv: k.iv.as_ref().map(hex::encode),
-                }
-            });
-            Ok((pl, final_url, key_info))
+        if let Some(etag) = &headers.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &headers.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().await?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some((body, mut headers)) = cached {
+            info!("Playlist not modified, serving from cache: {:?}", body_path);
+            headers.fetched_at = unix_now();
+            fs::write(&headers_path, serde_json::to_vec_pretty(&headers)?).await?;
+            check_playlist_size(body.len() as u64, max_size)?;
+            return Ok((
+                Url::parse(&headers.final_url)?,
+                decode_body(body, headers.content_type.as_deref(), raw),
+            ));
         }
     }
+
+    let response = response.error_for_status()?;
+    let final_url = response.url().clone();
+    let header_value = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let headers = CachedHeaders {
+        final_url: final_url.to_string(),
+        etag: header_value(ETAG),
+        last_modified: header_value(LAST_MODIFIED),
+        cache_control: header_value(CACHE_CONTROL),
+        content_type: header_value(CONTENT_TYPE),
+        fetched_at: unix_now(),
+    };
+    let content = read_limited_body(response, max_size).await?;
+
+    let no_store = headers
+        .cache_control
+        .as_deref()
+        .is_some_and(|cc| cache_control_has(cc, "no-store"));
+    if no_store {
+        debug!("Playlist response has Cache-Control: no-store, not caching.");
+    } else {
+        fs::write(&body_path, &content).await?;
+        fs::write(&headers_path, serde_json::to_vec_pretty(&headers)?).await?;
+        debug!("Cached playlist at {:?}", body_path);
+    }
+
+    Ok((
+        final_url,
+        decode_body(content, headers.content_type.as_deref(), raw),
+    ))
 }
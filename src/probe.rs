@@ -0,0 +1,335 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::util::ts_duration_secs;
+
+/// 音频时长与播放列表时长允许的最大相对误差
+const AUDIO_DURATION_TOLERANCE: f64 = 0.05;
+
+/// `ffprobe -show_entries stream=codec_type,codec_name,duration,width,height` 输出的单个流
+#[derive(Debug, Deserialize)]
+pub struct ProbeStream {
+    pub codec_type: Option<String>,
+    pub codec_name: Option<String>,
+    /// ffprobe 以字符串形式输出时长
+    pub duration: Option<String>,
+    /// 视频流的分辨率
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl ProbeStream {
+    /// 流时长（秒）
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.duration.as_deref()?.parse().ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+}
+
+/// `ffprobe -show_format` 输出的容器信息
+#[derive(Debug, Deserialize)]
+pub struct MediaFormat {
+    pub format_name: Option<String>,
+    pub bit_rate: Option<String>,
+}
+
+/// `ffprobe -show_streams` 输出的单个流，只保留 `--list-media-info` 显示的字段
+#[derive(Debug, Deserialize)]
+pub struct MediaStream {
+    pub index: u32,
+    pub codec_type: Option<String>,
+    pub codec_name: Option<String>,
+    pub profile: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// 形如 `30000/1001` 的平均帧率
+    pub avg_frame_rate: Option<String>,
+    pub sample_rate: Option<String>,
+    pub channels: Option<u32>,
+    pub bit_rate: Option<String>,
+}
+
+/// 一个分段的容器和各个流的信息
+#[derive(Debug, Deserialize)]
+pub struct MediaInfo {
+    pub format: Option<MediaFormat>,
+    #[serde(default)]
+    pub streams: Vec<MediaStream>,
+}
+
+impl MediaInfo {
+    /// 解析 `ffprobe -show_streams -show_format -of json` 的输出
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| anyhow!("Invalid ffprobe output: {}", e))
+    }
+}
+
+/// 将 ffprobe 以字符串输出的比特率格式化为 kb/s
+fn format_bit_rate(bit_rate: Option<&str>) -> Option<String> {
+    let bits: f64 = bit_rate?.parse().ok()?;
+    Some(format!("{:.0} kb/s", bits / 1000.0))
+}
+
+/// 将 `30000/1001` 形式的帧率格式化为 fps
+fn format_frame_rate(rate: Option<&str>) -> Option<String> {
+    let (num, den) = rate?.split_once('/')?;
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    (num > 0.0 && den > 0.0).then(|| format!("{:.2} fps", num / den))
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(format) = &self.format {
+            write!(
+                f,
+                "Container: {}",
+                format.format_name.as_deref().unwrap_or("unknown")
+            )?;
+            if let Some(bit_rate) = format_bit_rate(format.bit_rate.as_deref()) {
+                write!(f, ", {}", bit_rate)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "{:<3} {:<9} {:<16} Details", "#", "Type", "Codec")?;
+        for stream in &self.streams {
+            let codec = match (&stream.codec_name, &stream.profile) {
+                (Some(name), Some(profile)) => format!("{} ({})", name, profile),
+                (Some(name), None) => name.clone(),
+                (None, _) => "unknown".to_string(),
+            };
+            let mut details = Vec::new();
+            if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                details.push(format!("{}x{}", width, height));
+            }
+            details.extend(format_frame_rate(stream.avg_frame_rate.as_deref()));
+            if let Some(sample_rate) = &stream.sample_rate {
+                details.push(format!("{} Hz", sample_rate));
+            }
+            if let Some(channels) = stream.channels {
+                details.push(format!("{} channels", channels));
+            }
+            details.extend(format_bit_rate(stream.bit_rate.as_deref()));
+            let line = format!(
+                "{:<3} {:<9} {:<16} {}",
+                stream.index,
+                stream.codec_type.as_deref().unwrap_or("unknown"),
+                codec,
+                details.join(", ")
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// `ffprobe -show_frames` 输出的单个帧
+#[derive(Debug, Deserialize)]
+struct ProbeFrame {
+    key_frame: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FramesOutput {
+    #[serde(default)]
+    frames: Vec<ProbeFrame>,
+}
+
+/// 推导 ffprobe 路径：若指定了 ffmpeg 路径，则使用其同目录下的 ffprobe
+pub fn ffprobe_path(ffmpeg_path: Option<&Path>) -> PathBuf {
+    let name = if cfg!(target_os = "windows") {
+        "ffprobe.exe"
+    } else {
+        "ffprobe"
+    };
+    match ffmpeg_path.and_then(Path::parent) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.join(name),
+        _ => PathBuf::from(name),
+    }
+}
+
+/// 读取分段时长：MPEG-TS 分段直接解析 PTS，其余情况使用 ffprobe
+pub async fn segment_duration_secs(ffprobe: &Path, file: &Path) -> Result<f64> {
+    let data = fs::read(file)
+        .await
+        .map_err(|e| anyhow!("Failed to read segment {:?}: {}", file, e))?;
+    if let Some(duration) = ts_duration_secs(&data) {
+        return Ok(duration);
+    }
+
+    probe_streams(ffprobe, file)
+        .await?
+        .iter()
+        .filter_map(ProbeStream::duration_secs)
+        .fold(None, |longest: Option<f64>, d| {
+            Some(longest.map_or(d, |l| l.max(d)))
+        })
+        .ok_or_else(|| anyhow!("Could not determine the duration of {:?}", file))
+}
+
+/// 使用 ffprobe 读取文件中各个流的类型、编码、时长和分辨率
+pub async fn probe_streams(ffprobe: &Path, file: &Path) -> Result<Vec<ProbeStream>> {
+    let output = Command::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-show_entries")
+        .arg("stream=codec_type,codec_name,duration,width,height")
+        .arg("-of")
+        .arg("json")
+        .arg(file)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {:?}: {}", ffprobe, e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed with exit code {:?}: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+
+    let parsed: ProbeOutput = serde_json::from_slice(&output.stdout)?;
+    Ok(parsed.streams)
+}
+
+/// 使用 ffprobe 读取文件的容器和全部流信息，返回 ffprobe 输出的原始 JSON
+pub async fn probe_media_info(ffprobe: &Path, file: &Path) -> Result<String> {
+    let output = Command::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-show_streams")
+        .arg("-show_format")
+        .arg("-of")
+        .arg("json")
+        .arg(file)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {:?}: {}", ffprobe, e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed with exit code {:?}: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// 校验合并后的音频流是否存在且时长与播放列表一致
+///
+/// 非严格模式下时长不符只输出警告；找不到音频流或 `strict` 为真时返回错误。
+pub async fn verify_audio(
+    ffprobe: &Path,
+    file: &Path,
+    expected_secs: f64,
+    strict: bool,
+) -> Result<()> {
+    let streams = probe_streams(ffprobe, file).await?;
+    let audio_duration = streams
+        .iter()
+        .filter(|s| s.codec_type.as_deref() == Some("audio"))
+        .filter_map(ProbeStream::duration_secs)
+        .filter(|d| *d > 0.0)
+        .fold(None, |max: Option<f64>, d| {
+            Some(max.map_or(d, |m| m.max(d)))
+        });
+
+    let Some(audio_duration) = audio_duration else {
+        return Err(anyhow!(
+            "No audio stream with a positive duration in {:?}",
+            file
+        ));
+    };
+
+    let deviation = (audio_duration - expected_secs).abs() / expected_secs.max(f64::EPSILON);
+    if deviation > AUDIO_DURATION_TOLERANCE {
+        let message = format!(
+            "Audio duration {:.2}s differs from playlist duration {:.2}s by {:.1}%",
+            audio_duration,
+            expected_secs,
+            deviation * 100.0
+        );
+        if strict {
+            return Err(anyhow!(message));
+        }
+        warn!("{}", message);
+    } else {
+        info!(
+            "Audio verification passed: {:.2}s (expected {:.2}s).",
+            audio_duration, expected_secs
+        );
+    }
+
+    Ok(())
+}
+
+/// 使用 ffprobe 检查文件的第一个视频帧是否为关键帧（I 帧）
+pub async fn starts_with_keyframe(ffprobe: &Path, file: &Path) -> Result<bool> {
+    let output = Command::new(ffprobe)
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v")
+        .arg("-show_frames")
+        .arg("-read_intervals")
+        .arg("%+#1")
+        .arg("-show_entries")
+        .arg("frame=key_frame")
+        .arg("-of")
+        .arg("json")
+        .arg(file)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {:?}: {}", ffprobe, e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed with exit code {:?}: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+
+    let parsed: FramesOutput = serde_json::from_slice(&output.stdout)?;
+    let first = parsed
+        .frames
+        .first()
+        .ok_or_else(|| anyhow!("No video frames found in {:?}", file))?;
+    Ok(first.key_frame == Some(1))
+}
+
+/// 校验 `#EXT-X-INDEPENDENT-SEGMENTS` 声明：检查首尾分段是否以关键帧开始
+///
+/// 校验失败只输出警告，因为此时视频仍可播放，只是跳转可能不准确。
+pub async fn validate_independent_segments(ffprobe: &Path, segment_paths: &[PathBuf]) {
+    let mut checked = Vec::new();
+    for path in [segment_paths.first(), segment_paths.last()]
+        .into_iter()
+        .flatten()
+    {
+        if checked.contains(&path) {
+            continue;
+        }
+        checked.push(path);
+
+        match starts_with_keyframe(ffprobe, path).await {
+            Ok(true) => info!("Segment {:?} starts with a keyframe.", path),
+            Ok(false) => warn!(
+                "Playlist declares #EXT-X-INDEPENDENT-SEGMENTS but {:?} does not start with a keyframe; seeking may be unreliable.",
+                path
+            ),
+            Err(e) => warn!("Could not validate segment {:?}: {}", path, e),
+        }
+    }
+}
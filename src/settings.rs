@@ -0,0 +1,94 @@
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::cli::GuiTheme;
+
+/// 当前设置文件格式的版本号，新增字段时应保持向后兼容
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// 设置文件名
+const SETTINGS_FILE_NAME: &str = "gui.json";
+
+/// 持久化的GUI设置
+///
+/// 缺失的字段使用默认值，因此旧版本的设置文件可以直接读取。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuiSettings {
+    pub version: u32,
+    pub theme: GuiTheme,
+    /// 窗口内部尺寸 [宽, 高]
+    pub window_size: Option<[f32; 2]>,
+    /// 窗口左上角在屏幕上的位置 [x, y]
+    pub window_position: Option<[f32; 2]>,
+    /// 窗口置顶显示
+    pub always_on_top: bool,
+    pub output_dir: String,
+    pub threads: usize,
+}
+
+impl Default for GuiSettings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            theme: GuiTheme::System,
+            window_size: None,
+            window_position: None,
+            always_on_top: false,
+            output_dir: "output".to_string(),
+            threads: 10,
+        }
+    }
+}
+
+impl GuiSettings {
+    /// 设置文件路径，位于系统的配置目录中
+    pub fn path() -> Option<PathBuf> {
+        ProjectDirs::from("", "", "m3u8_downloader_rs")
+            .map(|dirs| dirs.config_dir().join(SETTINGS_FILE_NAME))
+    }
+
+    /// 读取设置，文件不存在或无法解析时返回默认设置
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+
+        match serde_json::from_str::<Self>(&content) {
+            Ok(settings) => {
+                if settings.version > SETTINGS_VERSION {
+                    warn!(
+                        "Settings file {:?} was written by a newer version (v{}); unknown fields are ignored.",
+                        path, settings.version
+                    );
+                }
+                Self {
+                    version: SETTINGS_VERSION,
+                    ..settings
+                }
+            }
+            Err(e) => {
+                warn!("Failed to parse settings file {:?}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// 保存设置
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("无法确定配置目录"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
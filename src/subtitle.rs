@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use std::path::Path;
+use tokio::fs;
+
+use crate::metadata::vtt_timestamp;
+
+/// MPEG-TS 时间戳的时钟频率（90kHz）
+const MPEGTS_CLOCK: f64 = 90_000.0;
+
+/// 去重时向前比较的提示数量，重复的提示只会出现在相邻分段中
+const DEDUP_WINDOW: usize = 32;
+
+/// 一条字幕提示
+#[derive(Debug, Clone, PartialEq)]
+struct Cue {
+    start_secs: f64,
+    end_secs: f64,
+    text: String,
+}
+
+/// 解析后的 WebVTT 文件
+#[derive(Debug, Default)]
+struct VttDocument {
+    /// `X-TIMESTAMP-MAP` 给出的偏移（秒）：MPEGTS 时间减去本地时间
+    timestamp_offset: Option<f64>,
+    cues: Vec<Cue>,
+}
+
+/// 第 `i` 个字幕分段的本地文件名
+pub fn vtt_segment_file_name(i: usize) -> String {
+    format!("subtitle{}.vtt", i)
+}
+
+/// 解析 WebVTT 时间戳，支持 `hh:mm:ss.ttt` 和省略小时的 `mm:ss.ttt`
+fn parse_vtt_timestamp(value: &str) -> Option<f64> {
+    let (clock, millis) = value.trim().split_once('.')?;
+    let parts: Vec<u64> = clock
+        .split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [h, m, s] => (*h, *m, *s),
+        [m, s] => (0, *m, *s),
+        _ => return None,
+    };
+    if millis.len() != 3 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    let millis: u64 = millis.parse().ok()?;
+    Some((hours * 3600 + minutes * 60 + seconds) as f64 + millis as f64 / 1000.0)
+}
+
+/// 将秒数格式化为 SRT 时间戳（hh:mm:ss,ttt）
+fn srt_timestamp(secs: f64) -> String {
+    vtt_timestamp(secs).replace('.', ",")
+}
+
+/// 解析 `X-TIMESTAMP-MAP=MPEGTS:<ticks>,LOCAL:<timestamp>` 头部
+fn parse_timestamp_map(line: &str) -> Option<f64> {
+    let value = line.strip_prefix("X-TIMESTAMP-MAP=")?;
+    let mut mpegts = None;
+    let mut local = None;
+    for field in value.split(',') {
+        match field.trim().split_once(':') {
+            Some(("MPEGTS", ticks)) => mpegts = ticks.parse::<u64>().ok(),
+            Some(("LOCAL", time)) => local = parse_vtt_timestamp(time),
+            _ => {}
+        }
+    }
+    Some(mpegts? as f64 / MPEGTS_CLOCK - local?)
+}
+
+/// 解析 WebVTT 文本，跳过头部、NOTE、STYLE 和 REGION 块
+fn parse_vtt(vtt: &str) -> Result<VttDocument> {
+    let vtt = vtt.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let mut blocks = vtt.split("\n\n").map(|b| b.trim_matches('\n'));
+
+    let header = blocks.next().unwrap_or_default();
+    if !header.starts_with("WEBVTT") {
+        return Err(anyhow!("Missing WEBVTT header"));
+    }
+    let mut document = VttDocument {
+        timestamp_offset: header.lines().find_map(parse_timestamp_map),
+        ..Default::default()
+    };
+
+    for block in blocks.filter(|b| !b.trim().is_empty()) {
+        let first_word = block.split_whitespace().next().unwrap_or_default();
+        if matches!(first_word, "NOTE" | "STYLE" | "REGION") {
+            continue;
+        }
+
+        // 提示标识符可选，时间行是第一个包含 `-->` 的行
+        let mut lines = block.lines();
+        let Some(timing) = lines.by_ref().find(|line| line.contains("-->")) else {
+            continue;
+        };
+        let (start, rest) = timing
+            .split_once("-->")
+            .ok_or_else(|| anyhow!("Invalid cue timing: {}", timing))?;
+        // 去掉时间行末尾的提示设置（如 `align:start`）
+        let end = rest.split_whitespace().next().unwrap_or_default();
+        let (Some(start_secs), Some(end_secs)) =
+            (parse_vtt_timestamp(start), parse_vtt_timestamp(end))
+        else {
+            return Err(anyhow!("Invalid cue timing: {}", timing));
+        };
+
+        document.cues.push(Cue {
+            start_secs,
+            end_secs,
+            text: lines.collect::<Vec<_>>().join("\n"),
+        });
+    }
+    Ok(document)
+}
+
+/// 写出 SRT 格式的提示列表
+fn format_srt(cues: &[Cue]) -> Result<String> {
+    let mut srt = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        writeln!(srt, "{}", i + 1)?;
+        writeln!(
+            srt,
+            "{} --> {}",
+            srt_timestamp(cue.start_secs),
+            srt_timestamp(cue.end_secs)
+        )?;
+        writeln!(srt, "{}", cue.text)?;
+        writeln!(srt)?;
+    }
+    Ok(srt)
+}
+
+/// 将 WebVTT 字幕转换为 SRT 格式
+pub fn vtt_to_srt(vtt: &str) -> Result<String> {
+    format_srt(&parse_vtt(vtt)?.cues)
+}
+
+/// 按顺序拼接 `dir` 下的 `count` 个 WebVTT 字幕分段，返回合并后的 WebVTT 文本
+///
+/// 带有 `X-TIMESTAMP-MAP` 的分段按 MPEG-TS 时间换算到统一的时间轴，并以第一个分段为零点；
+/// 跨分段重复出现的同一条提示只保留一次。
+pub async fn merge_vtt_segments(dir: &Path, count: usize) -> Result<String> {
+    let mut base_offset = None;
+    let mut cues: Vec<Cue> = Vec::new();
+
+    for i in 0..count {
+        let path = dir.join(vtt_segment_file_name(i));
+        let content = fs::read_to_string(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read subtitle segment {:?}: {}", path, e))?;
+        let document = parse_vtt(&content)
+            .map_err(|e| anyhow!("Invalid subtitle segment {:?}: {}", path, e))?;
+
+        let offset = match document.timestamp_offset {
+            Some(offset) => offset - *base_offset.get_or_insert(offset),
+            None => 0.0,
+        };
+        for mut cue in document.cues {
+            cue.start_secs += offset;
+            cue.end_secs += offset;
+            if !cues.iter().rev().take(DEDUP_WINDOW).any(|c| *c == cue) {
+                cues.push(cue);
+            }
+        }
+    }
+
+    let mut vtt = String::from("WEBVTT\n");
+    for cue in &cues {
+        writeln!(vtt)?;
+        writeln!(
+            vtt,
+            "{} --> {}",
+            vtt_timestamp(cue.start_secs),
+            vtt_timestamp(cue.end_secs)
+        )?;
+        writeln!(vtt, "{}", cue.text)?;
+    }
+    Ok(vtt)
+}
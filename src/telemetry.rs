@@ -0,0 +1,85 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// 一次运行的结束状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Ok,
+    Failed,
+    Cancelled,
+}
+
+/// 运行过程中收集的统计信息
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    pub segment_count: usize,
+    pub failed_segments: usize,
+    /// 本地已存在而未重新下载的分段数
+    pub skipped_segments: usize,
+    pub total_bytes: u64,
+    /// 流的标题
+    pub title: Option<String>,
+    /// 播放列表的总时长（秒）
+    pub duration_secs: f64,
+    /// 合并后的输出文件
+    pub output_file: Option<PathBuf>,
+    /// 分段所在的目录
+    pub segments_dir: Option<PathBuf>,
+}
+
+/// 遥测文件中的一行记录
+///
+/// 只写入本地文件，不会发送到任何服务器；URL以哈希形式记录。
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryRecord {
+    pub run_id: String,
+    pub url_hash: String,
+    pub start_time: String,
+    pub end_time: String,
+    pub segment_count: usize,
+    pub failed_segments: usize,
+    pub total_bytes: u64,
+    pub status: RunStatus,
+}
+
+impl TelemetryRecord {
+    pub fn new(
+        url: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+        stats: &RunStats,
+        status: RunStatus,
+    ) -> Self {
+        Self {
+            run_id: Uuid::new_v4().to_string(),
+            url_hash: sha256::digest(url),
+            start_time: start_time.to_rfc3339(),
+            end_time: end_time.to_rfc3339(),
+            segment_count: stats.segment_count,
+            failed_segments: stats.failed_segments,
+            total_bytes: stats.total_bytes,
+            status,
+        }
+    }
+}
+
+/// 以 JSONL 格式将记录追加到遥测文件
+pub async fn append_record(path: &Path, record: &TelemetryRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+    file.flush().await?;
+    Ok(())
+}
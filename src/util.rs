@@ -0,0 +1,414 @@
+use directories::BaseDirs;
+use log::{debug, warn};
+
+use crate::cli::Container;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// 清理文件名，将各平台上不合法的字符替换为下划线
+pub fn sanitize_filename(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows 不允许文件名以点或空格结尾
+    let sanitized = sanitized.trim().trim_end_matches('.').trim();
+    if sanitized.is_empty() {
+        "untitled".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+/// 展开路径开头的 `~` 以及其中的 `$VAR`、`${VAR}` 环境变量，未定义的变量保持原样
+pub fn expand_path(path: &str) -> PathBuf {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        if let Some(base_dirs) = BaseDirs::new() {
+            expanded.push_str(&base_dirs.home_dir().to_string_lossy());
+            rest = &rest[1..];
+        }
+    }
+
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let (name, consumed) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            }
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], end)
+        };
+
+        match std::env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[pos..pos + 1 + consumed]),
+        }
+        rest = &after[consumed..];
+    }
+    expanded.push_str(rest);
+
+    PathBuf::from(expanded)
+}
+
+/// MPEG-TS 空包（PID 0x1FFF），解复用器会直接丢弃
+const NULL_TS_PACKET: [u8; 188] = {
+    let mut packet = [0xFF; 188];
+    packet[0] = 0x47;
+    packet[1] = 0x1F;
+    packet[2] = 0xFF;
+    packet[3] = 0x10;
+    packet
+};
+
+/// 无法用 FFmpeg 生成黑屏静音分段时的占位内容：每秒时长对应一个 MPEG-TS 空包
+///
+/// 空包不携带 PCR 和 PTS，因此占位分段没有时长：合并后不会产生画面或声音，也不会把时间轴
+/// 补齐，播放器在此处直接跳过缺失的时间段。它只用于保证分段列表齐全、合并流程不中断。
+pub fn placeholder_segment(duration_secs: f32) -> Vec<u8> {
+    let packets = (duration_secs.ceil() as usize).max(1);
+    NULL_TS_PACKET.repeat(packets)
+}
+
+/// 占位分段的文件名：`index000003.ts` 对应 `index000003.placeholder.ts`
+///
+/// 与真实分段不同名，续传和跨下载复用都不会把占位文件当作已下载的分段。
+pub fn placeholder_file_name(segment_file_name: &str) -> String {
+    match segment_file_name.split_once('.') {
+        Some((stem, extension)) => format!("{}.placeholder.{}", stem, extension),
+        None => format!("{}.placeholder", segment_file_name),
+    }
+}
+
+/// MPEG-TS 包大小
+const TS_PACKET_SIZE: usize = 188;
+
+/// MPEG-TS 时间戳的时钟频率（90kHz）
+const TS_CLOCK: f64 = 90_000.0;
+
+/// PMT 中的流类型
+const STREAM_TYPE_AAC: u8 = 0x0F;
+const STREAM_TYPE_AAC_LATM: u8 = 0x11;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_HEVC: u8 = 0x24;
+
+/// 取出 TS 包的 PID、负载起始标志和负载
+fn ts_payload(packet: &[u8]) -> Option<(u16, bool, &[u8])> {
+    if packet.len() < TS_PACKET_SIZE || packet[0] != 0x47 {
+        return None;
+    }
+    let pid = (u16::from(packet[1] & 0x1F) << 8) | u16::from(packet[2]);
+    let payload_start = packet[1] & 0x40 != 0;
+    let offset = match (packet[3] >> 4) & 0x03 {
+        0x01 => 4,
+        0x03 => 5 + usize::from(packet[4]),
+        _ => return None,
+    };
+    Some((pid, payload_start, packet.get(offset..TS_PACKET_SIZE)?))
+}
+
+/// 取出 PSI 表的节数据（跳过 pointer_field，并截去 CRC）
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let section = payload.get(1 + usize::from(*payload.first()?)..)?;
+    let length = (usize::from(section.get(1)? & 0x0F) << 8) | usize::from(*section.get(2)?);
+    section.get(..(3 + length).checked_sub(4)?)
+}
+
+/// 解析分段开头的 PAT/PMT，返回节目中各个基本流的流类型
+pub fn ts_stream_types(data: &[u8]) -> Vec<u8> {
+    let mut pmt_pid = None;
+    for packet in data.chunks(TS_PACKET_SIZE) {
+        let Some((pid, true, payload)) = ts_payload(packet) else {
+            continue;
+        };
+        let Some(section) = psi_section(payload) else {
+            continue;
+        };
+
+        match (pid, section.first()) {
+            // PAT：取第一个节目（节目号 0 为网络信息表）
+            (0, Some(0x00)) if pmt_pid.is_none() => {
+                pmt_pid = section.get(8..).and_then(|programs| {
+                    programs
+                        .chunks_exact(4)
+                        .find(|p| p[0] != 0 || p[1] != 0)
+                        .map(|p| (u16::from(p[2] & 0x1F) << 8) | u16::from(p[3]))
+                });
+            }
+            // PMT：依次读取基本流的描述
+            (pid, Some(0x02)) if Some(pid) == pmt_pid => {
+                let Some(&info_len) = section.get(11) else {
+                    return Vec::new();
+                };
+                let program_info_length =
+                    (usize::from(section[10] & 0x0F) << 8) | usize::from(info_len);
+                let mut streams = section.get(12 + program_info_length..).unwrap_or_default();
+                let mut types = Vec::new();
+                while streams.len() >= 5 {
+                    types.push(streams[0]);
+                    let es_info_length =
+                        (usize::from(streams[3] & 0x0F) << 8) | usize::from(streams[4]);
+                    streams = streams.get(5 + es_info_length..).unwrap_or_default();
+                }
+                return types;
+            }
+            _ => {}
+        }
+    }
+    Vec::new()
+}
+
+/// 从 PES 包头中读取 PTS，`payload` 须以 PES 起始码开头
+fn pes_pts(payload: &[u8]) -> Option<u64> {
+    if payload.len() < 14 || payload[..3] != [0, 0, 1] || payload[7] & 0x80 == 0 {
+        return None;
+    }
+    let p = &payload[9..14];
+    Some(
+        (u64::from(p[0] >> 1) & 0x07) << 30
+            | u64::from(p[1]) << 22
+            | u64::from(p[2] >> 1) << 15
+            | u64::from(p[3]) << 7
+            | u64::from(p[4] >> 1),
+    )
+}
+
+/// 根据 PES 的 PTS 估算 MPEG-TS 数据的时长（秒）
+///
+/// 对每个 PID 取最大与最小 PTS 之差，并按 PES 数量补上最后一帧的时长，返回各 PID 中的最大值。
+/// 不是有效的 MPEG-TS 或没有 PTS 时返回 `None`。
+pub fn ts_duration_secs(data: &[u8]) -> Option<f64> {
+    // PID -> (最小 PTS, 最大 PTS, PES 数量)
+    let mut ranges: HashMap<u16, (u64, u64, u64)> = HashMap::new();
+    for packet in data.chunks_exact(TS_PACKET_SIZE) {
+        if packet[0] != 0x47 {
+            return None;
+        }
+        // 只有 PES 的第一个包（payload_unit_start_indicator）带有包头
+        let Some((pid, true, payload)) = ts_payload(packet) else {
+            continue;
+        };
+        let Some(pts) = pes_pts(payload) else {
+            continue;
+        };
+        let range = ranges.entry(pid).or_insert((pts, pts, 0));
+        range.0 = range.0.min(pts);
+        range.1 = range.1.max(pts);
+        range.2 += 1;
+    }
+
+    ranges
+        .values()
+        .filter(|(_, _, count)| *count > 1)
+        .map(|(min, max, count)| {
+            (max - min) as f64 / TS_CLOCK * *count as f64 / (*count - 1) as f64
+        })
+        .fold(None, |longest: Option<f64>, d| {
+            Some(longest.map_or(d, |l| l.max(d)))
+        })
+}
+
+/// 根据第一个分段的内容推断合适的输出容器
+///
+/// fMP4 分段和 H.264/HEVC + AAC 的 TS 分段使用 MP4；其他编码组合（如 AC-3、MP3 音频）使用 MKV；
+/// 无法识别时返回 `None`。
+pub fn detect_container(data: &[u8]) -> Option<Container> {
+    // fMP4 分段以 ISO BMFF box 开头
+    if matches!(data.get(4..8), Some(b"ftyp" | b"styp" | b"moof" | b"moov")) {
+        return Some(Container::Mp4);
+    }
+
+    let types = ts_stream_types(data);
+    if types.is_empty() {
+        return None;
+    }
+    let mp4_compatible = types.iter().all(|t| {
+        matches!(
+            *t,
+            STREAM_TYPE_H264 | STREAM_TYPE_HEVC | STREAM_TYPE_AAC | STREAM_TYPE_AAC_LATM
+        )
+    });
+    Some(if mp4_compatible {
+        Container::Mp4
+    } else {
+        Container::Mkv
+    })
+}
+
+/// 令牌桶限速器，允许空闲时积累的令牌用于短时突发
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// 持续速率（字节/秒）
+    pub rate: u64,
+    /// 突发容量（字节）
+    pub burst: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// 创建一个令牌桶，初始时装满突发容量
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            burst,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// 消耗指定字节数的令牌，返回调用方需要等待的时长
+    ///
+    /// 令牌不足时记为欠账，后续调用会一并偿还，因此多个任务共享时总速率仍受限。
+    pub fn consume(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// 吞吐量至少提升该比例才视为随并发数增加而增长
+const ADAPTIVE_MIN_GAIN: f64 = 1.05;
+
+/// 根据吞吐量和限流错误动态调整下载并发数
+#[derive(Debug)]
+pub struct ConcurrencyController {
+    pub current: usize,
+    pub min: usize,
+    pub max: usize,
+    last_throughput: Option<f64>,
+}
+
+impl ConcurrencyController {
+    /// 创建控制器，初始并发数限制在 `[min, max]` 范围内
+    pub fn new(current: usize, min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            current: current.clamp(min, max),
+            min,
+            max,
+            last_throughput: None,
+        }
+    }
+
+    /// 根据最近一次测量的吞吐量（字节/秒）和限流错误率调整并发数，返回调整后的并发数
+    ///
+    /// 吞吐量没有提升且服务器返回了 429 时减一；吞吐量明显提升且没有错误时加一。
+    pub fn adjust(&mut self, throughput: f64, error_rate: f64) -> usize {
+        let improved = self
+            .last_throughput
+            .is_none_or(|last| throughput >= last * ADAPTIVE_MIN_GAIN);
+        if error_rate > 0.0 && !improved {
+            self.current = self.current.saturating_sub(1).max(self.min);
+        } else if error_rate == 0.0 && improved {
+            self.current = (self.current + 1).min(self.max);
+        }
+        self.last_throughput = Some(throughput);
+        self.current
+    }
+}
+
+/// 等待同步的已写完文件数上限，达到后立即同步并关闭，避免大量并发下载耗尽文件描述符
+const MAX_FINISHED_FILES: usize = 64;
+
+/// 定期将已写入的数据同步到磁盘，避免长时间下载因断电丢失仍在页缓存中的分段
+///
+/// 长期打开的文件以弱引用登记，文件关闭后自动移除；写完的分段文件保留句柄直到下一次同步，
+/// 积压超过 [`MAX_FINISHED_FILES`] 个时提前同步。
+#[derive(Debug, Default)]
+pub struct DiskFlusher {
+    open: Mutex<Vec<Weak<std::fs::File>>>,
+    finished: Mutex<Vec<std::fs::File>>,
+}
+
+impl DiskFlusher {
+    /// 创建同步器并启动后台任务，每隔 `interval` 同步一次；同步器被释放后任务自动结束
+    pub fn spawn(interval: Duration) -> Arc<Self> {
+        let flusher = Arc::new(Self::default());
+        let weak = Arc::downgrade(&flusher);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(flusher) = weak.upgrade() else {
+                    break;
+                };
+                if let Err(e) = tokio::task::spawn_blocking(move || flusher.flush()).await {
+                    warn!("Periodic disk flush failed: {}", e);
+                }
+            }
+        });
+        flusher
+    }
+
+    /// 登记一个仍在写入的文件，每次同步时都会同步它
+    pub fn track_open(&self, file: &Arc<std::fs::File>) {
+        self.open.lock().unwrap().push(Arc::downgrade(file));
+    }
+
+    /// 登记一个已写完的文件，下一次同步后关闭；积压的文件达到上限时立即同步这一批
+    pub async fn track_finished(&self, file: std::fs::File) {
+        let batch = {
+            let mut finished = self.finished.lock().unwrap();
+            finished.push(file);
+            if finished.len() < MAX_FINISHED_FILES {
+                return;
+            }
+            std::mem::take(&mut *finished)
+        };
+        if let Err(e) = tokio::task::spawn_blocking(move || sync_files(&batch)).await {
+            warn!("Disk flush failed: {}", e);
+        }
+    }
+
+    /// 同步所有登记的文件，返回同步的文件数
+    pub fn flush(&self) -> usize {
+        let open: Vec<Arc<std::fs::File>> = {
+            let mut open = self.open.lock().unwrap();
+            open.retain(|file| file.strong_count() > 0);
+            open.iter().filter_map(Weak::upgrade).collect()
+        };
+        let finished = std::mem::take(&mut *self.finished.lock().unwrap());
+        let open: Vec<&std::fs::File> = open.iter().map(Arc::as_ref).collect();
+        sync_files(&open) + sync_files(&finished)
+    }
+}
+
+/// 同步一组文件，返回成功同步的文件数
+fn sync_files<F: std::borrow::Borrow<std::fs::File>>(files: &[F]) -> usize {
+    let mut synced = 0;
+    for file in files {
+        match file.borrow().sync_data() {
+            Ok(()) => synced += 1,
+            Err(e) => warn!("Failed to sync file to disk: {}", e),
+        }
+    }
+    if synced > 0 {
+        debug!("Synced {} files to disk", synced);
+    }
+    synced
+}
@@ -0,0 +1,60 @@
+//! `--cookie-domain-allowlist` 的域名匹配规则
+
+use m3u8_downloader_rs::http::DomainFilter;
+
+fn filter(patterns: &[&str]) -> DomainFilter {
+    DomainFilter::new(patterns.iter().map(|p| p.to_string()))
+}
+
+#[test]
+fn exact_host_matches_only_itself() {
+    let domains = filter(&["example.com"]);
+
+    assert!(domains.matches("example.com"));
+    assert!(!domains.matches("cdn.example.com"));
+    assert!(!domains.matches("example.org"));
+}
+
+#[test]
+fn wildcard_matches_subdomains_but_not_the_parent() {
+    let domains = filter(&["*.example.com"]);
+
+    assert!(domains.matches("cdn.example.com"));
+    assert!(domains.matches("a.b.example.com"));
+    assert!(!domains.matches("example.com"));
+}
+
+#[test]
+fn wildcard_rejects_suffix_spoofing() {
+    let domains = filter(&["*.example.com"]);
+
+    assert!(!domains.matches("evilexample.com"));
+    assert!(!domains.matches(".example.com"));
+    assert!(!domains.matches("example.com.evil.net"));
+}
+
+#[test]
+fn leading_dot_cookie_domain_is_ignored() {
+    let domains = filter(&["example.com"]);
+
+    assert!(domains.matches(".example.com"));
+}
+
+#[test]
+fn matching_ignores_case_and_trailing_dot() {
+    let domains = filter(&["Example.COM", "*.CDN.example.net."]);
+
+    assert!(domains.matches("EXAMPLE.com"));
+    assert!(domains.matches("edge1.cdn.EXAMPLE.net."));
+}
+
+#[test]
+fn default_filter_covers_playlist_host_and_subdomains() {
+    let domains = DomainFilter::for_host("video.example.com");
+
+    assert!(domains.matches("video.example.com"));
+    assert!(domains.matches("seg.video.example.com"));
+    assert!(!domains.matches("example.com"));
+    assert!(!domains.matches("thirdparty-cdn.net"));
+    assert!(!domains.matches("evilvideo.example.com"));
+}
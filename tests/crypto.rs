@@ -0,0 +1,87 @@
+//! AES-CBC 解密的往返测试
+//!
+//! 用 `cbc::Encryptor` 加密后再解密，覆盖 AES-128、AES-192、AES-256 三种密钥长度。
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockCipher, BlockEncryptMut, KeyInit, KeyIvInit};
+use m3u8_downloader_rs::crypto::{decrypt_data, decrypt_with, key_len_for_method, DecryptBackend};
+
+const IV: [u8; 16] = *b"fedcba9876543210";
+
+/// 长度不是分组大小整数倍的明文，解密时需要正确去除填充
+const PLAINTEXT: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+/// 以指定的分组密码做 CBC 加密（PKCS#7 填充）
+fn encrypt<C>(plain: &[u8], key: &[u8]) -> Vec<u8>
+where
+    C: BlockCipher + BlockEncryptMut + KeyInit,
+{
+    let mut buf = plain.to_vec();
+    buf.resize(plain.len() + 16, 0);
+    let encrypted_len = cbc::Encryptor::<C>::new_from_slices(key, &IV)
+        .expect("valid key and IV length")
+        .encrypt_padded_mut::<Pkcs7>(&mut buf, plain.len())
+        .expect("buffer has room for padding")
+        .len();
+    buf.truncate(encrypted_len);
+    buf
+}
+
+/// 与密钥长度相同的测试密钥
+fn key(len: usize) -> Vec<u8> {
+    (0..len as u8).collect()
+}
+
+#[test]
+fn aes128_round_trip() {
+    let key = key(16);
+    let encrypted = encrypt::<aes::Aes128>(PLAINTEXT, &key);
+    assert_eq!(decrypt_data(&encrypted, &key, &IV).unwrap(), PLAINTEXT);
+}
+
+#[test]
+fn aes192_round_trip() {
+    let key = key(24);
+    let encrypted = encrypt::<aes::Aes192>(PLAINTEXT, &key);
+    assert_eq!(decrypt_data(&encrypted, &key, &IV).unwrap(), PLAINTEXT);
+}
+
+#[test]
+fn aes256_round_trip() {
+    let key = key(32);
+    let encrypted = encrypt::<aes::Aes256>(PLAINTEXT, &key);
+    assert_eq!(decrypt_data(&encrypted, &key, &IV).unwrap(), PLAINTEXT);
+}
+
+#[cfg(feature = "openssl-crypto")]
+#[test]
+fn openssl_round_trip_for_every_key_size() {
+    for (len, encrypted) in [
+        (16, encrypt::<aes::Aes128>(PLAINTEXT, &key(16))),
+        (24, encrypt::<aes::Aes192>(PLAINTEXT, &key(24))),
+        (32, encrypt::<aes::Aes256>(PLAINTEXT, &key(32))),
+    ] {
+        let decrypted = decrypt_with(DecryptBackend::OpenSsl, &encrypted, &key(len), &IV).unwrap();
+        assert_eq!(decrypted, PLAINTEXT, "{}-byte key", len);
+    }
+}
+
+#[test]
+fn rejects_unsupported_key_length() {
+    let encrypted = encrypt::<aes::Aes128>(PLAINTEXT, &key(16));
+    for len in [0, 15, 17, 20, 33] {
+        assert!(
+            decrypt_with(DecryptBackend::RustCrypto, &encrypted, &key(len), &IV).is_err(),
+            "{}-byte key was accepted",
+            len
+        );
+    }
+}
+
+#[test]
+fn key_length_follows_method() {
+    assert_eq!(key_len_for_method("AES-128"), Some(16));
+    assert_eq!(key_len_for_method("AES-192"), Some(24));
+    assert_eq!(key_len_for_method("aes-256"), Some(32));
+    assert_eq!(key_len_for_method("SAMPLE-AES"), None);
+}
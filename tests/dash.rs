@@ -0,0 +1,69 @@
+//! MPEG-DASH 清单转换为媒体播放列表，以及 DASH 分段的本地文件名
+#![cfg(feature = "dash")]
+
+use m3u8_downloader_rs::dash::{dash_segment_file_names, is_init_segment, mpd_to_media_playlist};
+use url::Url;
+
+/// 点播清单：两个视频表示和一个独立的音频自适应集，分段模板按编号展开
+const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT8S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="0">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" segmentAlignment="true">
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1"
+                       initialization="init-$RepresentationID$.mp4"
+                       media="chunk-$RepresentationID$-$Number%05d$.m4s"/>
+      <Representation id="v360" bandwidth="500000" width="640" height="360" codecs="avc1.64001e"/>
+      <Representation id="v720" bandwidth="1500000" width="1280" height="720" codecs="avc1.64001f"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" lang="en">
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1"
+                       initialization="init-$RepresentationID$.mp4"
+                       media="chunk-$RepresentationID$-$Number%05d$.m4s"/>
+      <Representation id="a128" bandwidth="128000" codecs="mp4a.40.2"/>
+    </AdaptationSet>
+  </Period>
+</MPD>
+"#;
+
+fn media_playlist() -> m3u8_rs::MediaPlaylist {
+    let mpd = dash_mpd::parse(MPD).expect("fixture is a valid MPD");
+    let url = Url::parse("https://example.com/vod/manifest.mpd").unwrap();
+    mpd_to_media_playlist(&mpd, &url).unwrap()
+}
+
+#[test]
+fn selects_highest_bandwidth_video_with_init_segment_first() {
+    let playlist = media_playlist();
+    let uris: Vec<&str> = playlist.segments.iter().map(|s| s.uri.as_str()).collect();
+
+    assert_eq!(
+        uris,
+        [
+            "https://example.com/vod/init-v720.mp4",
+            "https://example.com/vod/chunk-v720-00001.m4s",
+            "https://example.com/vod/chunk-v720-00002.m4s",
+        ]
+    );
+    assert!(is_init_segment(&playlist.segments[0]));
+    assert!(!playlist.segments[1..].iter().any(is_init_segment));
+    assert!(playlist.end_list);
+}
+
+#[test]
+fn names_init_and_media_segments_as_mp4() {
+    let playlist = media_playlist();
+
+    assert_eq!(
+        dash_segment_file_names(&playlist.segments, false, 6),
+        ["init000000.mp4", "index000001.m4s", "index000002.m4s"]
+    );
+    assert_eq!(
+        dash_segment_file_names(&playlist.segments, true, 6),
+        [
+            "init-v720.mp4",
+            "chunk-v720-00001.m4s",
+            "chunk-v720-00002.m4s"
+        ]
+    );
+}
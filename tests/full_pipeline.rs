@@ -0,0 +1,378 @@
+//! 完整下载流程的集成测试
+//!
+//! 本地 HTTP 服务提供主播放列表、AES-128 加密的分段和密钥，调用 `run` 下载、解密并合并，
+//! 检查输出文件和分段清理。使用内置的拼接合并器，测试环境无需安装 FFmpeg。
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit};
+use axum::http::header;
+use axum::routing::get;
+use axum::Router;
+use m3u8_downloader_rs::cli::{Args, MergerKind};
+use m3u8_downloader_rs::run;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// 分段加密密钥
+const KEY: [u8; 16] = *b"0123456789abcdef";
+
+/// 播放列表中显式给出的 IV
+const IV: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+/// 分段数量
+const SEGMENT_COUNT: usize = 3;
+
+/// 每个分段包含的 MPEG-TS 包数量
+const PACKETS_PER_SEGMENT: usize = 4;
+
+/// MPEG-TS 包大小
+const TS_PACKET_SIZE: usize = 188;
+
+const MASTER_PLAYLIST: &str = "#EXTM3U
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"English\",LANGUAGE=\"en\",DEFAULT=YES,AUTOSELECT=YES,URI=\"subs/en.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,SUBTITLES=\"subs\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1600000,RESOLUTION=1280x720,SUBTITLES=\"subs\"
+high/index.m3u8
+";
+
+const SUBTITLE_PLAYLIST: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXTINF:4.000,
+en0.vtt
+#EXTINF:4.000,
+en1.vtt
+#EXT-X-ENDLIST
+";
+
+/// WebVTT 字幕分段，跨越两个分段的提示在两个分段中各出现一次
+const SUBTITLE_SEGMENTS: [&str; 2] = [
+    "WEBVTT
+X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000
+
+00:00:01.000 --> 00:00:03.000
+Hello
+
+00:00:03.500 --> 00:00:04.500
+Across segments
+",
+    "WEBVTT
+X-TIMESTAMP-MAP=MPEGTS:1260000,LOCAL:00:00:04.000
+
+00:00:03.500 --> 00:00:04.500
+Across segments
+
+00:00:05.000 --> 00:00:07.000
+World
+",
+];
+
+/// 两个字幕分段合并去重后转换得到的 SRT
+const EXPECTED_SRT: &str = "1
+00:00:01,000 --> 00:00:03,000
+Hello
+
+2
+00:00:03,500 --> 00:00:04,500
+Across segments
+
+3
+00:00:05,000 --> 00:00:07,000
+World
+
+";
+
+/// 由空包（PID 0x1FFF）组成的最小 MPEG-TS 分段，载荷填充分段序号以便区分
+fn ts_segment(index: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(PACKETS_PER_SEGMENT * TS_PACKET_SIZE);
+    for counter in 0..PACKETS_PER_SEGMENT {
+        data.extend_from_slice(&[0x47, 0x1F, 0xFF, 0x10 | counter as u8]);
+        data.resize(data.len() + TS_PACKET_SIZE - 4, index as u8);
+    }
+    data
+}
+
+/// 以 AES-128-CBC（PKCS#7 填充）加密分段
+fn encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut buf = plain.to_vec();
+    buf.resize(plain.len() + 16, 0);
+    let encrypted_len = cbc::Encryptor::<aes::Aes128>::new(&KEY.into(), &IV.into())
+        .encrypt_padded_mut::<Pkcs7>(&mut buf, plain.len())
+        .expect("buffer has room for padding")
+        .len();
+    buf.truncate(encrypted_len);
+    buf
+}
+
+/// 引用相对路径密钥和分段的媒体播放列表
+fn media_playlist() -> String {
+    let mut playlist = String::from(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:0\n",
+    );
+    playlist.push_str(&format!(
+        "#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x{}\n",
+        hex::encode(IV)
+    ));
+    for i in 0..SEGMENT_COUNT {
+        playlist.push_str(&format!("#EXTINF:4.000,\nsegment{}.ts\n", i));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// 直播媒体播放列表：首次获取时窗口为前两个分段，之后窗口滑动一个分段并结束直播
+fn live_playlist(reload: usize) -> String {
+    let first = reload.min(1);
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+        first
+    );
+    playlist.push_str(&format!(
+        "#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x{}\n",
+        hex::encode(IV)
+    ));
+    for i in first..first + 2 {
+        playlist.push_str(&format!("#EXTINF:4.000,\nsegment{}.ts\n", i));
+    }
+    if reload > 0 {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+/// 启动模拟服务器，两个码率的媒体播放列表结构相同，共用一个字幕轨道，返回主播放列表 URL
+///
+/// `/live/index.m3u8` 为直播媒体播放列表，每次获取都会前进一步。
+async fn start_server() -> String {
+    let mut router = Router::new()
+        .route(
+            "/master.m3u8",
+            get(|| async {
+                (
+                    [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")],
+                    MASTER_PLAYLIST,
+                )
+            }),
+        )
+        .route(
+            "/subs/en.m3u8",
+            get(|| async {
+                (
+                    [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")],
+                    SUBTITLE_PLAYLIST,
+                )
+            }),
+        );
+    for (i, segment) in SUBTITLE_SEGMENTS.into_iter().enumerate() {
+        router = router.route(
+            &format!("/subs/en{}.vtt", i),
+            get(move || async move { ([(header::CONTENT_TYPE, "text/vtt")], segment) }),
+        );
+    }
+    let reloads = Arc::new(AtomicUsize::new(0));
+    router = router.route(
+        "/live/index.m3u8",
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")],
+                live_playlist(reloads.fetch_add(1, Ordering::SeqCst)),
+            )
+        }),
+    );
+    for variant in ["low", "high"] {
+        router = router.route(
+            &format!("/{}/index.m3u8", variant),
+            get(|| async {
+                (
+                    [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")],
+                    media_playlist(),
+                )
+            }),
+        );
+    }
+    for variant in ["low", "high", "live"] {
+        router = router.route(
+            &format!("/{}/key.bin", variant),
+            get(|| async {
+                (
+                    [(header::CONTENT_TYPE, "application/octet-stream")],
+                    KEY.to_vec(),
+                )
+            }),
+        );
+        for i in 0..SEGMENT_COUNT {
+            let segment = encrypt(&ts_segment(i));
+            router = router.route(
+                &format!("/{}/segment{}.ts", variant, i),
+                get(move || async move { ([(header::CONTENT_TYPE, "video/mp2t")], segment) }),
+            );
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}/master.m3u8", addr)
+}
+
+/// 递归列出目录下的所有文件
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(files_in(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// 下载加密的主播放列表，检查合并结果为按顺序解密的分段且分段已清理
+async fn download_encrypted_stream(stream_segments: bool) {
+    let url = start_server().await;
+    let output_dir =
+        std::env::temp_dir().join(format!("m3u8_full_pipeline_{}", uuid::Uuid::new_v4()));
+
+    let result = run(Args {
+        url,
+        threads: 2,
+        output_dir: output_dir.clone(),
+        merger: MergerKind::Native,
+        stream_segments,
+        ..Args::default()
+    })
+    .await;
+
+    let files = files_in(&output_dir);
+    let output = files
+        .iter()
+        .find(|p| p.file_name().is_some_and(|name| name == "output_video.mp4"))
+        .cloned();
+    let leftover_segments: Vec<&PathBuf> = files
+        .iter()
+        .filter(|p| p.extension().is_some_and(|ext| ext == "ts"))
+        .collect();
+    let merged = output.as_deref().map(|p| fs::read(p).unwrap());
+    let _ = fs::remove_dir_all(&output_dir);
+
+    let stats = result.expect("download failed");
+    assert_eq!(stats.segments_downloaded, SEGMENT_COUNT);
+    assert_eq!(stats.segments_failed, 0);
+    assert_eq!(Some(stats.output_path), output);
+    let merged = merged.expect("output file was not created");
+    assert!(!merged.is_empty(), "output file is empty");
+    let expected: Vec<u8> = (0..SEGMENT_COUNT).flat_map(ts_segment).collect();
+    assert_eq!(
+        merged, expected,
+        "merged output is not the decrypted segments in order"
+    );
+    assert!(
+        leftover_segments.is_empty(),
+        "segments were not cleaned up: {:?}",
+        leftover_segments
+    );
+}
+
+#[tokio::test]
+async fn downloads_decrypts_and_merges_encrypted_stream() {
+    download_encrypted_stream(false).await;
+}
+
+#[tokio::test]
+async fn downloads_while_parsing_segments_lazily() {
+    download_encrypted_stream(true).await;
+}
+
+#[tokio::test]
+async fn converts_subtitle_track_to_srt() {
+    let url = start_server().await;
+    let output_dir =
+        std::env::temp_dir().join(format!("m3u8_full_pipeline_{}", uuid::Uuid::new_v4()));
+
+    let result = run(Args {
+        url,
+        threads: 2,
+        output_dir: output_dir.clone(),
+        merger: MergerKind::Native,
+        write_subtitles: true,
+        ..Args::default()
+    })
+    .await;
+
+    let files = files_in(&output_dir);
+    let srt = files
+        .iter()
+        .find(|p| {
+            p.file_name()
+                .is_some_and(|name| name == "output_video.en.srt")
+        })
+        .map(|p| fs::read_to_string(p).unwrap());
+    let leftover_subtitles: Vec<&PathBuf> = files
+        .iter()
+        .filter(|p| p.extension().is_some_and(|ext| ext == "vtt"))
+        .collect();
+    let _ = fs::remove_dir_all(&output_dir);
+
+    result.expect("download failed");
+    assert_eq!(srt.as_deref(), Some(EXPECTED_SRT));
+    assert!(
+        leftover_subtitles.is_empty(),
+        "subtitle segments were not cleaned up: {:?}",
+        leftover_subtitles
+    );
+}
+
+/// 录制直播播放列表直到结束，检查合并结果包含窗口滑动前后的全部分段
+async fn record_live_playlist(concurrent_playlist_fetch: Option<u64>) {
+    let url = start_server()
+        .await
+        .replace("master.m3u8", "live/index.m3u8");
+    let output_dir =
+        std::env::temp_dir().join(format!("m3u8_full_pipeline_{}", uuid::Uuid::new_v4()));
+
+    let result = run(Args {
+        url,
+        threads: 2,
+        output_dir: output_dir.clone(),
+        merger: MergerKind::Native,
+        live: true,
+        live_poll_interval: Some(0.0),
+        playlist_refresh_jitter: 0.0,
+        concurrent_playlist_fetch,
+        ..Args::default()
+    })
+    .await;
+
+    let output = files_in(&output_dir)
+        .into_iter()
+        .find(|p| p.file_name().is_some_and(|name| name == "output_video.mp4"));
+    let merged = output.as_deref().map(|p| fs::read(p).unwrap());
+    let _ = fs::remove_dir_all(&output_dir);
+
+    let stats = result.expect("live recording failed");
+    assert_eq!(stats.segments_downloaded, SEGMENT_COUNT);
+    let expected: Vec<u8> = (0..SEGMENT_COUNT).flat_map(ts_segment).collect();
+    assert_eq!(
+        merged.expect("output file was not created"),
+        expected,
+        "the recording is not every segment of the live window in order"
+    );
+}
+
+#[tokio::test]
+async fn records_live_playlist_until_endlist() {
+    record_live_playlist(None).await;
+}
+
+#[tokio::test]
+async fn records_live_playlist_with_prefetched_reloads() {
+    record_live_playlist(Some(2)).await;
+}
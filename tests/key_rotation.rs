@@ -0,0 +1,54 @@
+//! 播放列表中途轮换 `#EXT-X-KEY` 时，每个分段对应的密钥
+
+use m3u8_downloader_rs::playlist::segment_keys;
+
+const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:4
+#EXT-X-KEY:METHOD=AES-128,URI=\"a.key\"
+#EXTINF:4.000,
+segment0.ts
+#EXTINF:4.000,
+segment1.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"b.key\",IV=0x00000000000000000000000000000002
+#EXTINF:4.000,
+segment2.ts
+#EXTINF:4.000,
+segment3.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4.000,
+segment4.ts
+#EXT-X-ENDLIST
+";
+
+#[test]
+fn tracks_most_recent_key_per_segment() {
+    let playlist = m3u8_rs::parse_media_playlist_res(PLAYLIST.as_bytes()).unwrap();
+    let uris: Vec<Option<String>> = segment_keys(&playlist.segments)
+        .into_iter()
+        .map(|key| key.map(|k| k.uri))
+        .collect();
+
+    assert_eq!(
+        uris,
+        [
+            Some("a.key".to_string()),
+            Some("a.key".to_string()),
+            Some("b.key".to_string()),
+            Some("b.key".to_string()),
+            None,
+        ]
+    );
+}
+
+#[test]
+fn rotated_key_keeps_its_own_iv() {
+    let playlist = m3u8_rs::parse_media_playlist_res(PLAYLIST.as_bytes()).unwrap();
+    let keys = segment_keys(&playlist.segments);
+
+    assert_eq!(keys[1].as_ref().unwrap().iv, None);
+    assert_eq!(
+        keys[3].as_ref().unwrap().iv.as_deref(),
+        Some("0x00000000000000000000000000000002")
+    );
+}